@@ -10,24 +10,22 @@ pub struct Region {
    pub load: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Patch {
-   pub start: u32,
-   pub data: Vec<u8>,
-}
-
 #[derive(Debug, Deserialize)]
 pub struct Cpu {
     pub svd: String,
-    pub vector_table: u32,
+    /// Optional when the firmware is an ELF file
+    pub vector_table: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
    pub cpu: Cpu,
    pub regions: Vec<Region>,
-   pub patches: Option<Vec<Patch>>,
+   /// Symbol map files, in the nm format
+   pub symbols: Option<Vec<String>>,
+   pub patches: Option<Vec<crate::patches::Patch>>,
    pub peripherals: Option<crate::peripherals::PeripheralsConfig>,
    pub devices: Option<crate::ext_devices::ExtDevicesConfig>,
    pub framebuffers: Option<Vec<crate::framebuffers::FramebufferConfig>>,
+   pub watchpoints: Option<Vec<crate::watchpoints::WatchpointConfig>>,
 }
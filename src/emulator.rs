@@ -1,9 +1,9 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::{mem::MaybeUninit, sync::atomic::{AtomicU64, Ordering, AtomicBool}, cell::RefCell};
+use std::{mem::MaybeUninit, sync::atomic::{AtomicU64, Ordering, AtomicBool}, cell::RefCell, rc::Rc};
 use svd_parser::svd::Device as SvdDevice;
 use unicorn_engine::{unicorn_const::{Arch, Mode, HookType, MemType}, Unicorn, RegisterARM};
-use crate::{config::Config, util::UniErr, Args, system::System, framebuffers::sdl_engine::{PUMP_EVENT_INST_INTERVAL, SDL}};
+use crate::{config::Config, util::UniErr, Args, system::System, framebuffers::sdl_engine::{PUMP_EVENT_INST_INTERVAL, SDL}, gdb::{GdbServer, StopReason}, peripherals::nvic::{Fault, cfsr, hfsr}};
 use anyhow::{Context as _, Result, bail};
 use capstone::prelude::*;
 
@@ -50,6 +50,54 @@ fn disassemble_instruction(diassembler: &Capstone, uc: &Unicorn<()>, pc: u64) ->
     return "??".to_string();
 }
 
+/// Returns " (function+offset)" when we have symbols, and "" otherwise.
+pub fn describe_addr(addr: u32) -> String {
+    crate::symbols::describe(addr)
+        .map(|s| format!(" ({})", s))
+        .unwrap_or_default()
+}
+
+/// Instruction fetches in these regions are MemManage faults, and not BusFaults.
+fn is_execute_never(addr: u32) -> bool {
+    (0x4000_0000..0x6000_0000).contains(&addr) || addr >= 0xA000_0000
+}
+
+fn is_code_addr(v: u32) -> bool {
+    (0x0800_0000..0x0810_0000).contains(&v)
+}
+
+/// Best effort backtrace: pc, lr, and whatever looks like a return address on the stack.
+pub fn backtrace(uc: &Unicorn<()>) -> Vec<u32> {
+    const MAX_STACK_WORDS: u64 = 64;
+    const MAX_DEPTH: usize = 8;
+
+    let pc = unsafe { LAST_INSTRUCTION.0 };
+    let lr = uc.reg_read(RegisterARM::LR).unwrap() as u32;
+    let sp = uc.reg_read(RegisterARM::SP).unwrap();
+
+    let mut frames = vec![pc];
+    if is_code_addr(lr) {
+        frames.push(lr & !1);
+    }
+
+    for i in 0..MAX_STACK_WORDS {
+        if frames.len() >= MAX_DEPTH {
+            break;
+        }
+        let mut v = [0,0,0,0];
+        if uc.mem_read(sp + 4*i, &mut v).is_err() {
+            break;
+        }
+        let v = u32::from_le_bytes(v);
+        // Return addresses have the thumb bit set
+        if is_code_addr(v) && v & 1 != 0 {
+            frames.push(v & !1);
+        }
+    }
+
+    frames
+}
+
 pub fn dump_stack(uc: &mut Unicorn<()>, count: usize) {
     let mut sp = uc.reg_read(RegisterARM::SP).unwrap();
 
@@ -61,9 +109,9 @@ pub fn dump_stack(uc: &mut Unicorn<()>, count: usize) {
         }
         let v = u32::from_le_bytes(v);
 
-        if (0x0800_0000..0x0810_0000).contains(&v) {
+        if is_code_addr(v) {
             // Probably a return address
-            info!("*** 0x{:08x} (sp=0x{:08x})", v, sp);
+            info!("*** 0x{:08x} (sp=0x{:08x}){}", v, sp, describe_addr(v & !1));
         } else {
             info!("    0x{:08x} (sp=0x{:08x})", v, sp);
         }
@@ -76,9 +124,12 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
     let mut uc = Unicorn::new(Arch::ARM, Mode::MCLASS | Mode::LITTLE_ENDIAN)
         .map_err(UniErr).context("Failed to initialize Unicorn instance")?;
 
-    let vector_table_addr = config.cpu.vector_table;
+    let mut watchpoints = config.watchpoints.clone().unwrap_or_default();
+    watchpoints.extend(args.watch.iter().cloned());
 
-    let (sys, framebuffers) = crate::system::prepare(&mut uc, config, svd_device)?;
+    let (sys, framebuffers, vector_table_addr) = crate::system::prepare(&mut uc, config, svd_device)?;
+    let ext_devices = sys.d.clone();
+    let peripherals = sys.p.clone();
 
     let diassembler = Capstone::new()
         .arm()
@@ -86,6 +137,13 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
         .build()
         .expect("failed to initialize capstone");
 
+    for w in &watchpoints {
+        sys.p.watchpoints.borrow_mut().add(&mut sys.uc.borrow_mut(), sys.p.clone(), w)?;
+    }
+
+    let gdb = args.gdb.map(|port| GdbServer::listen(port, sys.p.clone())).transpose()?
+        .map(|gdb| Rc::new(RefCell::new(gdb)));
+
     // We hook on each instructions, but we could skip this.
     // The slowdown is less than 50%. It's okay for now.
     {
@@ -94,10 +152,11 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
         let p = sys.p.clone();
         let d = sys.d.clone();
         let interrupt_period = args.interrupt_period;
+        let gdb = gdb.clone();
         sys.uc.borrow_mut().add_code_hook(0, u64::MAX, move |uc, pc, size| {
             unsafe {
                 if busy_loop_stop && LAST_INSTRUCTION.0 == pc as u32 {
-                    info!("Busy loop reached");
+                    info!("Busy loop reached{}", describe_addr(pc as u32));
                     uc.emu_stop().unwrap();
                     BUSY_LOOP_REACHED.store(true, Ordering::Release);
                 }
@@ -106,16 +165,43 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
 
             let n = NUM_INSTRUCTIONS.fetch_add(1, Ordering::Acquire);
 
+            let watchpoint_hit = p.watchpoints.borrow_mut().take_hit();
+            if watchpoint_hit.is_some() && gdb.is_none() {
+                info!("Watchpoint reached, stopping");
+                STOP_REQUESTED.store(true, Ordering::Relaxed);
+                uc.emu_stop().unwrap();
+                return;
+            }
+
+            if let Some(ref gdb) = gdb {
+                let mut gdb = gdb.borrow_mut();
+                let reason = watchpoint_hit.map(StopReason::Watchpoint)
+                    .or_else(|| gdb.should_stop(pc as u32, n));
+                if let Some(reason) = reason {
+                    gdb.handle_stop(uc, reason);
+                    if gdb.kill_requested {
+                        STOP_REQUESTED.store(true, Ordering::Relaxed);
+                        uc.emu_stop().unwrap();
+                        return;
+                    }
+                }
+            }
+
             if trace_instructions {
                 info!("{}", disassemble_instruction(&diassembler, uc, pc));
             }
 
+            if p.clock.borrow().has_due_event() {
+                let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                p.run_due_events(&sys);
+            }
+
             if n % interrupt_period as u64 == 0 {
                 let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
                 p.nvic.borrow_mut().run_pending_interrupts(&sys, vector_table_addr);
             }
 
-            if n & PUMP_EVENT_INST_INTERVAL == 0 {
+            if n.is_multiple_of(PUMP_EVENT_INST_INTERVAL) {
                 for fb in &framebuffers.sdls {
                     fb.borrow_mut().maybe_redraw();
                 }
@@ -123,6 +209,11 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
                     STOP_REQUESTED.store(true, Ordering::Relaxed);
                     uc.emu_stop().unwrap();
                 }
+
+                // Input events may have changed what external devices drive on the GPIOs.
+                // That's the only time their pins change, so edges are seen right away.
+                let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                p.poll_gpio_edges(&sys);
             }
         }).expect("add_code_hook failed");
     }
@@ -130,6 +221,7 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
     {
         let p = sys.p.clone();
         let d = sys.d.clone();
+        let faults = args.faults;
         sys.uc.borrow_mut().add_intr_hook(move |uc, exception| {
             match exception {
                 /*
@@ -158,9 +250,25 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
                 8 => {
                     // Return from interrupt
                     let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
-                    p.nvic.borrow_mut().return_from_interrupt(&sys);
+                    p.nvic.borrow_mut().return_from_interrupt(&sys, vector_table_addr);
                     p.nvic.borrow_mut().run_pending_interrupts(&sys, vector_table_addr);
                 }
+                1 | 3 | 4 | 7 | 17 | 18 | 22 if faults => {
+                    let (fault, status) = match exception {
+                        1 => (Fault::Usage, cfsr::UNDEFINSTR),
+                        3 => (Fault::Bus, cfsr::IBUSERR),
+                        4 => (Fault::Bus, cfsr::PRECISERR),
+                        7 => (Fault::Hard, hfsr::DEBUGEVT),
+                        17 => (Fault::Usage, cfsr::NOCP),
+                        18 => (Fault::Usage, cfsr::INVSTATE),
+                        _ => (Fault::Usage, cfsr::UNALIGNED),
+                    };
+                    let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                    if !p.nvic.borrow_mut().raise_fault(&sys, vector_table_addr, fault, status, None) {
+                        STOP_REQUESTED.store(true, Ordering::Relaxed);
+                        sys.uc.borrow_mut().emu_stop().unwrap();
+                    }
+                }
                 3 => {
                     error!("intr_hook intno={:08x}", exception);
                 }
@@ -172,23 +280,46 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
         }).expect("add_intr_hook failed");
     }
 
-    uc.add_mem_hook(HookType::MEM_UNMAPPED, 0, u64::MAX, |uc, type_, addr, size, value| {
-        if type_ == MemType::WRITE_UNMAPPED {
-            warn!("{:?} addr=0x{:08x} size={} value=0x{:08x}", type_, addr, size, value);
-        } else {
-            warn!("{:?} addr=0x{:08x} size={}", type_, addr, size);
-        }
+    {
+        let p = sys.p.clone();
+        let d = sys.d.clone();
+        let faults = args.faults;
+        sys.uc.borrow_mut().add_mem_hook(HookType::MEM_UNMAPPED, 0, u64::MAX, move |uc, type_, addr, size, value| {
+            if type_ == MemType::WRITE_UNMAPPED {
+                warn!("{:?} addr=0x{:08x} size={} value=0x{:08x}", type_, addr, size, value);
+            } else {
+                warn!("{:?} addr=0x{:08x} size={}", type_, addr, size);
+            }
 
-        unsafe {
-            let pc = uc.reg_read(RegisterARM::PC).expect("failed to get pc");
-            assert!(pc as u32 == LAST_INSTRUCTION.0);
-            uc.reg_write(RegisterARM::PC, thumb(pc as u64 + LAST_INSTRUCTION.1 as u64)).unwrap();
-        }
+            if faults {
+                let addr = addr as u32;
+                let (fault, status, fault_addr) = match type_ {
+                    MemType::FETCH_UNMAPPED if is_execute_never(addr) => (Fault::MemManage, cfsr::IACCVIOL, None),
+                    MemType::FETCH_UNMAPPED => (Fault::Bus, cfsr::IBUSERR, None),
+                    _ => (Fault::Bus, cfsr::PRECISERR, Some(addr)),
+                };
+
+                // The PC is still on the faulting instruction, which is what gets stacked.
+                let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                if p.nvic.borrow_mut().raise_fault(&sys, vector_table_addr, fault, status, fault_addr) {
+                    CONTINUE_EXECUTION.store(true, Ordering::Release);
+                } else {
+                    STOP_REQUESTED.store(true, Ordering::Relaxed);
+                }
+                return false;
+            }
 
-        CONTINUE_EXECUTION.store(true, Ordering::Release);
+            unsafe {
+                let pc = uc.reg_read(RegisterARM::PC).expect("failed to get pc");
+                assert!(pc as u32 == LAST_INSTRUCTION.0);
+                uc.reg_write(RegisterARM::PC, thumb(pc + LAST_INSTRUCTION.1 as u64)).unwrap();
+            }
 
-        false
-    }).expect("add_mem_hook failed");
+            CONTINUE_EXECUTION.store(true, Ordering::Release);
+
+            false
+        }).expect("add_mem_hook failed");
+    }
 
     let vector_table = VectorTable::from_memory(&uc, vector_table_addr)?;
     let mut pc = vector_table.reset as u64;
@@ -243,6 +374,10 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
         }
     }
 
+    if let Some(gdb) = gdb {
+        gdb.borrow_mut().finish(&mut uc);
+    }
+
     if let Some(n) = args.dump_stack {
         dump_stack(&mut uc, n);
     }
@@ -251,5 +386,15 @@ pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result
         fb.borrow().write_to_disk()?;
     }
 
+    for flash in &ext_devices.spi_flashes {
+        flash.borrow().save()?;
+    }
+
+    for eeprom in &ext_devices.eeproms {
+        eeprom.borrow().save()?;
+    }
+
+    peripherals.dac.borrow_mut().finish_recording()?;
+
     Ok(())
 }
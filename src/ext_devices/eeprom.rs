@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::I2cDevice;
+
+// Implements an AT24Cxx I2C EEPROM.
+// Small parts (up to 24C16) use a single address byte, and the upper address
+// bits are taken from the device address, so they occupy several addresses on the bus.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct EepromConfig {
+    pub peripheral: String,
+    /// 7-bit address. Defaults to 0x50.
+    pub address: Option<u8>,
+    /// Size in bytes, e.g. 256 for a 24C02
+    pub size: usize,
+    /// Defaults to 8 bytes for parts up to 2KB, and 32 bytes above.
+    pub page_size: Option<usize>,
+    /// Number of address bytes. Defaults to 1 for parts up to 2KB, and 2 above.
+    pub address_bytes: Option<usize>,
+    /// Content is loaded from this file if it exists, and saved back at exit
+    pub file: String,
+}
+
+#[derive(Default)]
+pub struct Eeprom {
+    pub config: EepromConfig,
+    name: String,
+    content: Vec<u8>,
+    dirty: bool,
+
+    base_address: u8,
+    // Device address bits used as upper memory address bits
+    address_mask: u8,
+    page_size: usize,
+    address_bytes: usize,
+
+    // Internal address counter
+    addr: usize,
+    // Address bytes received since the start condition
+    address_received: Vec<u8>,
+    // Data bytes of a write, committed on stop
+    write_buffer: Vec<(usize, u8)>,
+}
+
+const DEFAULT_ADDRESS: u8 = 0x50;
+
+impl Eeprom {
+    pub fn new(config: EepromConfig) -> Result<Self> {
+        let size = config.size;
+        if size == 0 || !size.is_power_of_two() {
+            bail!("eeprom {}: size must be a power of two", config.file);
+        }
+
+        let address_bytes = config.address_bytes.unwrap_or(if size <= 2048 { 1 } else { 2 });
+        let page_size = config.page_size.unwrap_or(if size <= 2048 { 8 } else { 32 });
+        if !page_size.is_power_of_two() {
+            bail!("eeprom {}: page_size must be a power of two", config.file);
+        }
+        // The device address has only 3 bits to select 256 byte blocks
+        if address_bytes == 1 && size > 2048 {
+            bail!("eeprom {}: parts larger than 2KB need 2 address bytes", config.file);
+        }
+
+        // With a single address byte, blocks of 256 bytes are selected with the device address
+        let address_mask = if address_bytes == 1 { ((size.max(256) / 256) - 1) as u8 } else { 0 };
+        let base_address = config.address.unwrap_or(DEFAULT_ADDRESS) & !address_mask;
+
+        let mut content = match std::fs::read(&config.file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", config.file)),
+        };
+        // A blank EEPROM reads as 0xFF
+        content.resize(size, 0xFF);
+
+        Ok(Self {
+            config, content, base_address, address_mask, page_size, address_bytes,
+            ..Self::default()
+        })
+    }
+
+    /// Saves the content if it was modified
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        std::fs::write(&self.config.file, &self.content)
+            .with_context(|| format!("Failed to write {}", self.config.file))?;
+        info!("{} wrote content to {}", self.name, self.config.file);
+        Ok(())
+    }
+}
+
+impl I2cDevice for Eeprom {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} eeprom", peri_name);
+        self.name.clone()
+    }
+
+    fn has_address(&self, addr: u8) -> bool {
+        addr & !self.address_mask == self.base_address
+    }
+
+    fn start(&mut self, _sys: &System, addr: u8, _read: bool) -> bool {
+        // Upper bits of the memory address, for small parts
+        if self.address_bytes == 1 {
+            let block = (addr & self.address_mask) as usize;
+            self.addr = (block << 8) | (self.addr & 0xFF);
+        }
+        self.address_received.clear();
+        true
+    }
+
+    fn write(&mut self, _sys: &System, v: u8) -> bool {
+        if self.address_received.len() < self.address_bytes {
+            self.address_received.push(v);
+            if self.address_received.len() == self.address_bytes {
+                let low = self.address_received.iter().fold(0, |addr, b| (addr << 8) | *b as usize);
+                let block = if self.address_bytes == 1 { self.addr & !0xFF } else { 0 };
+                self.addr = (block | low) % self.config.size;
+            }
+            return true;
+        }
+
+        self.write_buffer.push((self.addr, v));
+
+        // The address wraps within the page
+        let page = self.addr & !(self.page_size - 1);
+        self.addr = page + ((self.addr + 1) % self.page_size);
+        true
+    }
+
+    fn read(&mut self, _sys: &System) -> u8 {
+        let v = self.content[self.addr];
+        self.addr = (self.addr + 1) % self.config.size;
+        v
+    }
+
+    fn stop(&mut self, _sys: &System) {
+        // The write cycle starts on stop
+        if !self.write_buffer.is_empty() {
+            debug!("{} write addr=0x{:04x} len={}", self.name, self.write_buffer[0].0, self.write_buffer.len());
+            for (addr, v) in self.write_buffer.drain(..) {
+                self.content[addr] = v;
+            }
+            self.dirty = true;
+        }
+    }
+}
@@ -2,15 +2,23 @@
 
 mod spi_flash;
 mod usart_probe;
+mod usart_pty;
+mod usart_tcp;
+mod usart_feeder;
 mod display;
 mod lcd;
 mod touchscreen;
+mod eeprom;
 
 use spi_flash::{SpiFlashConfig, SpiFlash};
 use usart_probe::{UsartProbeConfig, UsartProbe};
+use usart_pty::{UsartPtyConfig, UsartPty};
+use usart_tcp::{UsartTcpConfig, UsartTcp};
+use usart_feeder::{UsartFeederConfig, UsartFeeder};
 use display::{DisplayConfig, Display};
 use lcd::{LcdConfig, Lcd};
 use touchscreen::{TouchscreenConfig, Touchscreen};
+use eeprom::{EepromConfig, Eeprom};
 
 use std::{rc::Rc, cell::RefCell};
 use serde::Deserialize;
@@ -23,17 +31,25 @@ use crate::{system::System, framebuffers::Framebuffers, peripherals::gpio::GpioP
 pub struct ExtDevicesConfig {
     pub spi_flash: Option<Vec<SpiFlashConfig>>,
     pub usart_probe: Option<Vec<UsartProbeConfig>>,
+    pub usart_pty: Option<Vec<UsartPtyConfig>>,
+    pub usart_tcp: Option<Vec<UsartTcpConfig>>,
+    pub usart_feeder: Option<Vec<UsartFeederConfig>>,
     pub display: Option<Vec<DisplayConfig>>,
     pub lcd: Option<Vec<LcdConfig>>,
     pub touchscreen: Option<Vec<TouchscreenConfig>>,
+    pub eeprom: Option<Vec<EepromConfig>>,
 }
 
 pub struct ExtDevices {
     pub spi_flashes: Vec<Rc<RefCell<SpiFlash>>>,
     pub usart_probes: Vec<Rc<RefCell<UsartProbe>>>,
+    pub usart_ptys: Vec<Rc<RefCell<UsartPty>>>,
+    pub usart_tcps: Vec<Rc<RefCell<UsartTcp>>>,
+    pub usart_feeders: Vec<Rc<RefCell<UsartFeeder>>>,
     pub displays: Vec<Rc<RefCell<Display>>>,
     pub lcds: Vec<Rc<RefCell<Lcd>>>,
     pub touchscreens: Vec<Rc<RefCell<Touchscreen>>>,
+    pub eeproms: Vec<Rc<RefCell<Eeprom>>>,
 }
 
 impl ExtDevices {
@@ -47,6 +63,21 @@ impl ExtDevices {
             .filter(|d| d.borrow().config.peripheral == peri_name)
             .next()
             .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.usart_ptys.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.usart_tcps.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.usart_feeders.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
        )
         .or_else(||
         self.lcds.iter()
@@ -62,6 +93,14 @@ impl ExtDevices {
        )
     }
 
+    /// All the devices on the bus of an I2C peripheral
+    pub fn find_i2c_devices(&self, peri_name: &str) -> Vec<Rc<RefCell<dyn I2cDevice>>> {
+        self.eeproms.iter()
+            .filter(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn I2cDevice>>)
+            .collect()
+    }
+
     pub fn find_mem_device(&self, peri_name: &str) -> Option<Rc<RefCell<dyn ExtDevice<u32, u32>>>> {
         self.displays.iter()
             .filter(|d| d.borrow().config.peripheral == peri_name)
@@ -73,13 +112,25 @@ impl ExtDevices {
 impl ExtDevicesConfig {
     pub fn into_ext_devices(self, gpio: &mut GpioPorts, framebuffers: &Framebuffers) -> Result<ExtDevices> {
         let spi_flashes = self.spi_flash.unwrap_or_default().into_iter()
-            .map(|config| SpiFlash::new(config).map(RefCell::new).map(Rc::new))
+            .map(|config| SpiFlash::new(config, gpio).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
 
         let usart_probes = self.usart_probe.unwrap_or_default().into_iter()
             .map(|config| UsartProbe::new(config).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
 
+        let usart_ptys = self.usart_pty.unwrap_or_default().into_iter()
+            .map(|config| UsartPty::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let usart_tcps = self.usart_tcp.unwrap_or_default().into_iter()
+            .map(|config| UsartTcp::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let usart_feeders = self.usart_feeder.unwrap_or_default().into_iter()
+            .map(|config| UsartFeeder::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
         let displays = self.display.unwrap_or_default().into_iter()
             .map(|config| Display::new(config, framebuffers).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
@@ -92,7 +143,11 @@ impl ExtDevicesConfig {
             .map(|config| Touchscreen::new(config, gpio, framebuffers).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
 
-        Ok(ExtDevices { spi_flashes, usart_probes, displays, lcds, touchscreens })
+        let eeproms = self.eeprom.unwrap_or_default().into_iter()
+            .map(|config| Eeprom::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        Ok(ExtDevices { spi_flashes, usart_probes, usart_ptys, usart_tcps, usart_feeders, displays, lcds, touchscreens, eeproms })
     }
 }
 
@@ -103,4 +158,22 @@ pub trait ExtDevice<A, T> {
     fn connect_peripheral<'a>(&mut self, peri_name: &str) -> String;
     fn read(&mut self, sys: &System, addr: A) -> T;
     fn write(&mut self, sys: &System, addr: A, v: T);
+
+    /// For serial devices: true when there's data for the peripheral to read.
+    /// This drives the RXNE flag of USARTs.
+    fn rx_available(&mut self, _sys: &System) -> bool { false }
+}
+
+pub trait I2cDevice {
+    /// Should returns "{peri_name} {ext_device_name}"
+    fn connect_peripheral(&mut self, peri_name: &str) -> String;
+    /// True when the device responds to this 7-bit address
+    fn has_address(&self, addr: u8) -> bool;
+    /// The device is addressed after a (repeated) start condition.
+    /// Returns true when the device acknowledges.
+    fn start(&mut self, sys: &System, addr: u8, read: bool) -> bool;
+    /// Returns true when the device acknowledges.
+    fn write(&mut self, sys: &System, v: u8) -> bool;
+    fn read(&mut self, sys: &System) -> u8;
+    fn stop(&mut self, sys: &System);
 }
@@ -2,11 +2,12 @@
 
 use std::collections::VecDeque;
 use std::convert::TryFrom;
+use std::{rc::Rc, cell::Cell};
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
 
-use crate::{util, system::System};
+use crate::{util, system::System, peripherals::gpio::{GpioPorts, Pin}};
 
 use super::ExtDevice;
 
@@ -16,6 +17,17 @@ pub struct SpiFlashConfig {
     pub jedec_id: u32,
     pub file: String,
     pub size: usize,
+    /// Chip select pin, e.g. PB12. It tells us when commands end. Without it,
+    /// page programs end at the end of the page.
+    pub cs: Option<String>,
+    /// Write the modified content back to `file` at exit
+    pub write_back: Option<bool>,
+    /// Copy-on-write overlay. Content is loaded from this file when it exists,
+    /// and modifications are saved to it at exit. `file` is left untouched.
+    pub overlay: Option<String>,
+    /// Content of the SFDP table, read with the 0x5A command. When not given,
+    /// a basic table is generated from the size.
+    pub sfdp: Option<Vec<u8>>,
 }
 
 #[derive(Default)]
@@ -23,20 +35,146 @@ pub struct SpiFlash {
     pub config: SpiFlashConfig,
     name: String,
     content: Vec<u8>,
+    dirty: bool,
+
+    // Status register
+    write_enabled: bool,
+    four_byte_address: bool,
+    powered_down: bool,
+    reset_enabled: bool,
+    sfdp: Vec<u8>,
+
+    // Set by the GPIO callback when the chip is deselected
+    cs_released: Rc<Cell<bool>>,
 
     reply: Option<Reply>,
     /// Command and arguments
     cmd: Option<(Command, Vec<u8>)>,
 }
 
+// Status register bits
+const SR_WIP: u8 = 1 << 0;
+const SR_WEL: u8 = 1 << 1;
+
+const PAGE_SIZE: usize = 256;
+
 impl SpiFlash {
-    pub fn new(config: SpiFlashConfig) -> Result<Self> {
-        let mut content = util::read_file(&config.file)
-            .with_context(|| format!("Failed to read {}", &config.file))?;
+    pub fn new(config: SpiFlashConfig, gpio: &mut GpioPorts) -> Result<Self> {
+        let path = config.overlay.as_ref()
+            .filter(|overlay| std::path::Path::new(overlay).exists())
+            .unwrap_or(&config.file);
+
+        let mut content = util::read_file(path)
+            .with_context(|| format!("Failed to read {}", path))?;
+
+        content.resize(config.size, 0xFF);
+
+        let cs_released = Rc::new(Cell::new(false));
+        if let Some(ref cs) = config.cs {
+            let cs_released = cs_released.clone();
+            gpio.add_write_callback(Pin::from_str(cs), move |_sys, v| {
+                if v {
+                    cs_released.set(true);
+                }
+            });
+        }
+
+        let sfdp = config.sfdp.clone().unwrap_or_else(|| default_sfdp(config.size));
+
+        Ok(Self { config, content, cs_released, sfdp, ..Self::default() })
+    }
+
+    /// Saves the content if it was modified, according to the config
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
 
-        content.resize(config.size, 0);
+        let path = match (&self.config.overlay, self.config.write_back.unwrap_or(false)) {
+            (Some(overlay), _) => overlay,
+            (None, true) => &self.config.file,
+            (None, false) => return Ok(()),
+        };
 
-        Ok(Self { config, content, ..Self::default() })
+        std::fs::write(path, &self.content)
+            .with_context(|| format!("Failed to write {}", path))?;
+        info!("{} wrote content to {}", self.name, path);
+        Ok(())
+    }
+
+    /// When the chip gets deselected, the current command ends.
+    fn check_cs(&mut self) {
+        if self.cs_released.take() {
+            if let Some(Reply::Program(_)) = self.reply {
+                self.write_enabled = false;
+            }
+            self.cmd = None;
+            self.reply = None;
+        }
+    }
+
+    fn status(&self) -> u8 {
+        // Programs and erases are instantaneous, so WIP is never set
+        let mut v = 0;
+        if self.write_enabled { v |= SR_WEL; }
+        v & !SR_WIP
+    }
+
+    fn address(&self, cmd: Command, bytes: &[u8]) -> usize {
+        let addr = bytes.iter().fold(0, |addr, b| (addr << 8) | *b as usize);
+        if addr >= self.config.size {
+            warn!("{} cmd={:?} addr=0x{:06x} larger than size={:06x}",
+                self.name, cmd, addr, self.config.size);
+        }
+        addr % self.config.size
+    }
+
+    /// Number of address bytes of a command
+    fn address_len(&self, cmd: Command) -> usize {
+        if cmd.is_4byte() || self.four_byte_address { 4 } else { 3 }
+    }
+
+    /// Returns the address once the address and the `dummy` bytes are received
+    fn parse_address(&self, cmd: Command, args: &[u8], dummy: usize) -> Option<usize> {
+        let len = self.address_len(cmd);
+        if args.len() == len + dummy {
+            Some(self.address(cmd, &args[..len]))
+        } else {
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        self.write_enabled = false;
+        self.four_byte_address = false;
+        self.powered_down = false;
+        self.cmd = None;
+        self.reply = None;
+    }
+
+    fn is_writable(&self, cmd: Command) -> bool {
+        if !self.write_enabled {
+            debug!("{} cmd={:?} ignored, write is not enabled", self.name, cmd);
+        }
+        self.write_enabled
+    }
+
+    fn erase(&mut self, cmd: Command, addr: usize, len: usize) {
+        if !self.is_writable(cmd) {
+            return;
+        }
+
+        let start = addr & !(len - 1);
+        let end = (start + len).min(self.content.len());
+        self.content[start..end].fill(0xFF);
+        self.dirty = true;
+        self.write_enabled = false;
+    }
+
+    fn program(&mut self, addr: usize, v: u8) {
+        // Programming can only clear bits
+        self.content[addr] &= v;
+        self.dirty = true;
     }
 }
 
@@ -47,6 +185,8 @@ impl ExtDevice<(), u8> for SpiFlash {
     }
 
     fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        self.check_cs();
+
         match self.reply.as_mut() {
             Some(Reply::Data(d)) => {
                 d.pop_front().unwrap_or_default()
@@ -57,11 +197,42 @@ impl ExtDevice<(), u8> for SpiFlash {
                 *addr = (*addr + 1) % self.config.size;
                 c
             }
-            None => 0,
+            Some(Reply::Status) => self.status(),
+            Some(Reply::Sfdp(addr)) => {
+                let c = self.sfdp.get(*addr).cloned().unwrap_or(0xFF);
+                *addr += 1;
+                c
+            }
+            Some(Reply::Program(_)) | Some(Reply::Ignore(_)) | None => 0,
         }
     }
 
     fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        self.check_cs();
+
+        if let Some(Reply::Program(addr) | Reply::Ignore(addr)) = self.reply {
+            let program = matches!(self.reply, Some(Reply::Program(_)));
+            if program {
+                self.program(addr, v);
+            }
+
+            // The address wraps within the page
+            let addr = (addr & !(PAGE_SIZE - 1)) + ((addr + 1) % PAGE_SIZE);
+            self.reply = if self.config.cs.is_none() && addr.is_multiple_of(PAGE_SIZE) {
+                // Without chip select, we consider the end of the page to be
+                // the end of the command.
+                if program {
+                    self.write_enabled = false;
+                }
+                None
+            } else if program {
+                Some(Reply::Program(addr))
+            } else {
+                Some(Reply::Ignore(addr))
+            };
+            return;
+        }
+
         if let Some((cmd, mut args)) = self.cmd.take() {
             // We are collecting a command argument
             args.push(v);
@@ -72,6 +243,16 @@ impl ExtDevice<(), u8> for SpiFlash {
             }
         } else if let Some(cmd) = Command::try_from(v).ok() {
             // We are receiving a new command
+            let reset_enabled = std::mem::replace(&mut self.reset_enabled, false);
+            if self.powered_down && !matches!(cmd, Command::ReleasePowerDown) {
+                debug!("{} cmd={:?} ignored, powered down", self.name, cmd);
+                return;
+            }
+            if matches!(cmd, Command::Reset) && !reset_enabled {
+                debug!("{} cmd={:?} ignored, reset is not enabled", self.name, cmd);
+                return;
+            }
+
             if let Some(reply) = self.try_process_command(cmd, &[]) {
                 self.reply = Some(reply);
             } else {
@@ -87,29 +268,114 @@ impl SpiFlash {
     /// Return some reply when the command is processed.
     /// None when command arguments are incomplete.
     fn try_process_command(&mut self, cmd: Command, args: &[u8]) -> Option<Reply> {
-        match (cmd, args) {
-            (Command::ReadJEDECID, []) => {
+        let no_reply = || Some(Reply::Data(VecDeque::new()));
+
+        match cmd {
+            Command::ReadJEDECID => {
                 let id = self.config.jedec_id;
                 let data = id.to_be_bytes();
                 Some(Reply::Data(data.into()))
             }
-            (Command::ReadDeviceID, []) => {
+            Command::ReadDeviceID => {
                 let id: u32 = 0xAABBCC;
                 let data = id.to_be_bytes();
                 Some(Reply::Data(data.into()))
             }
-            (Command::ReadData, [a,b,c]) => {
-                let mut addr = u32::from_be_bytes([0,*a,*b,*c]) as usize;
-
-                if addr >= self.config.size {
-                    warn!("{} cmd={:?} addr=0x{:06x} larger than size={:06x}",
-                        self.name, cmd, addr, self.config.size);
-                    addr = addr % self.config.size;
+            Command::ReadData | Command::ReadData4B => {
+                self.parse_address(cmd, args, 0).map(Reply::FileContent)
+            }
+            // Dual and quad reads send the data on more lines. For us, it's just bytes.
+            Command::FastRead | Command::FastRead4B |
+            Command::FastReadDualOutput | Command::FastReadDualOutput4B |
+            Command::FastReadQuadOutput | Command::FastReadQuadOutput4B => {
+                self.parse_address(cmd, args, 1).map(Reply::FileContent)
+            }
+            Command::ReadSfdp => {
+                // Always a 3 byte address, followed by a dummy byte
+                match args {
+                    [a,b,c,_dummy] => Some(Reply::Sfdp(u32::from_be_bytes([0,*a,*b,*c]) as usize)),
+                    _ => None,
                 }
-
-                Some(Reply::FileContent(addr))
             }
-            _ => None,
+            Command::WriteEnable => {
+                self.write_enabled = true;
+                no_reply()
+            }
+            Command::WriteDisable => {
+                self.write_enabled = false;
+                no_reply()
+            }
+            Command::ReadStatus => {
+                // The status is sent repeatedly while the chip is selected
+                Some(Reply::Status)
+            }
+            Command::ReadStatus2 => {
+                Some(Reply::Data(VecDeque::from(vec![0])))
+            }
+            Command::ReadStatus3 => {
+                // ADS bit: current address mode
+                let v = if self.four_byte_address { 1 } else { 0 };
+                Some(Reply::Data(VecDeque::from(vec![v])))
+            }
+            Command::WriteStatus => {
+                // We don't implement block protection
+                if args.is_empty() {
+                    return None;
+                }
+                self.write_enabled = false;
+                no_reply()
+            }
+            Command::PageProgram | Command::PageProgram4B => {
+                let addr = self.parse_address(cmd, args, 0)?;
+                if self.is_writable(cmd) {
+                    Some(Reply::Program(addr))
+                } else {
+                    // The data bytes must not be taken as commands
+                    Some(Reply::Ignore(addr))
+                }
+            }
+            Command::SectorErase | Command::SectorErase4B |
+            Command::BlockErase32 | Command::BlockErase64 | Command::BlockErase64_4B => {
+                let addr = self.parse_address(cmd, args, 0)?;
+                let len = match cmd {
+                    Command::BlockErase32 => 32*1024,
+                    Command::BlockErase64 | Command::BlockErase64_4B => 64*1024,
+                    _ => 4*1024,
+                };
+                self.erase(cmd, addr, len);
+                no_reply()
+            }
+            Command::ChipErase | Command::ChipErase2 => {
+                let size = self.config.size.next_power_of_two();
+                self.erase(cmd, 0, size);
+                no_reply()
+            }
+            Command::Enter4ByteAddress => {
+                self.four_byte_address = true;
+                no_reply()
+            }
+            Command::Exit4ByteAddress => {
+                self.four_byte_address = false;
+                no_reply()
+            }
+            Command::PowerDown => {
+                self.powered_down = true;
+                no_reply()
+            }
+            Command::ReleasePowerDown => {
+                // The device ID comes after 3 dummy bytes
+                self.powered_down = false;
+                let id = (self.config.jedec_id as u8).wrapping_sub(1);
+                Some(Reply::Data(VecDeque::from(vec![0, 0, 0, id])))
+            }
+            Command::ResetEnable => {
+                self.reset_enabled = true;
+                no_reply()
+            }
+            Command::Reset => {
+                self.reset();
+                no_reply()
+            }
         }.map(|reply| {
             debug!("{} cmd={:?} args={:02x?} reply={:02x?}",
                 self.name, cmd, args, reply);
@@ -118,16 +384,101 @@ impl SpiFlash {
     }
 }
 
+/// Generates a JESD216 SFDP table with the basic flash parameters
+fn default_sfdp(size: usize) -> Vec<u8> {
+    const BFPT_OFFSET: u32 = 0x10;
+    const BFPT_DWORDS: u32 = 9;
+
+    // Flashes larger than 16MB support 3 and 4 byte addresses
+    let address_bytes = if size > 16*1024*1024 { 0b01 } else { 0b00 };
+    let density_bits = (size as u64 * 8).saturating_sub(1).min(0x7FFF_FFFF) as u32;
+
+    let dwords: [u32; BFPT_DWORDS as usize] = [
+        // 4K erase with 0x20, write granularity >= 64 bytes, 1-1-2 and 1-1-4 fast reads
+        0b01 | (1 << 2) | (0x20 << 8) | (1 << 16) | (address_bytes << 17) | (1 << 22),
+        density_bits,
+        // 1-1-4 fast read: 0x6B with 8 dummy clocks
+        (0x6B << 24) | (8 << 16),
+        // 1-1-2 fast read: 0x3B with 8 dummy clocks
+        (0x3B << 8) | 8,
+        // No 2-2-2 or 4-4-4 reads
+        0xFFFF_FFEE,
+        0xFFFF_FFFF,
+        0xFFFF_FFFF,
+        // Erase types: 4K with 0x20, 32K with 0x52
+        0x520F_200C,
+        // Erase types: 64K with 0xD8
+        0x0000_D810,
+    ];
+
+    let mut table = vec![];
+    // SFDP header: signature, revision 1.6, one parameter header
+    table.extend(b"SFDP");
+    table.extend([0x06, 0x01, 0x00, 0xFF]);
+    // Basic flash parameter table header
+    table.extend([0x00, 0x06, 0x01, BFPT_DWORDS as u8]);
+    table.extend(&BFPT_OFFSET.to_le_bytes()[..3]);
+    table.push(0xFF);
+
+    for dword in dwords {
+        table.extend(dword.to_le_bytes());
+    }
+    table
+}
+
 #[derive(Debug, Clone, Copy, num_enum::TryFromPrimitive)]
 #[repr(u8)]
 enum Command {
+    WriteStatus = 0x01,
+    PageProgram = 0x02,
     ReadData = 0x03,
-    ReadJEDECID = 0x9F,
+    WriteDisable = 0x04,
+    ReadStatus = 0x05,
+    WriteEnable = 0x06,
+    FastRead = 0x0B,
+    FastRead4B = 0x0C,
+    PageProgram4B = 0x12,
+    ReadData4B = 0x13,
+    ReadStatus3 = 0x15,
+    SectorErase = 0x20,
+    SectorErase4B = 0x21,
+    ReadStatus2 = 0x35,
+    FastReadDualOutput = 0x3B,
+    FastReadDualOutput4B = 0x3C,
+    BlockErase32 = 0x52,
+    ReadSfdp = 0x5A,
+    ChipErase2 = 0x60,
+    ResetEnable = 0x66,
+    FastReadQuadOutput = 0x6B,
+    FastReadQuadOutput4B = 0x6C,
     ReadDeviceID = 0x90,
+    Reset = 0x99,
+    ReadJEDECID = 0x9F,
+    ReleasePowerDown = 0xAB,
+    Enter4ByteAddress = 0xB7,
+    PowerDown = 0xB9,
+    ChipErase = 0xC7,
+    BlockErase64 = 0xD8,
+    BlockErase64_4B = 0xDC,
+    Exit4ByteAddress = 0xE9,
+}
+
+impl Command {
+    /// Commands that always take a 4 byte address
+    fn is_4byte(&self) -> bool {
+        matches!(self,
+            Self::FastRead4B | Self::PageProgram4B | Self::ReadData4B |
+            Self::SectorErase4B | Self::FastReadDualOutput4B |
+            Self::FastReadQuadOutput4B | Self::BlockErase64_4B)
+    }
 }
 
 #[derive(Debug)]
 enum Reply {
     FileContent(usize), // address
     Data(VecDeque<u8>),
+    Status,
+    Program(usize), // address
+    Ignore(usize), // address of a refused program
+    Sfdp(usize), // address
 }
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+
+use anyhow::{Result, Context};
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::ExtDevice;
+
+// Feeds bytes to the receive side of a USART. The bytes come from a file,
+// from the config, or are sent when the firmware outputs some expected text.
+// What the firmware outputs is logged line by line, like the usart probe.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct UsartFeederConfig {
+    pub peripheral: String,
+    /// Content of this file is fed first
+    pub file: Option<String>,
+    /// Then this string
+    pub data: Option<String>,
+    /// Then, in order, each `send` is fed once the firmware has output `expect`
+    pub script: Option<Vec<ExpectSendConfig>>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ExpectSendConfig {
+    pub expect: String,
+    pub send: String,
+}
+
+#[derive(Default)]
+pub struct UsartFeeder {
+    pub config: UsartFeederConfig,
+    name: String,
+    tx: VecDeque<u8>,
+    // Index of the script step we are waiting on
+    script_step: usize,
+    // The last bytes the firmware has output, at most as long as the
+    // pending expect
+    output: VecDeque<u8>,
+    line: Vec<u8>,
+}
+
+impl UsartFeeder {
+    pub fn new(config: UsartFeederConfig) -> Result<Self> {
+        let mut tx = VecDeque::new();
+
+        if let Some(ref file) = config.file {
+            let content = std::fs::read(file)
+                .with_context(|| format!("Failed to read {}", file))?;
+            tx.extend(content);
+        }
+
+        if let Some(ref data) = config.data {
+            tx.extend(data.as_bytes());
+        }
+
+        Ok(Self { config, tx, ..Self::default() })
+    }
+
+    fn check_script(&mut self, v: u8) {
+        let steps = self.config.script.as_deref().unwrap_or_default();
+        if let Some(step) = steps.get(self.script_step) {
+            // Only keep what's needed to match the expect
+            self.output.push_back(v);
+            if self.output.len() > step.expect.len() {
+                self.output.pop_front();
+            }
+
+            if self.output.iter().eq(step.expect.as_bytes()) {
+                debug!("{} matched '{}', sending '{}'", self.name, step.expect.escape_default(), step.send.escape_default());
+                self.tx.extend(step.send.as_bytes());
+                self.script_step += 1;
+                self.output.clear();
+
+                if self.script_step == steps.len() {
+                    info!("{} script completed", self.name);
+                }
+            }
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for UsartFeeder {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} usart-feeder", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        self.tx.pop_front().unwrap_or_default()
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        if v == 0x0a {
+            // EOL
+            let line = String::from_utf8_lossy(&self.line);
+            let line = line.trim();
+            info!("{} '{}'", self.name, line);
+            self.line.clear();
+        } else {
+            self.line.push(v);
+        }
+
+        self.check_script(v);
+    }
+
+    fn rx_available(&mut self, _sys: &System) -> bool {
+        !self.tx.is_empty()
+    }
+}
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::VecDeque, fs::File, io::{Read, Write, ErrorKind}, os::unix::io::FromRawFd, ffi::CStr};
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::ExtDevice;
+
+// Connects a USART to a host pseudo-terminal. Use `screen /dev/pts/N` or any
+// other serial tool to talk to the firmware.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct UsartPtyConfig {
+    pub peripheral: String,
+    /// Creates a symlink to the pty at this path, so tools can use a stable name.
+    pub link: Option<String>,
+}
+
+pub struct UsartPty {
+    pub config: UsartPtyConfig,
+    name: String,
+    master: File,
+    // We keep the slave side open, otherwise reads on the master fail with EIO
+    // when no client is attached.
+    _slave: File,
+    rx: VecDeque<u8>,
+}
+
+impl UsartPty {
+    pub fn new(config: UsartPtyConfig) -> Result<Self> {
+        let (master, slave, path) = open_pty()?;
+
+        if let Some(ref link) = config.link {
+            // Replace the link left by a previous run, but nothing else
+            match std::fs::symlink_metadata(link) {
+                Ok(m) if m.file_type().is_symlink() => std::fs::remove_file(link)
+                    .with_context(|| format!("Failed to remove old symlink {}", link))?,
+                Ok(_) => bail!("{} exists and is not a symlink, not replacing it", link),
+                Err(_) => {}
+            }
+            std::os::unix::fs::symlink(&path, link)
+                .with_context(|| format!("Failed to create symlink {}", link))?;
+        }
+
+        info!("{} pty is at {}", config.peripheral, config.link.as_ref().unwrap_or(&path));
+
+        Ok(Self {
+            config,
+            name: "".to_string(), // filled up in connect_periperhal()
+            master,
+            _slave: slave,
+            rx: VecDeque::new(),
+        })
+    }
+
+    /// Pulls what the host has sent so far, without blocking
+    fn poll_rx(&mut self) {
+        let mut buf = [0u8; 256];
+        loop {
+            match self.master.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.rx.extend(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("{} read error: {}", self.name, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the (master, slave, slave path) of a new pty in raw mode
+fn open_pty() -> Result<(File, File, String)> {
+    unsafe {
+        let mut master = 0;
+        let mut slave = 0;
+        if libc::openpty(&mut master, &mut slave, std::ptr::null_mut(),
+                         std::ptr::null(), std::ptr::null()) < 0 {
+            bail!("openpty() failed: {}", std::io::Error::last_os_error());
+        }
+
+        let master_file = File::from_raw_fd(master);
+        let slave_file = File::from_raw_fd(slave);
+
+        // Raw mode: no echo, no line editing, no CR/LF translation. That's what a UART is.
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(slave, &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave, libc::TCSANOW, &termios);
+        }
+
+        let flags = libc::fcntl(master, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(master, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            bail!("Failed to make the pty non-blocking: {}", std::io::Error::last_os_error());
+        }
+
+        let path = libc::ptsname(master);
+        if path.is_null() {
+            bail!("ptsname() failed: {}", std::io::Error::last_os_error());
+        }
+        let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+
+        Ok((master_file, slave_file, path))
+    }
+}
+
+impl ExtDevice<(), u8> for UsartPty {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} usart-pty", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        self.poll_rx();
+        self.rx.pop_front().unwrap_or_default()
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        // When nobody reads the pty, its buffer fills up. We drop bytes
+        // rather than blocking the emulation, like a UART with no one listening.
+        match self.master.write(&[v]) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => warn!("{} write error: {}", self.name, e),
+        }
+    }
+
+    fn rx_available(&mut self, _sys: &System) -> bool {
+        if self.rx.is_empty() {
+            self.poll_rx();
+        }
+        !self.rx.is_empty()
+    }
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::VecDeque, io::{Read, Write, ErrorKind}, net::{TcpListener, TcpStream}};
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::ExtDevice;
+
+// Connects a USART to a TCP socket. Either we listen for a client (one at a
+// time), or we connect to a server.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct UsartTcpConfig {
+    pub peripheral: String,
+    /// Address to listen on, e.g. 127.0.0.1:5000
+    pub listen: Option<String>,
+    /// Address to connect to, e.g. 127.0.0.1:5000
+    pub connect: Option<String>,
+    /// When listening, wait for a client before starting the emulation,
+    /// so it doesn't miss anything the firmware sends at boot.
+    pub wait_for_client: Option<bool>,
+}
+
+pub struct UsartTcp {
+    pub config: UsartTcpConfig,
+    name: String,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    rx: VecDeque<u8>,
+}
+
+impl UsartTcp {
+    pub fn new(config: UsartTcpConfig) -> Result<Self> {
+        let (listener, stream) = match (&config.listen, &config.connect) {
+            (Some(addr), None) => {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("Failed to listen on {}", addr))?;
+                info!("{} listening on {}", config.peripheral, addr);
+
+                let stream = if config.wait_for_client.unwrap_or(false) {
+                    info!("{} waiting for a client", config.peripheral);
+                    let (stream, peer) = listener.accept()?;
+                    info!("{} client connected from {}", config.peripheral, peer);
+                    Some(stream)
+                } else {
+                    None
+                };
+
+                listener.set_nonblocking(true)?;
+                (Some(listener), stream)
+            }
+            (None, Some(addr)) => {
+                let stream = TcpStream::connect(addr)
+                    .with_context(|| format!("Failed to connect to {}", addr))?;
+                info!("{} connected to {}", config.peripheral, addr);
+                (None, Some(stream))
+            }
+            _ => bail!("usart_tcp {}: exactly one of `listen` or `connect` must be given", config.peripheral),
+        };
+
+        if let Some(ref stream) = stream {
+            Self::setup_stream(stream)?;
+        }
+
+        Ok(Self {
+            config,
+            name: "".to_string(), // filled up in connect_periperhal()
+            listener,
+            stream,
+            rx: VecDeque::new(),
+        })
+    }
+
+    fn setup_stream(stream: &TcpStream) -> Result<()> {
+        stream.set_nonblocking(true)?;
+        // Bytes are sent one at a time, we don't want them to wait.
+        stream.set_nodelay(true)?;
+        Ok(())
+    }
+
+    fn accept_client(&mut self) {
+        if self.stream.is_some() {
+            return;
+        }
+
+        if let Some(ref listener) = self.listener {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(e) = Self::setup_stream(&stream) {
+                        warn!("{} failed to setup client: {}", self.name, e);
+                        return;
+                    }
+                    info!("{} client connected from {}", self.name, peer);
+                    self.stream = Some(stream);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => warn!("{} accept error: {}", self.name, e),
+            }
+        }
+    }
+
+    fn disconnect(&mut self, reason: &str) {
+        info!("{} {}", self.name, reason);
+        self.stream = None;
+    }
+
+    /// Pulls what the host has sent so far, without blocking
+    fn poll_rx(&mut self) {
+        self.accept_client();
+
+        let mut buf = [0u8; 256];
+        while let Some(ref mut stream) = self.stream {
+            match stream.read(&mut buf) {
+                Ok(0) => self.disconnect("client disconnected"),
+                Ok(n) => self.rx.extend(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => self.disconnect(&format!("read error: {}", e)),
+            }
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for UsartTcp {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} usart-tcp", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        self.poll_rx();
+        self.rx.pop_front().unwrap_or_default()
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        self.accept_client();
+
+        // Without a client, bytes are lost, like a UART with no one listening.
+        if let Some(ref mut stream) = self.stream {
+            match stream.write(&[v]) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => self.disconnect(&format!("write error: {}", e)),
+            }
+        }
+    }
+
+    fn rx_available(&mut self, _sys: &System) -> bool {
+        if self.rx.is_empty() {
+            self.poll_rx();
+        }
+        !self.rx.is_empty()
+    }
+}
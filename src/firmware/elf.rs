@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Minimal ELF32 little-endian parser. Just enough to load ARM firmware images:
+// loadable segments, the entry point, and the symbol table.
+
+use anyhow::{bail, Context, Result};
+
+use crate::symbols::Symbol;
+use super::{Firmware, Segment};
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+const VECTOR_TABLE_SECTIONS: [&str; 4] = [".isr_vector", ".vector_table", ".vectors", ".isr_vectors"];
+const VECTOR_TABLE_SYMBOLS: [&str; 5] = ["g_pfnVectors", "__isr_vector", "__Vectors", "__vector_table", "__VECTOR_TABLE"];
+
+pub fn is_elf(content: &[u8]) -> bool {
+    content.starts_with(b"\x7fELF")
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+        self.0.get(offset..offset+len).context("ELF file truncated")
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+
+    fn str(&self, offset: usize) -> Result<&'a str> {
+        let s = self.0.get(offset..).context("ELF file truncated")?;
+        let len = s.iter().position(|c| *c == 0).unwrap_or(s.len());
+        Ok(std::str::from_utf8(&s[..len])?)
+    }
+}
+
+struct Section {
+    name: u32,
+    type_: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+}
+
+pub fn parse(content: &[u8]) -> Result<Firmware> {
+    let r = Reader(content);
+
+    if !is_elf(content) {
+        bail!("Not an ELF file");
+    }
+    if r.u8(4)? != 1 || r.u8(5)? != 1 {
+        bail!("Only 32-bit little-endian ELF files are supported");
+    }
+
+    let entry = r.u32(24)?;
+    let phoff = r.u32(28)? as usize;
+    let shoff = r.u32(32)? as usize;
+    let phentsize = r.u16(42)? as usize;
+    let phnum = r.u16(44)? as usize;
+    let shentsize = r.u16(46)? as usize;
+    let shnum = r.u16(48)? as usize;
+    let shstrndx = r.u16(50)? as usize;
+
+    let mut segments = vec![];
+    let mut lowest_exec_segment: Option<u32> = None;
+
+    for i in 0..phnum {
+        let ph = phoff + i*phentsize;
+        let type_ = r.u32(ph)?;
+        let offset = r.u32(ph+4)? as usize;
+        let paddr = r.u32(ph+12)?;
+        let filesz = r.u32(ph+16)? as usize;
+        let flags = r.u32(ph+24)?;
+
+        if type_ != PT_LOAD || filesz == 0 {
+            continue;
+        }
+
+        // Segments are placed at their physical (load) address. That's where
+        // the flash content lives, .data included.
+        segments.push(Segment { addr: paddr, data: r.bytes(offset, filesz)?.to_vec() });
+
+        if flags & PF_X != 0 {
+            lowest_exec_segment = Some(lowest_exec_segment.map_or(paddr, |a| a.min(paddr)));
+        }
+    }
+
+    let sections = (0..shnum).map(|i| {
+        let sh = shoff + i*shentsize;
+        Ok(Section {
+            name: r.u32(sh)?,
+            type_: r.u32(sh+4)?,
+            addr: r.u32(sh+12)?,
+            offset: r.u32(sh+16)?,
+            size: r.u32(sh+20)?,
+            link: r.u32(sh+24)?,
+        })
+    }).collect::<Result<Vec<_>>>()?;
+
+    let section_name = |s: &Section| -> Result<&str> {
+        let strtab = sections.get(shstrndx).context("Invalid section name table")?;
+        r.str(strtab.offset as usize + s.name as usize)
+    };
+
+    let mut symbols = vec![];
+    for symtab in sections.iter().filter(|s| s.type_ == SHT_SYMTAB) {
+        let strtab = sections.get(symtab.link as usize).context("Invalid symbol string table")?;
+        for i in 0..(symtab.size / 16) as usize {
+            let sym = symtab.offset as usize + i*16;
+            let name = r.str(strtab.offset as usize + r.u32(sym)? as usize)?;
+            let value = r.u32(sym+4)?;
+            let size = r.u32(sym+8)?;
+            let type_ = r.u8(sym+12)? & 0xf;
+            let shndx = r.u16(sym+14)?;
+
+            // Skip undefined symbols, and the ARM mapping symbols ($t, $d, ...)
+            if name.is_empty() || name.starts_with('$') || shndx == 0 {
+                continue;
+            }
+
+            if type_ == STT_FUNC || type_ == STT_OBJECT {
+                // Thumb functions have the low bit set
+                let addr = if type_ == STT_FUNC { value & !1 } else { value };
+                symbols.push(Symbol { name: name.to_string(), addr, size });
+            }
+        }
+    }
+
+    let vector_table = sections.iter()
+        .find(|s| section_name(s).is_ok_and(|n| VECTOR_TABLE_SECTIONS.contains(&n)))
+        .map(|s| s.addr)
+        .or_else(|| symbols.iter().find(|s| VECTOR_TABLE_SYMBOLS.contains(&s.name.as_str())).map(|s| s.addr))
+        .or(lowest_exec_segment);
+
+    Ok(Firmware { segments, entry: Some(entry), vector_table, symbols })
+}
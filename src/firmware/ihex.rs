@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Intel HEX: lines of ":LLAAAATT<data>CC"
+
+use anyhow::{bail, Context as _, Result};
+
+use super::{Firmware, Segment, decode_hex_record};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+pub fn is_ihex(content: &[u8]) -> bool {
+    super::first_line_matches(content, r"^:[0-9A-Fa-f]{10,}$")
+}
+
+pub fn parse(content: &[u8]) -> Result<Firmware> {
+    let content = std::str::from_utf8(content).context("Intel HEX file is not valid text")?;
+
+    let mut segments: Vec<Segment> = vec![];
+    let mut base: u32 = 0;
+    let mut entry = None;
+
+    for (lineno, line) in content.lines().enumerate().map(|(i, l)| (i+1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = line.strip_prefix(':')
+            .with_context(|| format!("line {}: missing ':'", lineno))?;
+        let record = decode_hex_record(record)
+            .with_context(|| format!("line {}", lineno))?;
+
+        if record.len() < 5 || record.len() != 5 + record[0] as usize {
+            bail!("line {}: invalid record length", lineno);
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            bail!("line {}: invalid checksum", lineno);
+        }
+
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let type_ = record[3];
+        let data = &record[4..record.len()-1];
+
+        match type_ {
+            DATA => super::push_data(&mut segments, base.wrapping_add(offset), data),
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            START_SEGMENT_ADDRESS if data.len() == 4 => {
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                entry = Some((cs << 4) + ip);
+            }
+            START_LINEAR_ADDRESS if data.len() == 4 => {
+                entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+            }
+            _ => bail!("line {}: invalid record type={:02x}", lineno, type_),
+        }
+    }
+
+    Ok(Firmware { segments, entry, vector_table: None, symbols: vec![] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_works() {
+        let content = b":020000040800F2\n:0400000001020304F2\n:0400000508000101ED\n:00000001FF\n";
+        assert!(is_ihex(content));
+        let fw = parse(content).unwrap();
+        assert_eq!(fw.segments.len(), 1);
+        assert_eq!(fw.segments[0].addr, 0x0800_0000);
+        assert_eq!(fw.segments[0].data, vec![1, 2, 3, 4]);
+        assert_eq!(fw.entry, Some(0x0800_0101));
+    }
+
+    #[test]
+    fn parse_rejects_bad_checksum() {
+        assert!(parse(b":0400000001020304F3\n").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+pub mod elf;
+pub mod ihex;
+pub mod srec;
+
+use anyhow::{Context as _, Result};
+use regex::Regex;
+use crate::symbols::Symbol;
+
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+/// A firmware image that knows where its content goes, as opposed to a raw
+/// binary that is loaded at the start of a region.
+pub struct Firmware {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u32>,
+    pub vector_table: Option<u32>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Firmware {
+    /// Returns None if the content is a raw binary
+    pub fn parse(content: &[u8]) -> Result<Option<Self>> {
+        if elf::is_elf(content) {
+            elf::parse(content).map(Some)
+        } else if ihex::is_ihex(content) {
+            ihex::parse(content).map(Some)
+        } else if srec::is_srec(content) {
+            srec::parse(content).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Used to detect text based formats
+fn first_line_matches(content: &[u8], re: &str) -> bool {
+    let first_line = content.split(|c| *c == b'\n').next().unwrap_or_default();
+    let first_line = String::from_utf8_lossy(first_line);
+    Regex::new(re).unwrap().is_match(first_line.trim())
+}
+
+fn decode_hex_record(record: &str) -> Result<Vec<u8>> {
+    let record = record.as_bytes();
+    if !record.len().is_multiple_of(2) {
+        anyhow::bail!("odd number of hex digits");
+    }
+    record.chunks(2)
+        .map(|digits| std::str::from_utf8(digits).ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .context("invalid hex digits"))
+        .collect()
+}
+
+/// Appends data to the last segment when contiguous, otherwise starts a new segment.
+fn push_data(segments: &mut Vec<Segment>, addr: u32, data: &[u8]) {
+    match segments.last_mut() {
+        Some(s) if s.addr as u64 + s.data.len() as u64 == addr as u64 => s.data.extend_from_slice(data),
+        _ => segments.push(Segment { addr, data: data.to_vec() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_hex_record_works() {
+        assert_eq!(decode_hex_record("00ff1A").unwrap(), vec![0x00, 0xff, 0x1a]);
+        assert_eq!(decode_hex_record("").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn decode_hex_record_rejects_bad_input() {
+        assert!(decode_hex_record("abc").is_err());
+        assert!(decode_hex_record("zz").is_err());
+        // Non-ASCII must be an error, not a panic on a char boundary
+        assert!(decode_hex_record("é0").is_err());
+        assert!(decode_hex_record("0é").is_err());
+    }
+
+    #[test]
+    fn push_data_merges_contiguous_data() {
+        let mut segments = vec![];
+        push_data(&mut segments, 0x100, &[1, 2]);
+        push_data(&mut segments, 0x102, &[3]);
+        push_data(&mut segments, 0x200, &[4]);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].data, vec![1, 2, 3]);
+        assert_eq!(segments[1].addr, 0x200);
+    }
+}
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Motorola S-record: lines of "S<type><count><address><data><checksum>"
+
+use anyhow::{bail, Context as _, Result};
+
+use super::{Firmware, Segment, decode_hex_record};
+
+pub fn is_srec(content: &[u8]) -> bool {
+    super::first_line_matches(content, r"^S[0-9][0-9A-Fa-f]{6,}$")
+}
+
+pub fn parse(content: &[u8]) -> Result<Firmware> {
+    let content = std::str::from_utf8(content).context("S-record file is not valid text")?;
+
+    let mut segments: Vec<Segment> = vec![];
+    let mut entry = None;
+
+    for (lineno, line) in content.lines().enumerate().map(|(i, l)| (i+1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (type_, record) = line.strip_prefix('S')
+            .and_then(|l| l.split_at_checked(1))
+            .with_context(|| format!("line {}: missing 'S'", lineno))?;
+        let record = decode_hex_record(record)
+            .with_context(|| format!("line {}", lineno))?;
+
+        if record.is_empty() || record.len() != 1 + record[0] as usize {
+            bail!("line {}: invalid record length", lineno);
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            bail!("line {}: invalid checksum", lineno);
+        }
+
+        let addr_len = match type_ {
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => bail!("line {}: invalid record type=S{}", lineno, type_),
+        };
+
+        let record = &record[1..record.len()-1];
+        if record.len() < addr_len {
+            bail!("line {}: record too short", lineno);
+        }
+        let (addr, data) = record.split_at(addr_len);
+        let addr = addr.iter().fold(0u32, |a, b| (a << 8) | *b as u32);
+
+        match type_ {
+            // Header, and record counts
+            "0" | "5" | "6" => {}
+            "1" | "2" | "3" => super::push_data(&mut segments, addr, data),
+            // Start address
+            _ => entry = Some(addr),
+        }
+    }
+
+    Ok(Firmware { segments, entry, vector_table: None, symbols: vec![] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_works() {
+        let content = b"S00600004844521B\nS3090800000001020304E4\nS70508000101F0\n";
+        assert!(is_srec(content));
+        let fw = parse(content).unwrap();
+        assert_eq!(fw.segments.len(), 1);
+        assert_eq!(fw.segments[0].addr, 0x0800_0000);
+        assert_eq!(fw.segments[0].data, vec![1, 2, 3, 4]);
+        assert_eq!(fw.entry, Some(0x0800_0101));
+    }
+
+    #[test]
+    fn parse_rejects_bad_checksum() {
+        assert!(parse(b"S3090800000001020304E5\n").is_err());
+    }
+}
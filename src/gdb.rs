@@ -0,0 +1,562 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// A GDB server speaking the remote serial protocol (RSP) over TCP.
+// Usage: run the emulator with --gdb 3333, then in gdb: `target remote :3333`.
+//
+// The server is driven from the instruction hook. When we need to stop
+// (breakpoint, single step, ctrl-c), we block in handle_stop() and serve
+// gdb requests until it tells us to resume.
+
+use std::{rc::Rc, collections::BTreeSet, io::{Read, Write, ErrorKind}, net::{TcpListener, TcpStream}};
+use anyhow::{Context as _, Result};
+use unicorn_engine::{Unicorn, RegisterARM};
+
+use crate::{peripherals::Peripherals, watchpoints::{Hit, WatchKind, WatchAction, WatchpointConfig}};
+
+/// How often we check for a ctrl-c coming from gdb while the target runs.
+const INTERRUPT_POLL_INST_INTERVAL: u64 = 0x1_0000;
+
+// (feature, name, register, bitsize, type)
+const REGISTERS: [(&str, &str, RegisterARM, u32, &str); 40] = [
+    ("org.gnu.gdb.arm.m-profile", "r0",  RegisterARM::R0,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r1",  RegisterARM::R1,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r2",  RegisterARM::R2,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r3",  RegisterARM::R3,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r4",  RegisterARM::R4,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r5",  RegisterARM::R5,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r6",  RegisterARM::R6,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r7",  RegisterARM::R7,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r8",  RegisterARM::R8,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r9",  RegisterARM::R9,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r10", RegisterARM::R10, 32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r11", RegisterARM::R11, 32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "r12", RegisterARM::R12, 32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "sp",  RegisterARM::SP,  32, "data_ptr"),
+    ("org.gnu.gdb.arm.m-profile", "lr",  RegisterARM::LR,  32, "uint32"),
+    ("org.gnu.gdb.arm.m-profile", "pc",  RegisterARM::PC,  32, "code_ptr"),
+    ("org.gnu.gdb.arm.m-profile", "xpsr", RegisterARM::XPSR, 32, "uint32"),
+    ("org.gnu.gdb.arm.m-system", "msp", RegisterARM::MSP, 32, "data_ptr"),
+    ("org.gnu.gdb.arm.m-system", "psp", RegisterARM::PSP, 32, "data_ptr"),
+    ("org.gnu.gdb.arm.m-system", "primask", RegisterARM::PRIMASK, 32, "uint32"),
+    ("org.gnu.gdb.arm.m-system", "basepri", RegisterARM::BASEPRI, 32, "uint32"),
+    ("org.gnu.gdb.arm.m-system", "faultmask", RegisterARM::FAULTMASK, 32, "uint32"),
+    ("org.gnu.gdb.arm.m-system", "control", RegisterARM::CONTROL, 32, "uint32"),
+    ("org.gnu.gdb.arm.vfp", "d0",  RegisterARM::D0,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d1",  RegisterARM::D1,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d2",  RegisterARM::D2,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d3",  RegisterARM::D3,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d4",  RegisterARM::D4,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d5",  RegisterARM::D5,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d6",  RegisterARM::D6,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d7",  RegisterARM::D7,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d8",  RegisterARM::D8,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d9",  RegisterARM::D9,  64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d10", RegisterARM::D10, 64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d11", RegisterARM::D11, 64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d12", RegisterARM::D12, 64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d13", RegisterARM::D13, 64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d14", RegisterARM::D14, 64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "d15", RegisterARM::D15, 64, "ieee_double"),
+    ("org.gnu.gdb.arm.vfp", "fpscr", RegisterARM::FPSCR, 32, "uint32"),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum StopReason {
+    /// Single step done, or ctrl-c
+    Signal(u8),
+    Breakpoint,
+    Watchpoint(Hit),
+}
+
+pub struct GdbServer {
+    stream: TcpStream,
+    p: Rc<Peripherals>,
+    breakpoints: BTreeSet<u32>,
+    single_step: bool,
+    interrupt_requested: bool,
+    /// true when gdb believes the target is running and waits for a stop reply
+    running: bool,
+    detached: bool,
+    pub kill_requested: bool,
+    last_packet: Vec<u8>,
+    no_ack: bool,
+}
+
+impl GdbServer {
+    pub fn listen(port: u16, p: Rc<Peripherals>) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .with_context(|| format!("Failed to listen on port {}", port))?;
+
+        info!("Waiting for gdb to connect on port {}", port);
+        let (stream, addr) = listener.accept().context("Failed to accept gdb connection")?;
+        stream.set_nodelay(true)?;
+        info!("gdb connected from {}", addr);
+
+        // The target is halted when gdb connects. It will ask why with '?'.
+        Ok(Self {
+            stream,
+            p,
+            breakpoints: BTreeSet::new(),
+            single_step: false,
+            interrupt_requested: true,
+            running: false,
+            detached: false,
+            kill_requested: false,
+            last_packet: vec![],
+            no_ack: false,
+        })
+    }
+
+    /// Called before each instruction executes.
+    pub fn should_stop(&mut self, pc: u32, num_instructions: u64) -> Option<StopReason> {
+        if self.detached {
+            return None;
+        }
+
+        if num_instructions.is_multiple_of(INTERRUPT_POLL_INST_INTERVAL) {
+            self.poll_interrupt();
+        }
+
+        if self.interrupt_requested {
+            self.interrupt_requested = false;
+            Some(StopReason::Signal(2)) // SIGINT
+        } else if self.single_step {
+            self.single_step = false;
+            Some(StopReason::Signal(5)) // SIGTRAP
+        } else if self.breakpoints.contains(&pc) {
+            Some(StopReason::Breakpoint)
+        } else {
+            None
+        }
+    }
+
+    fn poll_interrupt(&mut self) {
+        let mut buf = [0; 64];
+        self.stream.set_nonblocking(true).unwrap();
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    warn!("gdb disconnected");
+                    self.detached = true;
+                    break;
+                }
+                Ok(n) => {
+                    if buf[..n].contains(&0x03) {
+                        self.interrupt_requested = true;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("gdb connection error: {}", e);
+                    self.detached = true;
+                    break;
+                }
+            }
+        }
+        self.stream.set_nonblocking(false).unwrap();
+    }
+
+    /// Reports the stop to gdb, and serve requests until gdb resumes execution.
+    pub fn handle_stop(&mut self, uc: &mut Unicorn<()>, reason: StopReason) {
+        if self.detached {
+            return;
+        }
+
+        debug!("gdb stop reason={:?}", reason);
+
+        if self.running {
+            self.running = false;
+            self.send_stop_reply(reason);
+        }
+
+        while !self.running && !self.detached && !self.kill_requested {
+            match self.recv_packet() {
+                Some(packet) => self.handle_packet(uc, &packet, reason),
+                None => {
+                    warn!("gdb disconnected");
+                    self.detached = true;
+                }
+            }
+        }
+    }
+
+    /// Tell gdb that the program is gone. We let gdb inspect the final state first.
+    pub fn finish(&mut self, uc: &mut Unicorn<()>) {
+        self.handle_stop(uc, StopReason::Signal(5));
+        if !self.detached && !self.kill_requested {
+            self.send_packet(b"W00");
+        }
+    }
+
+    fn send_stop_reply(&mut self, reason: StopReason) {
+        let reply = match reason {
+            StopReason::Signal(sig) => format!("S{:02x}", sig),
+            StopReason::Breakpoint => "T05swbreak:;".to_string(),
+            StopReason::Watchpoint(Hit { addr, kind }) => {
+                let kind = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{}:{:08x};", kind, addr)
+            }
+        };
+        self.send_packet(reply.as_bytes());
+    }
+
+    fn handle_packet(&mut self, uc: &mut Unicorn<()>, packet: &[u8], reason: StopReason) {
+        let packet = String::from_utf8_lossy(packet);
+        trace!("gdb <- {}", packet);
+
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => {
+                self.send_stop_reply(reason);
+                return;
+            }
+            Some(b'g') => self.read_all_regs(uc),
+            Some(b'G') => self.write_all_regs(uc, &packet[1..]),
+            Some(b'p') => self.read_reg(uc, &packet[1..]),
+            Some(b'P') => self.write_reg(uc, &packet[1..]),
+            Some(b'm') => self.read_mem(uc, &packet[1..]),
+            Some(b'M') => self.write_mem(uc, &packet[1..]),
+            Some(b'c') => { self.resume(uc, &packet[1..], false); return; }
+            Some(b's') => { self.resume(uc, &packet[1..], true); return; }
+            Some(b'Z') => self.set_breakpoint(uc, &packet[1..], true),
+            Some(b'z') => self.set_breakpoint(uc, &packet[1..], false),
+            Some(b'H') => "OK".to_string(),
+            Some(b'T') => "OK".to_string(),
+            Some(b'k') => {
+                info!("gdb requested kill");
+                self.kill_requested = true;
+                return;
+            }
+            Some(b'D') => {
+                info!("gdb detached");
+                self.send_packet(b"OK");
+                self.detached = true;
+                return;
+            }
+            Some(b'v') => {
+                if packet == "vCont?" {
+                    "vCont;c;C;s;S".to_string()
+                } else if let Some(actions) = packet.strip_prefix("vCont;") {
+                    // We only have one thread, the first action is what matters.
+                    let step = matches!(actions.as_bytes().first(), Some(b's' | b'S'));
+                    self.resume(uc, "", step);
+                    return;
+                } else {
+                    "".to_string()
+                }
+            }
+            Some(b'q') | Some(b'Q') => self.handle_query(&packet),
+            _ => "".to_string(),
+        };
+
+        self.send_packet(reply.as_bytes());
+    }
+
+    fn handle_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string()
+        } else if packet == "QStartNoAckMode" {
+            self.send_packet(b"OK");
+            self.no_ack = true;
+            // The reply has already been sent
+            String::new()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = Self::target_xml();
+            let (offset, len) = args.split_once(',')
+                .map(|(o, l)| (parse_hex(o), parse_hex(l)))
+                .unwrap_or_default();
+            let offset = (offset as usize).min(xml.len());
+            let end = (offset + len as usize).min(xml.len());
+            let prefix = if end == xml.len() { "l" } else { "m" };
+            format!("{}{}", prefix, &xml[offset..end])
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            "".to_string()
+        }
+    }
+
+    fn target_xml() -> String {
+        let mut xml = String::from(concat!(
+            r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+            r#"<target version="1.0"><architecture>arm</architecture>"#,
+        ));
+
+        let mut current_feature = "";
+        for (regnum, (feature, name, _, bitsize, type_)) in REGISTERS.iter().enumerate() {
+            if *feature != current_feature {
+                if !current_feature.is_empty() {
+                    xml.push_str("</feature>");
+                }
+                xml.push_str(&format!(r#"<feature name="{}">"#, feature));
+                current_feature = feature;
+            }
+            xml.push_str(&format!(r#"<reg name="{}" bitsize="{}" regnum="{}" type="{}"/>"#,
+                name, bitsize, regnum, type_));
+        }
+        xml.push_str("</feature></target>");
+        xml
+    }
+
+    fn resume(&mut self, uc: &mut Unicorn<()>, addr: &str, step: bool) {
+        if !addr.is_empty() {
+            // Resume at a given address
+            uc.reg_write(RegisterARM::PC, parse_hex(addr) | 1).unwrap();
+        }
+        self.single_step = step;
+        self.running = true;
+    }
+
+    fn set_breakpoint(&mut self, uc: &mut Unicorn<()>, args: &str, insert: bool) -> String {
+        // Z0,addr,kind (software) or Z1,addr,kind (hardware). Both are the same for us.
+        // Z2, Z3, Z4 are write, read, and access watchpoints. kind is the size.
+        let mut args = args.split(',');
+        let type_ = args.next().unwrap_or_default();
+        let addr = args.next().map(parse_hex).unwrap_or_default() as u32;
+        let size = args.next().map(parse_hex).unwrap_or_default() as u32;
+
+        let watch_kind = match type_ {
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => None,
+        };
+
+        if let Some(kind) = watch_kind {
+            let mut watchpoints = self.p.watchpoints.borrow_mut();
+            return if insert {
+                let config = WatchpointConfig {
+                    start: addr, size: Some(size), kind: Some(kind), action: Some(WatchAction::Stop),
+                };
+                match watchpoints.add(uc, self.p.clone(), &config) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => "E01".to_string(),
+                }
+            } else {
+                watchpoints.remove(uc, addr, kind);
+                "OK".to_string()
+            };
+        }
+
+        match type_ {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                "OK".to_string()
+            }
+            _ => "".to_string(),
+        }
+    }
+
+    fn read_reg_hex(uc: &Unicorn<()>, regnum: usize) -> String {
+        let (_, _, reg, bitsize, _) = REGISTERS[regnum];
+        let v = uc.reg_read(reg).unwrap_or_default();
+        let bytes = v.to_le_bytes();
+        to_hex(&bytes[..(bitsize/8) as usize])
+    }
+
+    /// Returns false when `hex` is not a valid value for the register
+    fn write_reg_hex(uc: &mut Unicorn<()>, regnum: usize, hex: &str) -> bool {
+        let (_, _, reg, bitsize, _) = REGISTERS[regnum];
+        let len = (bitsize/8) as usize;
+        let data = match from_hex(hex.as_bytes()) {
+            Some(data) if data.len() == len => data,
+            _ => return false,
+        };
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(&data);
+        let v = u64::from_le_bytes(bytes);
+        let v = if reg == RegisterARM::PC { v | 1 } else { v };
+        uc.reg_write(reg, v).unwrap();
+        true
+    }
+
+    fn read_all_regs(&self, uc: &Unicorn<()>) -> String {
+        (0..REGISTERS.len()).map(|i| Self::read_reg_hex(uc, i)).collect()
+    }
+
+    fn write_all_regs(&self, uc: &mut Unicorn<()>, hex: &str) -> String {
+        let mut offset = 0;
+        for (regnum, (_, _, _, bitsize, _)) in REGISTERS.iter().enumerate() {
+            let len = (bitsize/4) as usize;
+            if let Some(v) = hex.get(offset..offset+len) {
+                if !Self::write_reg_hex(uc, regnum, v) {
+                    return "E01".to_string();
+                }
+            }
+            offset += len;
+        }
+        "OK".to_string()
+    }
+
+    fn read_reg(&self, uc: &Unicorn<()>, args: &str) -> String {
+        let regnum = parse_hex(args) as usize;
+        if regnum < REGISTERS.len() {
+            Self::read_reg_hex(uc, regnum)
+        } else {
+            "E01".to_string()
+        }
+    }
+
+    fn write_reg(&self, uc: &mut Unicorn<()>, args: &str) -> String {
+        match args.split_once('=') {
+            Some((regnum, v)) if (parse_hex(regnum) as usize) < REGISTERS.len() => {
+                if Self::write_reg_hex(uc, parse_hex(regnum) as usize, v) {
+                    "OK".to_string()
+                } else {
+                    "E01".to_string()
+                }
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_mem(&self, uc: &Unicorn<()>, args: &str) -> String {
+        let (addr, len) = args.split_once(',')
+            .map(|(a, l)| (parse_hex(a), parse_hex(l) as usize))
+            .unwrap_or_default();
+
+        match uc.mem_read_as_vec(addr, len) {
+            Ok(data) => to_hex(&data),
+            Err(_) => "E14".to_string(), // EFAULT
+        }
+    }
+
+    fn write_mem(&self, uc: &mut Unicorn<()>, args: &str) -> String {
+        // The data must be valid hex, and match the length
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = range.split_once(',')?;
+            let data = from_hex(data.as_bytes()).filter(|d| d.len() as u64 == parse_hex(len))?;
+            Some((parse_hex(addr), data))
+        });
+
+        match parsed.map(|(addr, data)| uc.mem_write(addr, &data)) {
+            Some(Ok(())) => "OK".to_string(),
+            Some(Err(_)) => "E14".to_string(), // EFAULT
+            None => "E01".to_string(),
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut b = [0];
+        match self.stream.read(&mut b) {
+            Ok(1) => Some(b[0]),
+            _ => None,
+        }
+    }
+
+    /// Returns None if the connection is lost
+    fn recv_packet(&mut self) -> Option<Vec<u8>> {
+        loop {
+            // Skip acks, and ctrl-c (we are already stopped)
+            match self.read_byte()? {
+                b'$' => {},
+                b'-' => {
+                    let last_packet = std::mem::take(&mut self.last_packet);
+                    self.send_raw(&last_packet);
+                    self.last_packet = last_packet;
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let mut packet = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b'}' => packet.push(self.read_byte()? ^ 0x20),
+                    c => packet.push(c),
+                }
+            }
+
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let checksum = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+            let valid = checksum == Some(Self::checksum(&packet));
+
+            if !self.no_ack {
+                self.send_raw(if valid { b"+" } else { b"-" });
+            }
+
+            if valid {
+                return Some(packet);
+            }
+        }
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+    }
+
+    fn send_packet(&mut self, data: &[u8]) {
+        trace!("gdb -> {}", String::from_utf8_lossy(data));
+
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        for c in data {
+            // Escape the special characters
+            if matches!(c, b'$' | b'#' | b'}' | b'*') {
+                packet.push(b'}');
+                packet.push(c ^ 0x20);
+            } else {
+                packet.push(*c);
+            }
+        }
+        packet.push(b'#');
+        packet.extend_from_slice(format!("{:02x}", Self::checksum(&packet[1..])).as_bytes());
+
+        self.send_raw(&packet);
+        self.last_packet = packet;
+    }
+
+    fn send_raw(&mut self, data: &[u8]) {
+        if let Err(e) = self.stream.write_all(data) {
+            warn!("gdb connection error: {}", e);
+            self.detached = true;
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> u64 {
+    u64::from_str_radix(s, 16).unwrap_or_default()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// None when `s` is not made of pairs of hex digits
+fn from_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16);
+    s.chunks(2)
+        .map(|pair| Some((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_hex_works() {
+        assert_eq!(from_hex(b"00ff1A"), Some(vec![0x00, 0xff, 0x1a]));
+        assert_eq!(from_hex(b""), Some(vec![]));
+        assert_eq!(from_hex(b"abc"), None);
+        assert_eq!(from_hex(b"0g"), None);
+        assert_eq!(from_hex("\u{fffd}0".as_bytes()), None);
+    }
+}
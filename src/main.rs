@@ -7,6 +7,11 @@ mod peripherals;
 mod ext_devices;
 mod system;
 mod framebuffers;
+mod gdb;
+mod watchpoints;
+mod firmware;
+mod symbols;
+mod patches;
 
 use std::io::prelude::*;
 use std::sync::atomic::Ordering::Relaxed;
@@ -57,6 +62,20 @@ pub struct Args {
     /// Dump stack at the end. Parameter is the number of words to print
     #[clap(short, long)]
     dump_stack: Option<usize>,
+
+    /// Deliver faults (HardFault, MemManage, BusFault, UsageFault) to the firmware
+    /// fault handlers, instead of skipping bad memory accesses
+    #[clap(long)]
+    faults: bool,
+
+    /// Wait for a GDB connection on this TCP port before starting emulation
+    #[clap(long)]
+    gdb: Option<u16>,
+
+    /// Watch memory accesses. Can be repeated.
+    /// Format: [read|write|access:]ADDR[+SIZE][:stop|log], e.g. write:0x40011004
+    #[clap(short, long)]
+    watch: Vec<watchpoints::WatchpointConfig>,
 }
 
 #[derive(clap::ArgEnum, Clone, Copy, Debug)]
@@ -116,7 +135,10 @@ fn init_logging(args: &Args) {
             let mut style = buf.style();
             style.set_color(Color::Black).set_intense(true);
             //let header = format!("[tsc={:08} dtsc=+{:08} pc=0x{:08x}]", num_instructions, delta_instructions, pc);
-            let header = format!("[clk={:08} pc=0x{:08x}]", num_instructions, pc);
+            let header = match symbols::describe(pc) {
+                Some(sym) => format!("[clk={:08} pc=0x{:08x} {}]", num_instructions, pc, sym),
+                None => format!("[clk={:08} pc=0x{:08x}]", num_instructions, pc),
+            };
             let header = style.value(header);
 
             writeln!(buf, "{} {} {}", header, level, record.args())
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Patches applied to the firmware once loaded. They can target a raw address
+// or a symbol, and either provide the bytes, or a stub that we assemble here.
+
+use anyhow::{bail, Context as _, Result};
+use capstone::prelude::*;
+use serde::Deserialize;
+use unicorn_engine::Unicorn;
+
+use crate::{symbols::SYMBOLS, util::UniErr};
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Stub {
+    /// bx lr
+    Return,
+    /// r0 = N; bx lr
+    ReturnValue(i64),
+    /// Branch over the next N instructions
+    Skip(u32),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Patch {
+    pub start: Option<u32>,
+    pub symbol: Option<String>,
+    /// Added to start or to the symbol address
+    pub offset: Option<u32>,
+    pub data: Option<Vec<u8>>,
+    pub stub: Option<Stub>,
+}
+
+impl Patch {
+    fn addr(&self) -> Result<u32> {
+        let base = match (self.start, &self.symbol) {
+            (Some(start), None) => start,
+            (None, Some(symbol)) => {
+                SYMBOLS.read().unwrap().find_by_name(symbol)
+                    .with_context(|| format!("Symbol {} not found", symbol))?
+                    .addr
+            }
+            _ => bail!("A patch needs either `start` or `symbol`"),
+        };
+        Ok(base + self.offset.unwrap_or(0))
+    }
+
+    fn desc(&self, addr: u32) -> String {
+        match self.symbol {
+            Some(ref symbol) => format!("{}+0x{:x} (0x{:08x})", symbol, self.offset.unwrap_or(0), addr),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+}
+
+fn thumb16(code: &mut Vec<u8>, ins: u16) {
+    code.extend_from_slice(&ins.to_le_bytes());
+}
+
+fn thumb32(code: &mut Vec<u8>, ins: u32) {
+    thumb16(code, (ins >> 16) as u16);
+    thumb16(code, ins as u16);
+}
+
+/// movw/movt encoding T3. op is 0xf240 for movw, 0xf2c0 for movt.
+fn mov_imm16(code: &mut Vec<u8>, op: u32, rd: u32, imm: u16) {
+    let imm = imm as u32;
+    let (imm4, i, imm3, imm8) = (imm >> 12, (imm >> 11) & 1, (imm >> 8) & 7, imm & 0xff);
+    thumb32(code, (op | (i << 10) | imm4) << 16 | (imm3 << 12) | (rd << 8) | imm8);
+}
+
+/// Thumb-2 modified immediate of a 32 bit value (the imm12 field of
+/// i:imm3:imm8), when it has one. See ThumbExpandImm() in the ARM ARM.
+fn modified_imm(v: u32) -> Option<u32> {
+    let b = v & 0xff;
+    if v <= 0xff {
+        return Some(v);
+    }
+    if v == b * 0x0001_0001 {
+        return Some((0b01 << 8) | b);
+    }
+    if v == ((v >> 8) & 0xff) * 0x0100_0100 {
+        return Some((0b10 << 8) | ((v >> 8) & 0xff));
+    }
+    if v == b * 0x0101_0101 {
+        return Some((0b11 << 8) | b);
+    }
+    // An 8 bit value with its top bit set, rotated right by 8 to 31
+    (8..32).map(|rot| (rot, v.rotate_left(rot)))
+        .find(|(_, x)| *x <= 0xff && *x & 0x80 != 0)
+        .map(|(rot, x)| (rot << 7) | (x & 0x7f))
+}
+
+/// mov.w/mvn encoding T2/T1 with a modified immediate. op is 0xf04f for mov.w, 0xf06f for mvn.
+fn mov_modified_imm(code: &mut Vec<u8>, op: u32, rd: u32, imm12: u32) {
+    let (i, imm3, imm8) = (imm12 >> 11, (imm12 >> 8) & 7, imm12 & 0xff);
+    thumb32(code, (op | (i << 10)) << 16 | (imm3 << 12) | (rd << 8) | imm8);
+}
+
+/// Unconditional branch from pc to target, going forward.
+fn branch(code: &mut Vec<u8>, pc: u32, target: u32) -> Result<()> {
+    // The branch offset is relative to pc+4
+    let offset = target as i64 - (pc as i64 + 4);
+    if (-2048..2048).contains(&offset) {
+        // B encoding T2
+        thumb16(code, 0xe000 | ((offset >> 1) as u16 & 0x7ff));
+    } else if (0..(1 << 24)).contains(&offset) {
+        // B.W encoding T4, with S=0, so J1 = !I1 and J2 = !I2
+        let offset = offset as u32;
+        let (i1, i2) = ((offset >> 23) & 1, (offset >> 22) & 1);
+        let (j1, j2) = (i1 ^ 1, i2 ^ 1);
+        let imm10 = (offset >> 12) & 0x3ff;
+        let imm11 = (offset >> 1) & 0x7ff;
+        thumb32(code, (0xf000 | imm10) << 16 | 0x9000 | (j1 << 13) | (j2 << 11) | imm11);
+    } else {
+        bail!("Branch offset out of range");
+    }
+    Ok(())
+}
+
+/// Returns the size of the next `n` instructions at addr
+fn instructions_size(uc: &Unicorn<()>, addr: u32, n: u32) -> Result<u32> {
+    let cs = Capstone::new()
+        .arm()
+        .mode(arch::arm::ArchMode::Thumb)
+        .build()
+        .expect("failed to initialize capstone");
+
+    // A thumb instruction is at most 4 bytes
+    let code = uc.mem_read_as_vec(addr.into(), 4 * n as usize).map_err(UniErr)?;
+    let insns = cs.disasm_count(&code, addr.into(), n as usize)
+        .ok().filter(|i| i.len() == n as usize)
+        .with_context(|| format!("Failed to disassemble {} instructions at 0x{:08x}", n, addr))?;
+    Ok(insns.iter().map(|i| i.bytes().len() as u32).sum())
+}
+
+fn assemble(uc: &Unicorn<()>, addr: u32, stub: Stub) -> Result<Vec<u8>> {
+    const BX_LR: u16 = 0x4770;
+
+    let mut code = vec![];
+    match stub {
+        Stub::Return => thumb16(&mut code, BX_LR),
+        Stub::ReturnValue(v) => {
+            if !(i32::MIN as i64..=u32::MAX as i64).contains(&v) {
+                bail!("Return value {} does not fit in 32 bits", v);
+            }
+            let v = v as u32;
+            if v <= 0xff {
+                // movs r0, #imm8
+                thumb16(&mut code, 0x2000 | v as u16);
+            } else if let Some(imm12) = modified_imm(v) {
+                mov_modified_imm(&mut code, 0xf04f, 0, imm12);
+            } else if let Some(imm12) = modified_imm(!v) {
+                mov_modified_imm(&mut code, 0xf06f, 0, imm12);
+            } else {
+                mov_imm16(&mut code, 0xf240, 0, v as u16);
+                if v >> 16 != 0 {
+                    mov_imm16(&mut code, 0xf2c0, 0, (v >> 16) as u16);
+                }
+            }
+            thumb16(&mut code, BX_LR);
+        }
+        Stub::Skip(n) => {
+            let size = instructions_size(uc, addr, n)?;
+            branch(&mut code, addr, addr + size)?;
+            if code.len() as u32 > size {
+                bail!("Not enough room to place a branch");
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// A stub must not spill over the next function
+fn check_stub_size(addr: u32, len: u32) -> Result<()> {
+    if let Some((symbol, offset)) = SYMBOLS.read().unwrap().lookup(addr) {
+        if symbol.size != 0 && offset + len > symbol.size {
+            bail!("The stub is {} bytes, larger than {} (size={})", len, symbol.name, symbol.size);
+        }
+    }
+    Ok(())
+}
+
+pub fn apply(uc: &mut Unicorn<()>, patches: &[Patch]) -> Result<()> {
+    // (start, end) of the patches applied so far
+    let mut patched: Vec<(u32, u32)> = vec![];
+
+    for patch in patches {
+        let addr = patch.addr()?;
+        let data = match (&patch.data, patch.stub) {
+            (Some(data), None) => data.clone(),
+            (None, Some(stub)) => assemble(uc, addr, stub)
+                .and_then(|code| check_stub_size(addr, code.len() as u32).map(|_| code))
+                .with_context(|| format!("Failed to assemble patch at {}", patch.desc(addr)))?,
+            _ => bail!("Patch at {} needs either `data` or `stub`", patch.desc(addr)),
+        };
+
+        let end = addr + data.len() as u32;
+        if patched.iter().any(|(s, e)| addr < *e && *s < end) {
+            bail!("Patch at {} overlaps another patch", patch.desc(addr));
+        }
+        patched.push((addr, end));
+
+        debug!("Patching {} with {:02x?}", patch.desc(addr), data);
+        uc.mem_write(addr.into(), &data)
+            .map_err(UniErr).with_context(||
+                format!("Failed to apply patch at {}", patch.desc(addr)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modified_imm_works() {
+        assert_eq!(modified_imm(0xab), Some(0xab));
+        assert_eq!(modified_imm(0x00ab_00ab), Some(0x1ab));
+        assert_eq!(modified_imm(0xab00_ab00), Some(0x2ab));
+        assert_eq!(modified_imm(0xffff_ffff), Some(0x3ff));
+        // 0x80 rotated right by 31
+        assert_eq!(modified_imm(0x100), Some(0xf80));
+        assert_eq!(modified_imm(0x1234_5678), None);
+        assert_eq!(modified_imm(0x101), None);
+    }
+
+    #[test]
+    fn return_minus_one_is_6_bytes() {
+        let mut code = vec![];
+        mov_modified_imm(&mut code, 0xf04f, 0, modified_imm(0xffff_ffff).unwrap());
+        // mov.w r0, #-1
+        assert_eq!(code, [0x4f, 0xf0, 0xff, 0x30]);
+    }
+}
@@ -0,0 +1,509 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// The ADCs convert the voltages given by the analog sources of the config.
+// Conversions take the time the real hardware takes (sampling time plus
+// resolution bits, at the ADC clock), and results are delivered with clock events.
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+use super::{Peripheral, Trigger, analog::{AnalogSource, AnalogSourceConfig}};
+
+#[derive(Debug, Deserialize, Default)]
+pub struct AdcConfig {
+    /// VREF+ in volts. Defaults to 3.3V.
+    pub vref: Option<f64>,
+    /// Die temperature in Celsius, measured by the internal sensor. Defaults to 25C.
+    pub temperature: Option<f64>,
+    /// Backup battery voltage in volts. Defaults to 3.0V.
+    pub vbat: Option<f64>,
+    pub channels: Option<Vec<AdcChannelConfig>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdcChannelConfig {
+    /// e.g., "ADC1". When omitted, the source is connected to all the ADCs.
+    pub adc: Option<String>,
+    pub channel: u8,
+    pub source: AnalogSourceConfig,
+}
+
+const DEFAULT_VREF: f64 = 3.3;
+const DEFAULT_TEMPERATURE: f64 = 25.0;
+const DEFAULT_VBAT: f64 = 3.0;
+
+// Internal channels, connected to ADC1 only
+const CHANNEL_TEMPERATURE: u8 = 16;
+const CHANNEL_VREFINT: u8 = 17;
+const CHANNEL_VBAT: u8 = 18;
+const VREFINT: f64 = 1.21;
+// Temperature sensor characteristics
+const TEMP_V25: f64 = 0.76;
+const TEMP_AVG_SLOPE: f64 = 0.0025;
+
+// SR bits
+const SR_AWD: u32 = 1 << 0;
+const SR_EOC: u32 = 1 << 1;
+const SR_JEOC: u32 = 1 << 2;
+const SR_JSTRT: u32 = 1 << 3;
+const SR_STRT: u32 = 1 << 4;
+const SR_OVR: u32 = 1 << 5;
+
+// CR1 bits
+const CR1_AWDCH_MASK: u32 = 0x1F;
+const CR1_EOCIE: u32 = 1 << 5;
+const CR1_AWDIE: u32 = 1 << 6;
+const CR1_JEOCIE: u32 = 1 << 7;
+const CR1_SCAN: u32 = 1 << 8;
+const CR1_AWDSGL: u32 = 1 << 9;
+const CR1_JAUTO: u32 = 1 << 10;
+const CR1_JAWDEN: u32 = 1 << 22;
+const CR1_AWDEN: u32 = 1 << 23;
+const CR1_OVRIE: u32 = 1 << 26;
+
+// CR2 bits
+const CR2_ADON: u32 = 1 << 0;
+const CR2_CONT: u32 = 1 << 1;
+const CR2_DMA: u32 = 1 << 8;
+const CR2_EOCS: u32 = 1 << 10;
+const CR2_ALIGN: u32 = 1 << 11;
+const CR2_JSWSTART: u32 = 1 << 22;
+const CR2_SWSTART: u32 = 1 << 30;
+
+// CCR bits
+const CCR_VBATE: u32 = 1 << 22;
+const CCR_TSVREFE: u32 = 1 << 23;
+
+const DR_OFFSET: u32 = 0x4C;
+
+const SAMPLE_CYCLES: [u64; 8] = [3, 15, 28, 56, 84, 112, 144, 480];
+
+const EVENT_REGULAR: u32 = 0;
+const EVENT_INJECTED: u32 = 1;
+
+/// State shared by all the ADCs: the common registers and the analog inputs.
+pub struct AdcCommon {
+    ccr: u32,
+    vref: f64,
+    temperature: f64,
+    vbat: f64,
+    sources: Vec<(Option<String>, u8, AnalogSource)>,
+}
+
+impl Default for AdcCommon {
+    fn default() -> Self {
+        Self {
+            ccr: 0,
+            vref: DEFAULT_VREF,
+            temperature: DEFAULT_TEMPERATURE,
+            vbat: DEFAULT_VBAT,
+            sources: vec![],
+        }
+    }
+}
+
+impl AdcCommon {
+    pub fn new(config: AdcConfig) -> Result<Self> {
+        let sources = config.channels.unwrap_or_default().iter().map(|c| {
+            if c.channel >= CHANNEL_TEMPERATURE {
+                bail!("ADC channel {} is internal, it can't have a source", c.channel);
+            }
+            let source = AnalogSource::new(&c.source)
+                .with_context(|| format!("ADC channel {}", c.channel))?;
+            Ok((c.adc.clone(), c.channel, source))
+        }).collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            vref: config.vref.unwrap_or(DEFAULT_VREF),
+            temperature: config.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+            vbat: config.vbat.unwrap_or(DEFAULT_VBAT),
+            sources,
+            ..Self::default()
+        })
+    }
+
+    pub fn vref(&self) -> f64 {
+        self.vref
+    }
+
+    /// ADCCLK is PCLK2 divided by 2, 4, 6, or 8
+    fn adc_clock(&self, pclk2: u32) -> u32 {
+        let adcpre = (self.ccr >> 16) & 0b11;
+        pclk2 / (2 * (adcpre + 1))
+    }
+
+    /// Voltage seen by an ADC channel at time `t` in seconds
+    fn voltage(&self, adc: &str, channel: u8, t: f64) -> f64 {
+        let internal = adc == "ADC1";
+        match channel {
+            CHANNEL_TEMPERATURE if internal && self.ccr & CCR_TSVREFE != 0 => {
+                TEMP_V25 + TEMP_AVG_SLOPE * (self.temperature - 25.0)
+            }
+            CHANNEL_VREFINT if internal && self.ccr & CCR_TSVREFE != 0 => VREFINT,
+            // VBAT goes through a bridge divider by 2
+            CHANNEL_VBAT if internal && self.ccr & CCR_VBATE != 0 => self.vbat / 2.0,
+            _ => {
+                self.sources.iter()
+                    .find(|(a, c, _)| *c == channel && a.as_ref().is_none_or(|a| a == adc))
+                    .map_or(0.0, |(_, _, s)| s.voltage(t))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Adc {
+    name: String,
+    base: u32,
+    irq: Option<i32>,
+
+    sr: u32,
+    cr1: u32,
+    cr2: u32,
+    smpr: [u32; 2],
+    jofr: [u32; 4],
+    htr: u32,
+    ltr: u32,
+    sqr: [u32; 3],
+    jsqr: u32,
+    jdr: [u32; 4],
+    dr: u32,
+
+    // Position in the regular sequence of the conversion in progress
+    regular_pos: Option<usize>,
+    injected_running: bool,
+    dma_pending: bool,
+}
+
+impl Adc {
+    pub fn new(name: &str, base: u32, interrupts: &[(String, i32)]) -> Option<Box<dyn Peripheral>> {
+        let is_adc = name.strip_prefix("ADC").is_some_and(|n| n.parse::<u8>().is_ok());
+        if is_adc {
+            let irq = interrupts.first().map(|(_, irq)| *irq);
+            // HTR resets to the max value
+            Some(Box::new(Self { name: name.to_string(), base, irq, htr: 0xFFF, ..Default::default() }))
+        } else {
+            None
+        }
+    }
+
+    fn resolution_bits(&self) -> u32 {
+        [12, 10, 8, 6][((self.cr1 >> 24) & 0b11) as usize]
+    }
+
+    /// Channels of the regular sequence. Without SCAN, only the first one is converted.
+    fn regular_sequence(&self) -> Vec<u8> {
+        let len = if self.cr1 & CR1_SCAN != 0 { ((self.sqr[0] >> 20) & 0xF) as usize + 1 } else { 1 };
+        (0..len).map(|i| {
+            // SQR3 has SQ1-6, SQR2 has SQ7-12, SQR1 has SQ13-16
+            let reg = self.sqr[2 - i / 6];
+            ((reg >> (5 * (i % 6))) & 0x1F) as u8
+        }).collect()
+    }
+
+    /// (JDR index, channel) of the injected sequence. With JL=n, JSQ[4-n..4] are converted.
+    fn injected_sequence(&self) -> Vec<(usize, u8)> {
+        let len = ((self.jsqr >> 20) & 0b11) as usize + 1;
+        (0..len).map(|i| {
+            let jsq = 4 - len + i;
+            (i, ((self.jsqr >> (5 * jsq)) & 0x1F) as u8)
+        }).collect()
+    }
+
+    /// Conversion time of a channel in HCLK cycles
+    fn conversion_cycles(&self, sys: &System, channel: u8) -> u64 {
+        let (reg, shift) = if channel < 10 {
+            (self.smpr[1], 3 * channel as u32)
+        } else {
+            (self.smpr[0], 3 * (channel as u32 - 10))
+        };
+        let ticks = SAMPLE_CYCLES[((reg >> shift) & 0b111) as usize] + self.resolution_bits() as u64;
+
+        let clock = sys.p.clock.borrow();
+        let adcclk = sys.p.adc_common.borrow().adc_clock(clock.pclk(self.base));
+        clock.ticks_to_cycles(ticks, adcclk)
+    }
+
+    /// Raw conversion result, right aligned
+    fn convert(&self, sys: &System, channel: u8) -> u32 {
+        let t = sys.p.clock.borrow().now_ns() as f64 / 1e9;
+        let common = sys.p.adc_common.borrow();
+        let v = common.voltage(&self.name, channel, t);
+
+        let max = (1u32 << self.resolution_bits()) - 1;
+        let raw = (v / common.vref * max as f64).round().clamp(0.0, max as f64) as u32;
+        trace!("{} ch={} v={:.3} raw=0x{:03x}", self.name, channel, v, raw);
+        raw
+    }
+
+    fn align(&self, v: u32) -> u32 {
+        if self.cr2 & CR2_ALIGN == 0 {
+            v
+        } else if self.resolution_bits() == 6 {
+            // 6 bit results are aligned on a byte
+            (v << 2) & 0xFF
+        } else {
+            (v << (16 - self.resolution_bits())) & 0xFFFF
+        }
+    }
+
+    fn check_watchdog(&mut self, channel: u8, raw: u32, injected: bool) {
+        let enabled = if injected { CR1_JAWDEN } else { CR1_AWDEN };
+        if self.cr1 & enabled == 0 {
+            return;
+        }
+        if self.cr1 & CR1_AWDSGL != 0 && channel as u32 != self.cr1 & CR1_AWDCH_MASK {
+            return;
+        }
+        // Thresholds are compared with the 12 bit value
+        let raw = raw << (12 - self.resolution_bits());
+        if raw > self.htr || raw < self.ltr {
+            self.sr |= SR_AWD;
+        }
+    }
+
+    fn update_irq(&self, sys: &System) {
+        let pending = (self.sr & SR_EOC != 0 && self.cr1 & CR1_EOCIE != 0) ||
+                      (self.sr & SR_JEOC != 0 && self.cr1 & CR1_JEOCIE != 0) ||
+                      (self.sr & SR_AWD != 0 && self.cr1 & CR1_AWDIE != 0) ||
+                      (self.sr & SR_OVR != 0 && self.cr1 & CR1_OVRIE != 0);
+        if pending {
+            if let Some(irq) = self.irq {
+                sys.p.nvic.borrow_mut().set_intr_pending(irq);
+            }
+        }
+    }
+
+    fn schedule_regular(&mut self, sys: &System, pos: usize) {
+        let channel = self.regular_sequence()[pos];
+        let delay = self.conversion_cycles(sys, channel);
+        self.regular_pos = Some(pos);
+        sys.p.clock.borrow_mut().schedule(delay, self.base, EVENT_REGULAR);
+    }
+
+    fn start_regular(&mut self, sys: &System) {
+        if self.regular_pos.is_some() {
+            return;
+        }
+        self.sr |= SR_STRT;
+        self.schedule_regular(sys, 0);
+    }
+
+    fn start_injected(&mut self, sys: &System) {
+        if self.injected_running {
+            return;
+        }
+        let delay = self.injected_sequence().iter()
+            .map(|(_, channel)| self.conversion_cycles(sys, *channel))
+            .sum();
+        self.sr |= SR_JSTRT;
+        self.injected_running = true;
+        sys.p.clock.borrow_mut().schedule(delay, self.base, EVENT_INJECTED);
+    }
+
+    fn stop(&mut self, sys: &System) {
+        let mut clock = sys.p.clock.borrow_mut();
+        clock.cancel(self.base, EVENT_REGULAR);
+        clock.cancel(self.base, EVENT_INJECTED);
+        self.regular_pos = None;
+        self.injected_running = false;
+        self.dma_pending = false;
+    }
+
+    fn on_regular_conversion(&mut self, sys: &System) {
+        let pos = match self.regular_pos {
+            Some(pos) => pos,
+            None => return,
+        };
+        let sequence = self.regular_sequence();
+        // The sequence may have been shortened during the conversion
+        let pos = pos.min(sequence.len() - 1);
+        let channel = sequence[pos];
+        let raw = self.convert(sys, channel);
+
+        // Data is lost when the previous one wasn't read
+        let unread = if self.cr2 & CR2_DMA != 0 { self.dma_pending } else { self.sr & SR_EOC != 0 && self.cr2 & CR2_EOCS != 0 };
+        if unread {
+            // Only logged once until the flag is cleared, it can happen on every conversion
+            if self.sr & SR_OVR == 0 {
+                warn!("{} overrun", self.name);
+            }
+            self.sr |= SR_OVR;
+        }
+
+        self.dr = self.align(raw);
+        self.check_watchdog(channel, raw, false);
+
+        let last = pos + 1 == sequence.len();
+        if last || self.cr2 & CR2_EOCS != 0 {
+            self.sr |= SR_EOC;
+        }
+        if self.cr2 & CR2_DMA != 0 {
+            self.dma_pending = true;
+            sys.p.send_trigger(Trigger::DmaRequest);
+        }
+
+        if !last {
+            self.schedule_regular(sys, pos + 1);
+        } else if self.cr2 & CR2_CONT != 0 {
+            self.schedule_regular(sys, 0);
+        } else {
+            self.regular_pos = None;
+            // JAUTO converts the injected group after the regular one
+            if self.cr1 & CR1_JAUTO != 0 {
+                self.start_injected(sys);
+            }
+        }
+    }
+
+    fn on_injected_conversion(&mut self, sys: &System) {
+        self.injected_running = false;
+        for (i, channel) in self.injected_sequence() {
+            let raw = self.convert(sys, channel);
+            self.check_watchdog(channel, raw, true);
+            // The offset is subtracted, and the result may be negative
+            let v = (raw as i32 - (self.jofr[i] & 0xFFF) as i32) as u32;
+            self.jdr[i] = if self.cr2 & CR2_ALIGN == 0 { v & 0xFFFF } else { self.align(v) };
+        }
+        self.sr |= SR_JEOC;
+    }
+}
+
+impl Peripheral for Adc {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.sr,
+            0x0004 => self.cr1,
+            0x0008 => self.cr2,
+            0x000C => self.smpr[0],
+            0x0010 => self.smpr[1],
+            0x0014..=0x0020 => self.jofr[((offset - 0x14) / 4) as usize],
+            0x0024 => self.htr,
+            0x0028 => self.ltr,
+            0x002C..=0x0034 => self.sqr[((offset - 0x2C) / 4) as usize],
+            0x0038 => self.jsqr,
+            0x003C..=0x0048 => self.jdr[((offset - 0x3C) / 4) as usize],
+            DR_OFFSET => {
+                // Reading DR clears EOC
+                self.sr &= !SR_EOC;
+                self.dma_pending = false;
+                self.update_irq(sys);
+                self.dr
+            }
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => {
+                // rc_w0 flags
+                self.sr &= value | !0x3F;
+                self.update_irq(sys);
+            }
+            0x0004 => {
+                self.cr1 = value;
+                self.update_irq(sys);
+            }
+            0x0008 => {
+                let was_on = self.cr2 & CR2_ADON != 0;
+                let was_dma = self.cr2 & CR2_DMA != 0;
+                // The start bits are cleared by hardware when the conversion starts
+                self.cr2 = value & !(CR2_SWSTART | CR2_JSWSTART);
+
+                if self.cr2 & CR2_ADON == 0 {
+                    if was_on {
+                        self.stop(sys);
+                    }
+                    return;
+                }
+
+                if !was_dma {
+                    self.dma_pending = false;
+                }
+                if value & CR2_SWSTART != 0 {
+                    self.start_regular(sys);
+                }
+                if value & CR2_JSWSTART != 0 {
+                    self.start_injected(sys);
+                }
+            }
+            0x000C => self.smpr[0] = value,
+            0x0010 => self.smpr[1] = value,
+            0x0014..=0x0020 => self.jofr[((offset - 0x14) / 4) as usize] = value & 0xFFF,
+            0x0024 => self.htr = value & 0xFFF,
+            0x0028 => self.ltr = value & 0xFFF,
+            0x002C..=0x0034 => self.sqr[((offset - 0x2C) / 4) as usize] = value,
+            0x0038 => self.jsqr = value,
+            _ => {}
+        }
+    }
+
+    fn paces_dma(&self) -> bool {
+        true
+    }
+
+    fn dma_request(&mut self, _sys: &System, offset: u32, to_memory: bool) -> bool {
+        if offset == DR_OFFSET && to_memory && self.dma_pending {
+            // The DMA reads DR, which clears the request
+            true
+        } else {
+            false
+        }
+    }
+
+    fn on_event(&mut self, sys: &System, event: u32) {
+        match event {
+            EVENT_REGULAR => self.on_regular_conversion(sys),
+            EVENT_INJECTED => self.on_injected_conversion(sys),
+            _ => {}
+        }
+        self.update_irq(sys);
+    }
+}
+
+/// The common registers of the ADCs
+pub struct AdcCommonWrapper {
+    // Base address of ADC1
+    adc_base: u32,
+}
+
+impl AdcCommonWrapper {
+    pub fn new(name: &str, base: u32) -> Option<Box<dyn Peripheral>> {
+        if name == "ADC_Common" {
+            Some(Box::new(Self { adc_base: base - 0x300 }))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for AdcCommonWrapper {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => {
+                // CSR mirrors the status flags of the 3 ADCs
+                (0..3).map(|i| {
+                    let addr = self.adc_base + 0x100 * i;
+                    let sr = super::Peripherals::get_peripheral(&sys.p.peripherals, addr)
+                        .and_then(|p| p.peripheral.try_borrow_mut().ok().map(|mut p| p.read(sys, 0)))
+                        .unwrap_or(0);
+                    (sr & 0x3F) << (8 * i)
+                }).fold(0, |a, b| a | b)
+            }
+            0x0004 => sys.p.adc_common.borrow().ccr,
+            // Multi ADC modes are not supported
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        if offset == 0x0004 {
+            if value & 0x1F != 0 {
+                warn!("ADC multi mode is not supported");
+            }
+            sys.p.adc_common.borrow_mut().ccr = value;
+        }
+    }
+}
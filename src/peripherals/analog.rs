@@ -0,0 +1,377 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Analog signals fed to the ADC inputs. They are functions of the virtual
+// time, so what the firmware measures is reproducible from run to run.
+
+use std::f64::consts::PI;
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::util;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalogSourceConfig {
+    /// Voltage in volts
+    Constant(f64),
+    Sine {
+        amplitude: f64,
+        /// Hz
+        frequency: f64,
+        offset: Option<f64>,
+        /// Radians
+        phase: Option<f64>,
+    },
+    /// Sawtooth going from `from` to `to` volts in `period` seconds
+    Ramp {
+        from: f64,
+        to: f64,
+        period: f64,
+    },
+    /// File with `time,voltage` lines (seconds, volts). Values are interpolated.
+    Csv {
+        file: String,
+        /// Play the file in a loop. Otherwise, the last value is held.
+        repeat: Option<bool>,
+    },
+    /// Math expression of `t`, the time in seconds. e.g. "1.65 + 0.5*sin(2*pi*50*t)"
+    Expression(String),
+}
+
+pub enum AnalogSource {
+    Constant(f64),
+    Sine { amplitude: f64, frequency: f64, offset: f64, phase: f64 },
+    Ramp { from: f64, to: f64, period: f64 },
+    Samples { points: Vec<(f64, f64)>, repeat: bool },
+    Expression(Expr),
+}
+
+impl AnalogSource {
+    pub fn new(config: &AnalogSourceConfig) -> Result<Self> {
+        Ok(match config {
+            AnalogSourceConfig::Constant(v) => Self::Constant(*v),
+            AnalogSourceConfig::Sine { amplitude, frequency, offset, phase } => Self::Sine {
+                amplitude: *amplitude,
+                frequency: *frequency,
+                offset: offset.unwrap_or(0.0),
+                phase: phase.unwrap_or(0.0),
+            },
+            AnalogSourceConfig::Ramp { from, to, period } => {
+                if *period <= 0.0 {
+                    bail!("Ramp period must be positive");
+                }
+                Self::Ramp { from: *from, to: *to, period: *period }
+            }
+            AnalogSourceConfig::Csv { file, repeat } => {
+                let points = parse_csv(&util::read_file_str(file)?)
+                    .with_context(|| format!("Failed to parse {}", file))?;
+                Self::Samples { points, repeat: repeat.unwrap_or(false) }
+            }
+            AnalogSourceConfig::Expression(expr) => {
+                Self::Expression(Expr::parse(expr)
+                    .with_context(|| format!("Failed to parse expression '{}'", expr))?)
+            }
+        })
+    }
+
+    /// Voltage at time `t` in seconds
+    pub fn voltage(&self, t: f64) -> f64 {
+        match self {
+            Self::Constant(v) => *v,
+            Self::Sine { amplitude, frequency, offset, phase } => {
+                offset + amplitude * (2.0 * PI * frequency * t + phase).sin()
+            }
+            Self::Ramp { from, to, period } => {
+                from + (to - from) * (t % period) / period
+            }
+            Self::Samples { points, repeat } => {
+                let (first, last) = match (points.first(), points.last()) {
+                    (Some(first), Some(last)) => (first, last),
+                    _ => return 0.0,
+                };
+                let duration = last.0 - first.0;
+                let t = if *repeat && duration > 0.0 {
+                    first.0 + (t - first.0).rem_euclid(duration)
+                } else {
+                    t
+                };
+                interpolate(points, t)
+            }
+            Self::Expression(expr) => expr.eval(t),
+        }
+    }
+}
+
+fn parse_csv(content: &str) -> Result<Vec<(f64, f64)>> {
+    let mut points = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(',').map(|f| f.trim().parse::<f64>());
+        match (fields.next(), fields.next()) {
+            (Some(Ok(t)), Some(Ok(v))) => points.push((t, v)),
+            // A header line is fine
+            _ if points.is_empty() && i == 0 => {}
+            _ => bail!("line {}: expected `time,voltage`", i+1),
+        }
+    }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(points)
+}
+
+fn interpolate(points: &[(f64, f64)], t: f64) -> f64 {
+    let i = points.partition_point(|(pt, _)| *pt <= t);
+    match (i.checked_sub(1).map(|i| points[i]), points.get(i).copied()) {
+        (Some((t0, v0)), Some((t1, v1))) => v0 + (v1 - v0) * (t - t0) / (t1 - t0),
+        (Some((_, v)), None) | (None, Some((_, v))) => v,
+        (None, None) => 0.0,
+    }
+}
+
+/// A small math expression evaluator.
+/// Supports numbers, `t`, `pi`, `+ - * / % ^`, parentheses, and the functions
+/// sin, cos, tan, abs, sqrt, exp, ln, floor, min, max.
+pub enum Expr {
+    Num(f64),
+    Time,
+    Neg(Box<Expr>),
+    BinOp(char, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parser = Parser { s: s.as_bytes(), pos: 0 };
+        let expr = parser.expr()?;
+        parser.skip_spaces();
+        if parser.pos != s.len() {
+            bail!("Unexpected character at position {}", parser.pos);
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, t: f64) -> f64 {
+        match self {
+            Self::Num(v) => *v,
+            Self::Time => t,
+            Self::Neg(e) => -e.eval(t),
+            Self::BinOp(op, a, b) => {
+                let (a, b) = (a.eval(t), b.eval(t));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    '%' => a.rem_euclid(b),
+                    '^' => a.powf(b),
+                    _ => unreachable!(),
+                }
+            }
+            Self::Call(f, args) => {
+                let args = args.iter().map(|a| a.eval(t)).collect::<Vec<_>>();
+                match (f.as_str(), args.as_slice()) {
+                    ("sin", [x]) => x.sin(),
+                    ("cos", [x]) => x.cos(),
+                    ("tan", [x]) => x.tan(),
+                    ("abs", [x]) => x.abs(),
+                    ("sqrt", [x]) => x.sqrt(),
+                    ("exp", [x]) => x.exp(),
+                    ("ln", [x]) => x.ln(),
+                    ("floor", [x]) => x.floor(),
+                    ("min", [x, y]) => x.min(*y),
+                    ("max", [x, y]) => x.max(*y),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        while self.s.get(self.pos).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.s.get(self.pos).map(|c| *c as char)
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() != Some(c) {
+            bail!("Expected '{}' at position {}", c, self.pos);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // expr := term (('+'|'-') term)*
+    fn expr(&mut self) -> Result<Expr> {
+        let mut e = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            e = Expr::BinOp(op, Box::new(e), Box::new(self.term()?));
+        }
+        Ok(e)
+    }
+
+    // term := unary (('*'|'/'|'%') unary)*
+    fn term(&mut self) -> Result<Expr> {
+        let mut e = self.unary()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.pos += 1;
+            e = Expr::BinOp(op, Box::new(e), Box::new(self.unary()?));
+        }
+        Ok(e)
+    }
+
+    // unary := '-' unary | power
+    fn unary(&mut self) -> Result<Expr> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    // power := primary ('^' unary)?
+    fn power(&mut self) -> Result<Expr> {
+        let e = self.primary()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            return Ok(Expr::BinOp('^', Box::new(e), Box::new(self.unary()?)));
+        }
+        Ok(e)
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let e = self.expr()?;
+                self.expect(')')?;
+                Ok(e)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self.s.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == b'.' || *c == b'e' ||
+                    ((*c == b'-' || *c == b'+') && self.s[self.pos-1] == b'e')) {
+                    self.pos += 1;
+                }
+                let num = std::str::from_utf8(&self.s[start..self.pos]).unwrap();
+                Ok(Expr::Num(num.parse().with_context(|| format!("Invalid number {}", num))?))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.s.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_') {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.s[start..self.pos]).unwrap().to_string();
+                match name.as_str() {
+                    "t" => Ok(Expr::Time),
+                    "pi" => Ok(Expr::Num(PI)),
+                    _ => {
+                        self.expect('(')?;
+                        let mut args = vec![self.expr()?];
+                        while self.peek() == Some(',') {
+                            self.pos += 1;
+                            args.push(self.expr()?);
+                        }
+                        self.expect(')')?;
+
+                        let num_args = match name.as_str() {
+                            "sin" | "cos" | "tan" | "abs" | "sqrt" | "exp" | "ln" | "floor" => 1,
+                            "min" | "max" => 2,
+                            _ => bail!("Unknown function {}", name),
+                        };
+                        if args.len() != num_args {
+                            bail!("{}() takes {} argument(s)", name, num_args);
+                        }
+                        Ok(Expr::Call(name, args))
+                    }
+                }
+            }
+            Some(c) => bail!("Unexpected '{}' at position {}", c, self.pos),
+            None => bail!("Unexpected end of expression"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, t: f64) -> f64 {
+        Expr::parse(s).unwrap().eval(t)
+    }
+
+    #[test]
+    fn expr_precedence() {
+        assert_eq!(eval("1 + 2 * 3", 0.0), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", 0.0), 9.0);
+        assert_eq!(eval("10 - 4 - 3", 0.0), 3.0);
+        assert_eq!(eval("10 % 4 * 2", 0.0), 4.0);
+        assert_eq!(eval("2 ^ 3 ^ 2", 0.0), 512.0);
+        assert_eq!(eval("-2 ^ 2", 0.0), -4.0);
+        assert_eq!(eval("2 * -t", 3.0), -6.0);
+    }
+
+    #[test]
+    fn expr_numbers_and_functions() {
+        assert_eq!(eval("1e-3 * 1000", 0.0), 1.0);
+        assert_eq!(eval("2.5e+2", 0.0), 250.0);
+        assert_eq!(eval("max(1, min(t, 3))", 5.0), 3.0);
+        assert_eq!(eval("floor(t)", 1.7), 1.0);
+        assert!(eval("sin(pi)", 0.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn expr_rejects_bad_input() {
+        assert!(Expr::parse("1 +").is_err());
+        assert!(Expr::parse("(1").is_err());
+        assert!(Expr::parse("1 2").is_err());
+        assert!(Expr::parse("foo(1)").is_err());
+        assert!(Expr::parse("min(1)").is_err());
+    }
+
+    #[test]
+    fn parse_csv_works() {
+        let points = parse_csv("time,voltage\n# comment\n1, 3.0\n\n0,1\n").unwrap();
+        assert_eq!(points, vec![(0.0, 1.0), (1.0, 3.0)]);
+
+        assert_eq!(parse_csv("0,1\n1,2\n").unwrap().len(), 2);
+        // Only the first line can be a header
+        assert!(parse_csv("0,1\ntime,voltage\n").is_err());
+        assert!(parse_csv("0,1\n2\n").is_err());
+    }
+
+    #[test]
+    fn interpolate_works() {
+        let points = [(0.0, 1.0), (1.0, 3.0), (3.0, 0.0)];
+        assert_eq!(interpolate(&points, 0.5), 2.0);
+        assert_eq!(interpolate(&points, 1.0), 3.0);
+        assert_eq!(interpolate(&points, 2.0), 1.5);
+        // The first and last values are held
+        assert_eq!(interpolate(&points, -1.0), 1.0);
+        assert_eq!(interpolate(&points, 10.0), 0.0);
+        assert_eq!(interpolate(&[], 1.0), 0.0);
+    }
+
+    #[test]
+    fn samples_repeat() {
+        let source = AnalogSource::Samples { points: vec![(0.0, 0.0), (2.0, 2.0)], repeat: true };
+        assert_eq!(source.voltage(3.0), 1.0);
+        let source = AnalogSource::Samples { points: vec![(0.0, 0.0), (2.0, 2.0)], repeat: false };
+        assert_eq!(source.voltage(3.0), 2.0);
+    }
+}
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// The virtual clock. One executed instruction is one HCLK cycle. Frequencies
+// come from the RCC configuration, and peripherals can schedule events that
+// are delivered at a given cycle via `Peripheral::on_event()`.
+
+use std::{collections::BinaryHeap, cmp::Reverse, sync::atomic::Ordering};
+
+pub const HSI_FREQUENCY: u32 = 16_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Event {
+    cycle: u64,
+    // Events scheduled for the same cycle are delivered in order
+    seq: u64,
+    // The peripheral receiving the event is found by its address
+    addr: u32,
+    id: u32,
+}
+
+pub struct Clock {
+    pub sysclk: u32,
+    pub hclk: u32,
+    pub pclk1: u32,
+    pub pclk2: u32,
+    // APB prescalers, timers run at twice the APB clock when it's not 1.
+    apb1_prescaler: u32,
+    apb2_prescaler: u32,
+
+    // Virtual time at the last frequency change
+    base_cycles: u64,
+    base_ns: u64,
+
+    events: BinaryHeap<Reverse<Event>>,
+    next_seq: u64,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        // At reset, we run on the HSI, with no prescalers.
+        Self {
+            sysclk: HSI_FREQUENCY,
+            hclk: HSI_FREQUENCY,
+            pclk1: HSI_FREQUENCY,
+            pclk2: HSI_FREQUENCY,
+            apb1_prescaler: 1,
+            apb2_prescaler: 1,
+            base_cycles: 0,
+            base_ns: 0,
+            events: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl Clock {
+    /// Current cycle count
+    pub fn now(&self) -> u64 {
+        crate::emulator::NUM_INSTRUCTIONS.load(Ordering::Relaxed)
+    }
+
+    /// Virtual time since boot in nanoseconds
+    pub fn now_ns(&self) -> u64 {
+        let cycles = self.now() - self.base_cycles;
+        self.base_ns + (cycles as u128 * 1_000_000_000 / self.hclk as u128) as u64
+    }
+
+    pub fn set_frequencies(&mut self, sysclk: u32, hpre: u32, ppre1: u32, ppre2: u32) {
+        let hclk = sysclk / hpre;
+        if (sysclk, hclk, self.apb1_prescaler, self.apb2_prescaler) == (self.sysclk, self.hclk, ppre1, ppre2) {
+            return;
+        }
+
+        self.base_ns = self.now_ns();
+        self.base_cycles = self.now();
+
+        self.sysclk = sysclk;
+        self.hclk = hclk;
+        self.pclk1 = hclk / ppre1;
+        self.pclk2 = hclk / ppre2;
+        self.apb1_prescaler = ppre1;
+        self.apb2_prescaler = ppre2;
+
+        info!("Clocks sysclk={}Hz hclk={}Hz pclk1={}Hz pclk2={}Hz",
+            self.sysclk, self.hclk, self.pclk1, self.pclk2);
+    }
+
+    /// Peripherals below this address are on APB1, otherwise on APB2 (or AHB).
+    fn is_apb1(addr: u32) -> bool {
+        addr < 0x4001_0000
+    }
+
+    /// The clock feeding a peripheral bus, given one of its addresses
+    pub fn pclk(&self, addr: u32) -> u32 {
+        if Self::is_apb1(addr) { self.pclk1 } else { self.pclk2 }
+    }
+
+    /// Timers get twice the APB clock when the APB prescaler is not 1
+    pub fn timer_clock(&self, addr: u32) -> u32 {
+        let prescaler = if Self::is_apb1(addr) { self.apb1_prescaler } else { self.apb2_prescaler };
+        let pclk = self.pclk(addr);
+        if prescaler == 1 { pclk } else { 2 * pclk }
+    }
+
+    /// Converts a number of ticks of a clock running at `freq` into HCLK cycles.
+    /// Never returns 0, so events always make progress.
+    pub fn ticks_to_cycles(&self, ticks: u64, freq: u32) -> u64 {
+        let cycles = (ticks as u128 * self.hclk as u128 / freq.max(1) as u128) as u64;
+        cycles.max(1)
+    }
+
+    pub fn ns_to_cycles(&self, ns: u64) -> u64 {
+        self.ticks_to_cycles(ns, 1_000_000_000)
+    }
+
+    /// Schedules an event for the peripheral at `addr` in `delay` cycles.
+    pub fn schedule(&mut self, delay: u64, addr: u32, id: u32) {
+        let event = Event { cycle: self.now() + delay, seq: self.next_seq, addr, id };
+        self.next_seq += 1;
+        self.events.push(Reverse(event));
+    }
+
+    pub fn cancel(&mut self, addr: u32, id: u32) {
+        self.events.retain(|Reverse(e)| !(e.addr == addr && e.id == id));
+    }
+
+    pub fn has_due_event(&self) -> bool {
+        self.events.peek().is_some_and(|Reverse(e)| e.cycle <= self.now())
+    }
+
+    /// Returns the (addr, id) of the next event that is due.
+    pub fn pop_due_event(&mut self) -> Option<(u32, u32)> {
+        if self.has_due_event() {
+            self.events.pop().map(|Reverse(e)| (e.addr, e.id))
+        } else {
+            None
+        }
+    }
+}
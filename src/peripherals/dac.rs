@@ -0,0 +1,444 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// The DAC doesn't drive anything, but each conversion is recorded with its
+// virtual timestamp. Conversions are written to a file as they happen, as CSV
+// or WAV depending on the file extension. That's how we can listen to the
+// beeper, or check the analog setpoints.
+
+use std::{io::{BufWriter, Write, Seek, SeekFrom}, fs::File};
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+use super::{Peripheral, Trigger};
+
+#[derive(Debug, Deserialize, Default)]
+pub struct DacConfig {
+    /// Where to write the conversions. A .wav file gets audio, anything else gets CSV.
+    pub file: Option<String>,
+    /// Sample rate of the WAV file. Defaults to 44100Hz.
+    pub sample_rate: Option<u32>,
+}
+
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// CR bits, for channel 1. Channel 2 bits are 16 bits higher.
+const CR_EN: u32 = 1 << 0;
+const CR_TEN: u32 = 1 << 2;
+const CR_DMAEN: u32 = 1 << 12;
+const CR_DMAUDRIE: u32 = 1 << 13;
+
+// SR bits, for channel 1
+const SR_DMAUDR: u32 = 1 << 13;
+
+const WAVE_NOISE: u32 = 0b01;
+const WAVE_TRIANGLE: u32 = 0b10;
+
+// TSEL values. 6 is EXTI line 9, which we don't support.
+const TSEL_SOURCES: [Option<Trigger>; 6] = [
+    Some(Trigger::TimTrgo(6)),
+    Some(Trigger::TimTrgo(8)),
+    Some(Trigger::TimTrgo(7)),
+    Some(Trigger::TimTrgo(5)),
+    Some(Trigger::TimTrgo(2)),
+    Some(Trigger::TimTrgo(4)),
+];
+const TSEL_SOFTWARE: u32 = 0b111;
+
+struct Recording {
+    file: String,
+    w: BufWriter<File>,
+    conversions: u64,
+    // None for CSV
+    wav: Option<Wav>,
+}
+
+// The RIFF size (36 + data size) must fit in 32 bits
+const WAV_MAX_DATA_LEN: u32 = u32::MAX - 36;
+
+/// Resamples the conversions as they come. Each channel holds its last value
+/// until the next conversion.
+struct Wav {
+    sample_rate: u32,
+    next_sample: u64,
+    // Time of the last conversion, in ns
+    end: u64,
+    values: [u16; 2],
+    data_len: u32,
+    full: bool,
+}
+
+impl Wav {
+    /// Writes the samples up to time `t` in ns
+    fn write_samples(&mut self, w: &mut impl Write, t: u64, inclusive: bool) -> std::io::Result<()> {
+        while !self.full {
+            let sample_t = (self.next_sample as u128 * 1_000_000_000 / self.sample_rate as u128) as u64;
+            if sample_t > t || (sample_t == t && !inclusive) {
+                break;
+            }
+            if self.data_len > WAV_MAX_DATA_LEN - 4 {
+                warn!("DAC recording reached the WAV size limit, dropping the rest");
+                self.full = true;
+                break;
+            }
+
+            // 16 bit PCM. The DAC is unipolar, the middle of the range is silence.
+            for v in self.values {
+                let sample = (v as i32 - 2048) * 16;
+                w.write_all(&(sample as i16).to_le_bytes())?;
+            }
+            self.data_len += 4;
+            self.next_sample += 1;
+        }
+        Ok(())
+    }
+}
+
+fn write_wav_header(w: &mut impl Write, sample_rate: u32, data_len: u32) -> std::io::Result<()> {
+    const NUM_CHANNELS: u16 = 2;
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVEfmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?; // PCM
+    w.write_all(&NUM_CHANNELS.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * NUM_CHANNELS as u32 * 2).to_le_bytes())?;
+    w.write_all(&(NUM_CHANNELS * 2).to_le_bytes())?;
+    w.write_all(&16u16.to_le_bytes())?;
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
+
+impl Recording {
+    fn create(file: String, sample_rate: u32) -> Result<Self> {
+        let f = File::create(&file)
+            .with_context(|| format!("Failed to create {}", file))?;
+        let mut w = BufWriter::new(f);
+
+        let wav = if file.to_lowercase().ends_with(".wav") {
+            // The sizes are filled at the end
+            write_wav_header(&mut w, sample_rate, 0)?;
+            Some(Wav { sample_rate, next_sample: 0, end: 0, values: [2048; 2], data_len: 0, full: false })
+        } else {
+            writeln!(w, "time,channel,value,voltage")?;
+            None
+        };
+
+        Ok(Self { file, w, conversions: 0, wav })
+    }
+
+    fn record(&mut self, t: u64, ch: usize, v: u16, vref: f64) -> std::io::Result<()> {
+        self.conversions += 1;
+        match self.wav.as_mut() {
+            Some(wav) => {
+                // Samples before the conversion have the previous values
+                wav.write_samples(&mut self.w, t, false)?;
+                wav.values[ch] = v;
+                wav.end = t;
+                Ok(())
+            }
+            None => writeln!(self.w, "{:.9},{},{},{:.4}", t as f64 / 1e9, ch+1, v, v as f64 * vref / 4095.0),
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some(wav) = self.wav.as_mut() {
+            wav.write_samples(&mut self.w, wav.end, true)?;
+            self.w.seek(SeekFrom::Start(0))?;
+            write_wav_header(&mut self.w, wav.sample_rate, wav.data_len)?;
+        }
+        self.w.flush()
+            .with_context(|| format!("Failed to write {}", self.file))?;
+
+        info!("Wrote {} DAC conversions to {}", self.conversions, self.file);
+        Ok(())
+    }
+}
+
+pub struct Dac {
+    irq: Option<i32>,
+    vref: f64,
+
+    cr: u32,
+    sr: u32,
+    dhr: [u32; 2],
+    dor: [u32; 2],
+
+    // Wave generators
+    lfsr: [u32; 2],
+    triangle: [(u32, bool); 2],
+    // A trigger happened with DMAEN set, and the DMA hasn't written DHR yet
+    dma_pending: [bool; 2],
+
+    recording: Option<Recording>,
+}
+
+impl Default for Dac {
+    fn default() -> Self {
+        Self {
+            irq: None,
+            vref: 0.0,
+            cr: 0,
+            sr: 0,
+            dhr: [0; 2],
+            dor: [0; 2],
+            // The noise LFSR resets to 0xAAA
+            lfsr: [0xAAA; 2],
+            triangle: [(0, false); 2],
+            dma_pending: [false; 2],
+            recording: None,
+        }
+    }
+}
+
+impl Dac {
+    pub fn new(config: DacConfig, vref: f64) -> Result<Self> {
+        let sample_rate = config.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        if sample_rate == 0 || sample_rate > u32::MAX / 4 {
+            bail!("Invalid DAC sample_rate {}", sample_rate);
+        }
+        let recording = config.file.map(|file| Recording::create(file, sample_rate)).transpose()?;
+        Ok(Self { vref, recording, ..Self::default() })
+    }
+
+    /// The DAC interrupt (underrun) is shared with TIM6
+    pub fn configure_irq(&mut self, interrupts: &[(String, i32)]) {
+        self.irq = interrupts.first().map(|(_, irq)| *irq);
+    }
+
+    fn channel_cr(&self, ch: usize) -> u32 {
+        (self.cr >> (16 * ch)) & 0xFFFF
+    }
+
+    /// Mask of the wave generators, from MAMP
+    fn wave_mask(&self, ch: usize) -> u32 {
+        let mamp = (self.channel_cr(ch) >> 8) & 0xF;
+        (1 << (mamp.min(11) + 1)) - 1
+    }
+
+    fn next_wave(&mut self, ch: usize) -> u32 {
+        let mask = self.wave_mask(ch);
+        match (self.channel_cr(ch) >> 6) & 0b11 {
+            WAVE_NOISE => {
+                let v = self.lfsr[ch] & mask;
+                // 12 bit LFSR, with taps on bits 0, 1, 4 and 6
+                let l = self.lfsr[ch];
+                let bit = (l ^ (l >> 1) ^ (l >> 4) ^ (l >> 6)) & 1;
+                self.lfsr[ch] = ((l >> 1) | (bit << 11)) & 0xFFF;
+                v
+            }
+            WAVE_TRIANGLE => {
+                let (v, down) = self.triangle[ch];
+                self.triangle[ch] = match (down, v) {
+                    (false, v) if v >= mask => (v - 1, true),
+                    (false, v) => (v + 1, false),
+                    (true, 0) => (1, false),
+                    (true, v) => (v - 1, true),
+                };
+                v
+            }
+            _ => 0,
+        }
+    }
+
+    /// DHR is transferred to DOR
+    fn convert(&mut self, sys: &System, ch: usize, triggered: bool) {
+        let wave = if triggered { self.next_wave(ch) } else { 0 };
+        let v = (self.dhr[ch] + wave).min(0xFFF);
+        self.dor[ch] = v;
+
+        if let Some(recording) = self.recording.as_mut() {
+            let now = sys.p.clock.borrow().now_ns();
+            if let Err(e) = recording.record(now, ch, v as u16, self.vref) {
+                warn!("Failed to write {}, stopping the DAC recording: {}", recording.file, e);
+                self.recording = None;
+            }
+        }
+        trace!("DAC{} v={:.3}", ch+1, v as f64 * self.vref / 4095.0);
+    }
+
+    fn trigger(&mut self, sys: &System, ch: usize) {
+        self.convert(sys, ch, true);
+
+        if self.channel_cr(ch) & CR_DMAEN != 0 {
+            if self.dma_pending[ch] {
+                // The DMA didn't keep up. Only logged once until the flag is cleared.
+                if self.sr & (SR_DMAUDR << (16 * ch)) == 0 {
+                    warn!("DAC{} DMA underrun", ch+1);
+                }
+                self.sr |= SR_DMAUDR << (16 * ch);
+                if self.channel_cr(ch) & CR_DMAUDRIE != 0 {
+                    if let Some(irq) = self.irq {
+                        sys.p.nvic.borrow_mut().set_intr_pending(irq);
+                    }
+                }
+            }
+            self.dma_pending[ch] = true;
+            sys.p.send_trigger(Trigger::DmaRequest);
+        }
+    }
+
+    fn tsel(&self, ch: usize) -> u32 {
+        (self.channel_cr(ch) >> 3) & 0b111
+    }
+
+    fn is_triggered_by(&self, ch: usize, source: Option<Trigger>) -> bool {
+        let cr = self.channel_cr(ch);
+        if cr & CR_EN == 0 || cr & CR_TEN == 0 {
+            return false;
+        }
+        let tsel = self.tsel(ch);
+        match source {
+            Some(source) => TSEL_SOURCES.get(tsel as usize) == Some(&Some(source)),
+            None => tsel == TSEL_SOFTWARE,
+        }
+    }
+
+    pub fn on_trigger(&mut self, sys: &System, trigger: Trigger) {
+        for ch in 0..2 {
+            if self.is_triggered_by(ch, Some(trigger)) {
+                self.trigger(sys, ch);
+            }
+        }
+    }
+
+    /// Channels whose DHR is accessed at this offset
+    fn dhr_channels(offset: u32) -> &'static [usize] {
+        match offset {
+            0x08..=0x10 => &[0],
+            0x14..=0x1C => &[1],
+            0x20..=0x28 => &[0, 1],
+            _ => &[],
+        }
+    }
+
+    fn write_dhr(&mut self, sys: &System, offset: u32, value: u32) {
+        let channels = Self::dhr_channels(offset);
+        for (i, ch) in channels.iter().enumerate() {
+            // In dual mode, channel 2 is in the upper half (or upper byte for DHR8RD)
+            let v = if channels.len() == 2 && i == 1 {
+                if offset == 0x28 { value >> 8 } else { value >> 16 }
+            } else {
+                value
+            };
+            // 12 bit right, 12 bit left, 8 bit right
+            self.dhr[*ch] = match (offset - 0x08) % 0xC {
+                0x0 => v & 0xFFF,
+                0x4 => (v >> 4) & 0xFFF,
+                _ => (v & 0xFF) << 4,
+            };
+            self.dma_pending[*ch] = false;
+
+            // Without triggers, the conversion happens right away
+            let cr = self.channel_cr(*ch);
+            if cr & CR_EN != 0 && cr & CR_TEN == 0 {
+                self.convert(sys, *ch, false);
+            }
+        }
+    }
+
+    fn read_dhr(&self, offset: u32) -> u32 {
+        let format = |v: u32| match (offset - 0x08) % 0xC {
+            0x0 => v,
+            0x4 => v << 4,
+            _ => v >> 4,
+        };
+        match offset {
+            0x08..=0x10 => format(self.dhr[0]),
+            0x14..=0x1C => format(self.dhr[1]),
+            0x28 => format(self.dhr[0]) | (format(self.dhr[1]) << 8),
+            _ => format(self.dhr[0]) | (format(self.dhr[1]) << 16),
+        }
+    }
+
+    /// Completes the recording file, at the end of the emulation
+    pub fn finish_recording(&mut self) -> Result<()> {
+        match self.recording.take() {
+            Some(recording) => recording.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Peripheral for Dac {
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr,
+            0x0004 => 0, // SWTRIGR
+            0x0008..=0x0028 => self.read_dhr(offset),
+            0x002C => self.dor[0],
+            0x0030 => self.dor[1],
+            0x0034 => self.sr,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => {
+                let was_enabled = [self.channel_cr(0) & CR_EN != 0, self.channel_cr(1) & CR_EN != 0];
+                self.cr = value;
+                for (ch, was_enabled) in was_enabled.into_iter().enumerate() {
+                    if !was_enabled && self.channel_cr(ch) & CR_EN != 0 {
+                        trace!("DAC{} enabled", ch+1);
+                    }
+                }
+            }
+            0x0004 => {
+                // Software triggers, cleared by hardware
+                for ch in 0..2 {
+                    if value & (1 << ch) != 0 && self.is_triggered_by(ch, None) {
+                        self.trigger(sys, ch);
+                    }
+                }
+            }
+            0x0008..=0x0028 => self.write_dhr(sys, offset, value),
+            0x0034 => {
+                // rc_w1
+                self.sr &= !(value & (SR_DMAUDR | (SR_DMAUDR << 16)));
+            }
+            _ => {}
+        }
+    }
+
+    fn dma_request(&mut self, _sys: &System, offset: u32, to_memory: bool) -> bool {
+        // Dual mode transfers are paced by channel 1
+        match Self::dhr_channels(offset).first() {
+            Some(ch) if !to_memory => std::mem::replace(&mut self.dma_pending[*ch], false),
+            _ => false,
+        }
+    }
+}
+
+pub struct DacWrapper;
+
+impl DacWrapper {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "DAC" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for DacWrapper {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        sys.p.dac.borrow_mut().read(sys, offset)
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        sys.p.dac.borrow_mut().write(sys, offset, value)
+    }
+
+    fn dma_request(&mut self, sys: &System, offset: u32, to_memory: bool) -> bool {
+        sys.p.dac.borrow_mut().dma_request(sys, offset, to_memory)
+    }
+
+    fn paces_dma(&self) -> bool {
+        true
+    }
+}
@@ -1,30 +1,97 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+
 use crate::util::UniErr;
 use crate::system::System;
 use super::Peripheral;
 use super::Peripherals;
+use super::clock::Clock;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct DmaConfig {
+    /// When true, streams move one item each time the peripheral requests it,
+    /// instead of doing the whole transfer when enabled. Circular and double
+    /// buffer streams always do, as their transfer never ends.
+    pub request_driven: Option<bool>,
+}
 
 #[derive(Default)]
 pub struct Dma {
     name: String,
+    base: u32,
+    request_driven: bool,
     streams: [Stream; 8],
 }
 
+const EVENT_SERVE_REQUESTS: u32 = 0;
+
 impl Dma {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+    pub fn new(name: &str, base: u32, interrupts: &[(String, i32)], config: &DmaConfig) -> Option<Box<dyn Peripheral>> {
         if name.starts_with("DMA") {
             let name = name.to_string();
-            Some(Box::new(Self { name, ..Self::default() }))
+            let request_driven = config.request_driven.unwrap_or(false);
+            let mut dma = Self { name, base, request_driven, ..Self::default() };
+
+            // Interrupts are named DMA1_Stream0, DMA1_Stream1, etc.
+            for (irq_name, irq) in interrupts {
+                let stream = irq_name.rsplit_once("_Stream")
+                    .and_then(|(_, i)| i.parse::<usize>().ok())
+                    .filter(|i| *i < dma.streams.len());
+                if let Some(i) = stream {
+                    dma.streams[i].irq = Some(*irq);
+                }
+            }
+
+            Some(Box::new(dma))
         } else {
             None
         }
     }
+
+    /// Bit position of the flags of a stream in the ISR/IFCR registers
+    fn flags_shift(stream: usize) -> u32 {
+        [0, 6, 16, 22][stream % 4]
+    }
+
+    fn read_isr(&self, first_stream: usize) -> u32 {
+        (0..4).fold(0, |v, i| v | (self.streams[first_stream+i].flags as u32) << Self::flags_shift(i))
+    }
+
+    /// Peripherals send a `Trigger::DmaRequest` when they have a new request,
+    /// which gets the waiting streams served.
+    pub fn schedule_serve(clock: &mut Clock, base: u32, delay: u64) {
+        clock.cancel(base, EVENT_SERVE_REQUESTS);
+        clock.schedule(delay, base, EVENT_SERVE_REQUESTS);
+    }
+
+    /// Returns true when some items were moved
+    fn serve_requests(&mut self, sys: &System) -> bool {
+        let mut moved = false;
+        for stream in self.streams.iter_mut().filter(|s| s.waits_for_requests()) {
+            if stream.is_requested(sys) {
+                stream.transfer(&self.name, sys, 1);
+                moved = true;
+            }
+        }
+        moved
+    }
+
+    fn write_ifcr(&mut self, first_stream: usize, value: u32) {
+        for i in 0..4 {
+            let clear = (value >> Self::flags_shift(i)) as u8 & FLAGS_MASK;
+            self.streams[first_stream+i].flags &= !clear;
+        }
+    }
 }
 
 impl Peripheral for Dma {
     fn read(&mut self, sys: &System, offset: u32) -> u32 {
         match Access::from_offset(offset) {
+            Access::Reg(0x0000) => self.read_isr(0),
+            Access::Reg(0x0004) => self.read_isr(4),
             Access::StreamReg(i, offset) => self.streams[i].read(&self.name, sys, offset),
             _ => 0
         }
@@ -32,13 +99,49 @@ impl Peripheral for Dma {
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
         match Access::from_offset(offset) {
-            Access::StreamReg(i, offset) => self.streams[i].write(&self.name, sys, offset, value),
+            Access::Reg(0x0008) => self.write_ifcr(0, value),
+            Access::Reg(0x000C) => self.write_ifcr(4, value),
+            Access::StreamReg(i, offset) => {
+                self.streams[i].write(&self.name, sys, offset, value, self.request_driven);
+                // The peripheral may already have a request for the stream
+                if self.streams[i].waits_for_requests() {
+                    Self::schedule_serve(&mut sys.p.clock.borrow_mut(), self.base, 0);
+                }
+            }
             _ => {}
         }
     }
+
+    fn on_event(&mut self, sys: &System, _event: u32) {
+        // Some requests stay up after being served, like a USART with room in
+        // DR. We keep serving at one item per cycle until they are all down.
+        if self.serve_requests(sys) {
+            Self::schedule_serve(&mut sys.p.clock.borrow_mut(), self.base, 1);
+        }
+    }
 }
 
-#[derive(Default)]
+// CR bits
+const CR_EN: u32 = 1 << 0;
+const CR_CIRC: u32 = 1 << 8;
+const CR_PINC: u32 = 1 << 9;
+const CR_MINC: u32 = 1 << 10;
+const CR_PINCOS: u32 = 1 << 15;
+const CR_DBM: u32 = 1 << 18;
+const CR_CT: u32 = 1 << 19;
+
+// Stream flags, as they appear in the ISR registers
+const FLAG_FEIF: u8 = 1 << 0;
+const FLAG_TEIF: u8 = 1 << 3;
+const FLAG_HTIF: u8 = 1 << 4;
+const FLAG_TCIF: u8 = 1 << 5;
+const FLAGS_MASK: u8 = 0b11_1101;
+
+// FCR bits
+const FCR_FEIE: u32 = 1 << 7;
+const FCR_FS_MASK: u32 = 0b111 << 3;
+const FCR_FS_EMPTY: u32 = 0b100 << 3;
+
 struct Stream {
     pub cr: u32,
     pub next_cr: Option<u32>,
@@ -47,6 +150,39 @@ struct Stream {
     pub m0ar: u32,
     pub m1ar: u32,
     pub fcr: u32,
+
+    irq: Option<i32>,
+    flags: u8,
+    // The stream moves items when the peripheral requests it
+    request_driven: bool,
+    // NDTR value programmed by the firmware, reloaded in circular mode
+    ndtr_reload: u32,
+    // Progress in bytes in the current buffer, on each side
+    peri_offset: u32,
+    mem_offset: u32,
+    // Packing/unpacking between the peripheral and memory sizes
+    fifo: VecDeque<u8>,
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Self {
+            cr: 0,
+            next_cr: None,
+            ndtr: 0,
+            par: 0,
+            m0ar: 0,
+            m1ar: 0,
+            fcr: 0x21,
+            irq: None,
+            flags: 0,
+            request_driven: false,
+            ndtr_reload: 0,
+            peri_offset: 0,
+            mem_offset: 0,
+            fifo: VecDeque::new(),
+        }
+    }
 }
 
 impl Stream {
@@ -64,8 +200,8 @@ impl Stream {
     }
 
     // 1, 2, 4 (8bit, 16bit, 32bit)
-    fn word_size(&self) -> usize {
-        match (self.cr >> 11) & 0b11 {
+    fn size_from_bits(bits: u32) -> usize {
+        match bits & 0b11 {
             0b00 => 1,
             0b01 => 2,
             0b10 => 4,
@@ -73,70 +209,179 @@ impl Stream {
         }
     }
 
+    fn psize(&self) -> usize {
+        Self::size_from_bits(self.cr >> 11)
+    }
+
+    fn msize(&self) -> usize {
+        Self::size_from_bits(self.cr >> 13)
+    }
+
     fn data_size(&self) -> usize {
-        self.word_size() * self.ndtr as usize
+        self.psize() * self.ndtr as usize
+    }
+
+    fn is_circular(&self) -> bool {
+        // Double buffer mode implies circular mode
+        self.cr & (CR_CIRC | CR_DBM) != 0
     }
 
     fn data_addr(&self) -> u32 {
-        if (self.cr >> 19) & 1 != 0 {
+        if self.cr & CR_CT != 0 {
             self.m1ar
         } else {
             self.m0ar
         }
     }
 
-    fn do_xfer(&self, name: &str, sys: &System) {
-        let dir = self.dir();
-        let data_addr = self.data_addr();
-        let size = self.data_size();
-        let peri_addr = self.par;
+    fn waits_for_requests(&self) -> bool {
+        self.request_driven && self.cr & CR_EN != 0 && self.ndtr > 0
+    }
 
-        let peri = Peripherals::get_peripheral(&sys.p.peripherals, peri_addr);
+    /// Asks the peripheral if it has a DMA request for this stream
+    fn is_requested(&self, sys: &System) -> bool {
+        let to_memory = self.dir() == Dir::Read;
+        Peripherals::get_peripheral(&sys.p.peripherals, self.par)
+            .and_then(|p| p.peripheral.try_borrow_mut().ok()
+                .map(|mut peri| peri.dma_request(sys, self.par - p.start, to_memory)))
+            .unwrap_or(false)
+    }
 
-        let (src, dst) = match dir {
-            Dir::Read => (peri_addr, data_addr),
-            Dir::Write => (data_addr, peri_addr),
-            Dir::MemCopy => (peri_addr, data_addr),
-            Dir::Invalid => (0,0),
-        };
+    /// The peripheral sets the pace of the transfer, see `Peripheral::paces_dma()`
+    fn is_paced(&self, sys: &System) -> bool {
+        Peripherals::get_peripheral(&sys.p.peripherals, self.par)
+            .and_then(|p| p.peripheral.try_borrow().ok().map(|peri| peri.paces_dma()))
+            .unwrap_or(false)
+    }
 
-        if log::log_enabled!(log::Level::Debug) {
-            let peri_desc = sys.p.addr_desc(peri_addr);
-            debug!("{} xfer initiated channel={} peri_{} dir={:?} addr=0x{:08x} size={}",
-                name, self.channel(), peri_desc, dir, data_addr, size);
-        }
+    fn peri_item_addr(&self) -> u32 {
+        if self.cr & CR_PINC != 0 { self.par + self.peri_offset } else { self.par }
+    }
+
+    fn mem_item_addr(&self) -> u32 {
+        if self.cr & CR_MINC != 0 { self.data_addr() + self.mem_offset } else { self.data_addr() }
+    }
+
+    fn start_buffer(&mut self) {
+        self.peri_offset = 0;
+        self.mem_offset = 0;
+        self.fifo.clear();
+    }
+
+    /// Moves one item of PSIZE. Returns false on a bus error.
+    fn transfer_item(&mut self, sys: &System) -> bool {
+        let (psize, msize) = (self.psize(), self.msize());
+        let peri_step = if self.cr & CR_PINCOS != 0 { 4 } else { psize as u32 };
 
-        let buf = match dir {
-            Dir::Read => {
-                peri.map(|p| p.peripheral.borrow_mut().read_dma(sys, peri_addr-p.start, size))
+        match self.dir() {
+            Dir::Read | Dir::MemCopy => {
+                let v = match bus_read(sys, self.peri_item_addr(), psize) {
+                    Some(v) => v,
+                    None => return false,
+                };
+                self.fifo.extend(&v.to_le_bytes()[..psize]);
+                self.peri_offset += peri_step;
+
+                while self.fifo.len() >= msize {
+                    let v = self.fifo.drain(..msize).rev().fold(0, |v, b| (v << 8) | b as u32);
+                    if !bus_write(sys, self.mem_item_addr(), msize, v) {
+                        return false;
+                    }
+                    self.mem_offset += msize as u32;
+                }
             }
-            Dir::Write | Dir::MemCopy => {
-                sys.uc.borrow().mem_read_as_vec(src.into(), size)
-                    .map_err(|e| warn!("DMA read failed addr=0x{:08x} size={} e={}", src, size, UniErr(e)))
-                    .map(|v| v.into())
-                    .ok()
+            Dir::Write => {
+                while self.fifo.len() < psize {
+                    let v = match bus_read(sys, self.mem_item_addr(), msize) {
+                        Some(v) => v,
+                        None => return false,
+                    };
+                    self.fifo.extend(&v.to_le_bytes()[..msize]);
+                    self.mem_offset += msize as u32;
+                }
+
+                let v = self.fifo.drain(..psize).rev().fold(0, |v, b| (v << 8) | b as u32);
+                if !bus_write(sys, self.peri_item_addr(), psize, v) {
+                    return false;
+                }
+                self.peri_offset += peri_step;
             }
-            Dir::Invalid => Some(vec![].into()),
-        };
+            Dir::Invalid => {}
+        }
 
-        let mut buf = buf.unwrap_or_else(|| {
-            let mut rx = vec![];
-            rx.resize(size, 0);
-            rx.into()
-        });
+        true
+    }
 
-        trace!("{} xfer buf={:x?}", name, buf);
+    /// Moves up to `count` items, and handles the half transfer and transfer
+    /// complete events.
+    fn transfer(&mut self, name: &str, sys: &System, count: u32) {
+        for _ in 0..count {
+            if self.cr & CR_EN == 0 || self.ndtr == 0 {
+                break;
+            }
 
-        match dir {
-            Dir::Write => {
-                peri.map(|p| p.peripheral.borrow_mut().write_dma(sys, peri_addr-p.start, buf));
+            if !self.transfer_item(sys) {
+                warn!("{} transfer error", name);
+                self.flags |= FLAG_TEIF;
+                self.cr &= !CR_EN;
+                break;
             }
-            Dir::Read | Dir::MemCopy => {
-                if let Err(e) = sys.uc.borrow_mut().mem_write(dst.into(), buf.make_contiguous()) {
-                    warn!("DMA read failed addr=0x{:08x} size={} e={}", dst, size, UniErr(e));
+
+            self.ndtr -= 1;
+
+            if self.ndtr == self.ndtr_reload / 2 {
+                self.flags |= FLAG_HTIF;
+            }
+
+            if self.ndtr == 0 {
+                self.flags |= FLAG_TCIF;
+                if self.is_circular() {
+                    self.ndtr = self.ndtr_reload;
+                    if self.cr & CR_DBM != 0 {
+                        self.cr ^= CR_CT;
+                    }
+                    self.start_buffer();
+                } else {
+                    self.cr &= !CR_EN;
                 }
             }
-            Dir::Invalid => {}
+        }
+
+        self.update_irq(sys);
+    }
+
+    fn update_irq(&self, sys: &System) {
+        // TCIE, HTIE, TEIE, DMEIE are next to each other, like their flags
+        let mut enabled = (((self.cr >> 1) & 0b1111) << 2) as u8;
+        if self.fcr & FCR_FEIE != 0 {
+            enabled |= FLAG_FEIF;
+        }
+
+        if self.flags & enabled != 0 {
+            if let Some(irq) = self.irq {
+                sys.p.nvic.borrow_mut().set_intr_pending(irq);
+            }
+        }
+    }
+
+    fn enable(&mut self, name: &str, sys: &System, request_driven: bool) {
+        if log::log_enabled!(log::Level::Debug) {
+            let peri_desc = sys.p.addr_desc(self.par);
+            debug!("{} xfer initiated channel={} peri_{} dir={:?} addr=0x{:08x} size={} circ={}",
+                name, self.channel(), peri_desc, self.dir(), self.data_addr(), self.data_size(), self.is_circular());
+        }
+
+        self.ndtr_reload = self.ndtr;
+        self.start_buffer();
+
+        // Memory to memory transfers don't wait for requests. Circular ones
+        // can't be done at once, and paced ones would lose data, so they always do.
+        self.request_driven = (request_driven || self.is_circular() || self.is_paced(sys))
+            && self.dir() != Dir::MemCopy;
+        if !self.request_driven {
+            // Transfers are done all at once
+            let count = self.ndtr;
+            self.transfer(name, sys, count);
         }
     }
 
@@ -153,8 +398,10 @@ impl Stream {
                 // wait for it to go to 1 and then 0, with a timeout. So they
                 // are consistently hitting the timeout.
                 // We'll do toggles on the ready flag to speed things up avoiding the timeout.
-                if self.dir() == Dir::Write && self.data_size() == 0 {
-                    self.next_cr = Some(self.cr ^ 1)
+                // This must stay for the saturn, but it only concerns empty
+                // normal streams, which never move data anyway.
+                if self.dir() == Dir::Write && self.data_size() == 0 && !self.is_circular() {
+                    self.next_cr = Some(self.cr ^ CR_EN)
                 }
 
                 v
@@ -163,24 +410,34 @@ impl Stream {
             0x0008 => self.par,
             0x000c => self.m0ar,
             0x0010 => self.m1ar,
-            0x0014 => self.fcr,
+            // The FIFO is always empty
+            0x0014 => (self.fcr & !FCR_FS_MASK) | FCR_FS_EMPTY,
             _ => 0
         }
     }
 
-    pub fn write(&mut self, name: &str, sys: &System, offset: u32, mut value: u32) {
+    pub fn write(&mut self, name: &str, sys: &System, offset: u32, value: u32, request_driven: bool) {
         match offset {
             0x0000 => {
+                // CRx register
+                // Ignore the EN bit we may be showing after a completed transfer
+                let was_enabled = self.next_cr.take().unwrap_or(self.cr) & CR_EN != 0;
                 self.cr = value;
 
-                // CRx register
-                if value & 1 != 0 {
-                    // Enable is on. do the transfer.
-                    self.do_xfer(name, sys);
+                if value & CR_EN != 0 && !was_enabled {
+                    self.enable(name, sys, request_driven);
 
-                    value &= !1;
-                    self.ndtr = 0;
-                    self.next_cr = Some(value);
+                    // The firmware sees EN=1 on its next read, and then the
+                    // actual state of the stream. The saturn firmware waits
+                    // for EN to go to 1 and then 0 after enabling a stream,
+                    // which it would miss with transfers done at once.
+                    // Circular streams are never done at once, and keep EN=1.
+                    if self.cr & CR_EN == 0 {
+                        self.next_cr = Some(self.cr);
+                        self.cr |= CR_EN;
+                    }
+                } else {
+                    self.update_irq(sys);
                 }
             }
             0x0004 => { self.ndtr = value & 0xFFFF; }
@@ -193,6 +450,31 @@ impl Stream {
     }
 }
 
+/// Reads from memory or from a peripheral
+fn bus_read(sys: &System, addr: u32, size: usize) -> Option<u32> {
+    if Peripherals::is_mmio(addr) {
+        Some(sys.p.read(sys, addr, size as u8))
+    } else {
+        let mut buf = [0u8; 4];
+        sys.uc.borrow().mem_read(addr.into(), &mut buf[..size])
+            .map_err(|e| warn!("DMA read failed addr=0x{:08x} size={} e={}", addr, size, UniErr(e)))
+            .ok()?;
+        Some(u32::from_le_bytes(buf))
+    }
+}
+
+/// Writes to memory or to a peripheral
+fn bus_write(sys: &System, addr: u32, size: usize, value: u32) -> bool {
+    if Peripherals::is_mmio(addr) {
+        sys.p.write(sys, addr, size as u8, value);
+        true
+    } else {
+        sys.uc.borrow_mut().mem_write(addr.into(), &value.to_le_bytes()[..size])
+            .map_err(|e| warn!("DMA write failed addr=0x{:08x} size={} e={}", addr, size, UniErr(e)))
+            .is_ok()
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum Dir {
     Read,
@@ -209,7 +491,7 @@ enum Access {
 
 impl Access {
     pub fn from_offset(offset: u32) -> Self {
-        if offset < 0x28 {
+        if offset < 0x10 {
             Access::Reg(offset)
         } else {
             let stride = 0x18;
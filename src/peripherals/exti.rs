@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::system::System;
+use super::{Peripheral, nvic::Nvic};
+
+pub const NUM_LINES: usize = 23;
+pub const NUM_GPIO_LINES: usize = 16;
+
+// Lines 16 and up are connected to internal peripherals.
+// (line, irq) for the STM32F4.
+const INTERNAL_LINES_IRQ: [(usize, i32); 7] = [
+    (16, 1),  // PVD
+    (17, 41), // RTC Alarm
+    (18, 42), // OTG FS wakeup
+    (19, 62), // Ethernet wakeup
+    (20, 76), // OTG HS wakeup
+    (21, 2),  // RTC Tamper and TimeStamp
+    (22, 3),  // RTC Wakeup
+];
+
+pub mod line {
+    pub const RTC_ALARM: usize = 17;
+    pub const RTC_WAKEUP: usize = 22;
+}
+
+#[derive(Default)]
+pub struct Exti {
+    imr: u32,
+    emr: u32,
+    rtsr: u32,
+    ftsr: u32,
+    swier: u32,
+    pr: u32,
+
+    irqs: [Option<i32>; NUM_LINES],
+    // GPIO port of each of the GPIO lines, configured via SYSCFG_EXTICR
+    ports: [u8; NUM_GPIO_LINES],
+    // Last level seen on each line, to detect edges
+    levels: u32,
+    known_levels: u32,
+}
+
+impl Exti {
+    /// Interrupts come from the SVD file, e.g. EXTI0, EXTI9_5, EXTI15_10.
+    pub fn configure_irqs(&mut self, interrupts: &[(String, i32)]) {
+        for (name, irq) in interrupts {
+            let lines = match name.strip_prefix("EXTI").map(|l| l.split_once('_').unwrap_or((l, l))) {
+                Some((last, first)) => match (first.parse::<usize>(), last.parse::<usize>()) {
+                    (Ok(first), Ok(last)) => first.min(last)..=first.max(last),
+                    _ => continue,
+                },
+                None => continue,
+            };
+            for line in lines.filter(|l| *l < NUM_GPIO_LINES) {
+                self.irqs[line] = Some(*irq);
+            }
+        }
+
+        for (line, irq) in INTERNAL_LINES_IRQ {
+            self.irqs[line] = Some(irq);
+        }
+    }
+
+    pub fn set_gpio_port(&mut self, line: usize, port: u8) {
+        self.ports[line] = port;
+        // The level of the new pin is not known yet
+        self.known_levels &= !(1 << line);
+    }
+
+    pub fn gpio_port(&self, line: usize) -> u8 {
+        self.ports[line]
+    }
+
+    /// GPIO lines that can generate an interrupt or an event. Returns (line, port).
+    pub fn watched_gpio_lines(&self) -> Vec<(usize, u8)> {
+        let watched = (self.imr | self.emr) & (self.rtsr | self.ftsr);
+        (0..NUM_GPIO_LINES)
+            .filter(|line| watched & (1 << line) != 0)
+            .map(|line| (line, self.ports[line]))
+            .collect()
+    }
+
+    fn set_pending(&mut self, nvic: &mut Nvic, line: usize) {
+        let bit = 1 << line;
+        if self.imr & bit == 0 {
+            return;
+        }
+        trace!("EXTI line={} pending", line);
+        self.pr |= bit;
+        if let Some(irq) = self.irqs[line] {
+            nvic.set_intr_pending(irq);
+        }
+    }
+
+    /// For internal lines that only produce pulses (RTC alarm, wakeup)
+    pub fn trigger_line(&mut self, nvic: &mut Nvic, line: usize) {
+        if self.rtsr & (1 << line) != 0 {
+            self.set_pending(nvic, line);
+        }
+    }
+
+    /// Called with the current level of a line. Edges trigger the line
+    /// according to RTSR and FTSR.
+    pub fn set_line_level(&mut self, nvic: &mut Nvic, line: usize, level: bool) {
+        let bit = 1 << line;
+        let known = self.known_levels & bit != 0;
+        let previous = self.levels & bit != 0;
+
+        self.known_levels |= bit;
+        if level { self.levels |= bit } else { self.levels &= !bit }
+
+        if !known || previous == level {
+            return;
+        }
+
+        let trigger = if level { self.rtsr } else { self.ftsr };
+        if trigger & bit != 0 {
+            self.set_pending(nvic, line);
+        }
+    }
+}
+
+impl Exti {
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.imr,
+            0x0004 => self.emr,
+            0x0008 => self.rtsr,
+            0x000C => self.ftsr,
+            0x0010 => self.swier,
+            0x0014 => self.pr,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        let mask = (1 << NUM_LINES) - 1;
+        let value = value & mask;
+        match offset {
+            0x0000 => self.imr = value,
+            0x0004 => self.emr = value,
+            0x0008 => self.rtsr = value,
+            0x000C => self.ftsr = value,
+            0x0010 => {
+                // Setting a bit in SWIER triggers the line
+                let new_bits = value & !self.swier;
+                self.swier |= value;
+                let mut nvic = sys.p.nvic.borrow_mut();
+                for line in (0..NUM_LINES).filter(|l| new_bits & (1 << l) != 0) {
+                    self.set_pending(&mut nvic, line);
+                }
+            }
+            0x0014 => {
+                // Pending bits are cleared by writing 1, which also clears SWIER
+                self.pr &= !value;
+                self.swier &= !value;
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct ExtiWrapper;
+
+impl ExtiWrapper {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "EXTI" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for ExtiWrapper {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        sys.p.exti.borrow_mut().read(sys, offset)
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        sys.p.exti.borrow_mut().write(sys, offset, value)
+    }
+}
@@ -58,6 +58,13 @@ impl GpioPorts {
         v
     }
 
+    /// Returns None when nothing drives the pin
+    pub fn read_pin(&mut self, sys: &System, port: u8, pin: u8) -> Option<bool> {
+        self.read_callbacks[port as usize].iter_mut()
+            .find(|(pin_cb, _)| *pin_cb == pin)
+            .map(|(_, cb)| cb(sys))
+    }
+
     pub fn write_port(&mut self, sys: &System, port: u8, pin: u8, value: bool) {
         for (pin_cb, cb) in &mut self.write_callbacks[port as usize] {
             if *pin_cb == pin {
@@ -1,53 +1,346 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+// I2C in master mode. Transfers are instantaneous: bytes are exchanged with
+// the external device on the bus as soon as the firmware reads or writes DR.
+// When receiving, up to two bytes are buffered, like DR and the shift register.
+// Devices are selected by their 7-bit address.
+
+use std::{rc::Rc, cell::RefCell, collections::VecDeque};
+
+use crate::ext_devices::{ExtDevices, I2cDevice};
 use crate::system::System;
 use super::Peripheral;
 
+// CR1 bits
+const CR1_PE: u32 = 1 << 0;
+const CR1_START: u32 = 1 << 8;
+const CR1_STOP: u32 = 1 << 9;
+const CR1_ACK: u32 = 1 << 10;
+const CR1_POS: u32 = 1 << 11;
+const CR1_SWRST: u32 = 1 << 15;
+
+// CR2 bits
+const CR2_ITERREN: u32 = 1 << 8;
+const CR2_ITEVTEN: u32 = 1 << 9;
+const CR2_ITBUFEN: u32 = 1 << 10;
+
+// SR1 bits
+const SR1_SB: u32 = 1 << 0;
+const SR1_ADDR: u32 = 1 << 1;
+const SR1_BTF: u32 = 1 << 2;
+const SR1_RXNE: u32 = 1 << 6;
+const SR1_TXE: u32 = 1 << 7;
+const SR1_AF: u32 = 1 << 10;
+// Error flags are cleared by writing 0
+const SR1_ERRORS_MASK: u32 = 0b1101_1111 << 8;
+
+// SR2 bits
+const SR2_MSL: u32 = 1 << 0;
+const SR2_BUSY: u32 = 1 << 1;
+const SR2_TRA: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Idle,
+    // Start condition generated, waiting for the address in DR
+    Start,
+    Transmit,
+    Receive,
+}
+
+/// Devices connected to an I2C peripheral, selected by their 7-bit address
+#[derive(Default)]
+pub struct I2cBus {
+    devices: Vec<Rc<RefCell<dyn I2cDevice>>>,
+}
+
+impl I2cBus {
+    pub fn new(devices: Vec<Rc<RefCell<dyn I2cDevice>>>) -> Self {
+        Self { devices }
+    }
+
+    pub fn find(&self, addr: u8) -> Option<&Rc<RefCell<dyn I2cDevice>>> {
+        self.devices.iter().find(|d| d.borrow().has_address(addr))
+    }
+}
+
 #[derive(Default)]
 pub struct I2c {
     name: String,
-    toggle: u8,
+    bus: I2cBus,
+    irq_ev: Option<i32>,
+    irq_er: Option<i32>,
+
+    cr1: u32,
+    cr2: u32,
+    oar1: u32,
+    oar2: u32,
+    ccr: u32,
+    trise: u32,
+    fltr: u32,
+
+    state: State,
+    // Device selected by the address phase
+    device: Option<Rc<RefCell<dyn I2cDevice>>>,
+    addr_flag: bool,
+    // Set when a byte was transmitted, DR is empty, the firmware can stop
+    byte_transferred: bool,
+    // Received bytes not yet read from DR. The second one is in the shift register.
+    rx: VecDeque<u8>,
+    // The last received byte was not acknowledged, the device sends no more
+    nacked: bool,
+    // STOP was requested while received bytes are still to be read
+    stop_pending: bool,
+    errors: u32,
 }
 
 impl I2c {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+    pub fn new(name: &str, interrupts: &[(String, i32)], ext_devices: &ExtDevices) -> Option<Box<dyn Peripheral>> {
         if name.starts_with("I2C") {
-            let name = name.to_string();
-            Some(Box::new(Self { name, ..I2c::default() }))
+            let devices = ext_devices.find_i2c_devices(name);
+            for d in &devices {
+                let dev_name = d.borrow_mut().connect_peripheral(name);
+                debug!("{} on the bus", dev_name);
+            }
+
+            let find_irq = |suffix: &str| interrupts.iter()
+                .find(|(n, _)| n.ends_with(suffix))
+                .map(|(_, irq)| *irq);
+
+            Some(Box::new(Self {
+                name: name.to_string(),
+                bus: I2cBus::new(devices),
+                irq_ev: find_irq("_EV"),
+                irq_er: find_irq("_ER"),
+                trise: 0x0002,
+                ..I2c::default()
+            }))
         } else {
             None
         }
     }
+
+    fn sr1(&self) -> u32 {
+        let mut v = self.errors;
+        match self.state {
+            State::Start => v |= SR1_SB,
+            _ if self.addr_flag => v |= SR1_ADDR,
+            State::Transmit => {
+                v |= SR1_TXE;
+                if self.byte_transferred { v |= SR1_BTF; }
+            }
+            State::Receive => {
+                if !self.rx.is_empty() { v |= SR1_RXNE; }
+                if self.rx.len() == 2 { v |= SR1_BTF; }
+            }
+            State::Idle => {}
+        }
+        v
+    }
+
+    fn sr2(&self) -> u32 {
+        let mut v = 0;
+        if self.state != State::Idle {
+            v |= SR2_MSL | SR2_BUSY;
+        }
+        if self.state == State::Transmit {
+            v |= SR2_TRA;
+        }
+        v
+    }
+
+    fn update_irq(&self, sys: &System) {
+        let sr1 = self.sr1();
+
+        let mut ev_mask = 0;
+        if self.cr2 & CR2_ITEVTEN != 0 {
+            ev_mask |= SR1_SB | SR1_ADDR | SR1_BTF;
+            if self.cr2 & CR2_ITBUFEN != 0 {
+                ev_mask |= SR1_TXE | SR1_RXNE;
+            }
+        }
+        let er_mask = if self.cr2 & CR2_ITERREN != 0 { SR1_ERRORS_MASK } else { 0 };
+
+        let mut nvic = sys.p.nvic.borrow_mut();
+        if let (true, Some(irq)) = (sr1 & ev_mask != 0, self.irq_ev) {
+            nvic.set_intr_pending(irq);
+        }
+        if let (true, Some(irq)) = (sr1 & er_mask != 0, self.irq_er) {
+            nvic.set_intr_pending(irq);
+        }
+    }
+
+    fn start(&mut self) {
+        trace!("{} START", self.name);
+        self.state = State::Start;
+        self.addr_flag = false;
+        self.byte_transferred = false;
+        self.rx.clear();
+        self.nacked = false;
+        self.stop_pending = false;
+    }
+
+    fn stop(&mut self, sys: &System) {
+        trace!("{} STOP", self.name);
+        if let Some(device) = self.device.take() {
+            device.borrow_mut().stop(sys);
+        }
+        self.state = State::Idle;
+        self.addr_flag = false;
+        self.byte_transferred = false;
+        self.rx.clear();
+        self.nacked = false;
+        self.stop_pending = false;
+        self.cr1 &= !CR1_STOP;
+    }
+
+    // When receiving, the stop is generated after the last byte is read from DR
+    fn request_stop(&mut self, sys: &System) {
+        if self.state == State::Receive && !self.rx.is_empty() {
+            trace!("{} STOP pending", self.name);
+            self.stop_pending = true;
+        } else {
+            self.stop(sys);
+        }
+    }
+
+    fn send_address(&mut self, sys: &System, value: u8) {
+        let addr = value >> 1;
+        let read = value & 1 != 0;
+
+        let device = self.bus.find(addr).cloned();
+        let ack = device.as_ref().is_some_and(|d| d.borrow_mut().start(sys, addr, read));
+
+        if ack {
+            trace!("{} addr=0x{:02x} {} ACK", self.name, addr, if read { "read" } else { "write" });
+            self.device = device;
+            self.addr_flag = true;
+            self.state = if read { State::Receive } else { State::Transmit };
+        } else {
+            debug!("{} addr=0x{:02x} NACK", self.name, addr);
+            self.device = None;
+            self.errors |= SR1_AF;
+            // The bus stays busy until the firmware generates a stop
+            self.state = State::Transmit;
+        }
+    }
+
+    fn write_data(&mut self, sys: &System, value: u8) {
+        let ack = self.device.as_ref().is_some_and(|d| d.borrow_mut().write(sys, value));
+        trace!("{} write={:02x} {}", self.name, value, if ack { "ACK" } else { "NACK" });
+        if !ack {
+            self.errors |= SR1_AF;
+        }
+        self.byte_transferred = true;
+    }
+
+    /// Receives bytes from the device until DR and the shift register are
+    /// full, the master has not acknowledged a byte, or a stop is pending.
+    fn receive_data(&mut self, sys: &System) {
+        while self.rx.len() < 2 && !self.nacked && !self.stop_pending {
+            let v = self.device.as_ref().map_or(0xFF, |d| d.borrow_mut().read(sys));
+            // With POS, ACK applies to the byte in the shift register
+            let ack = self.cr1 & CR1_ACK != 0 || (self.cr1 & CR1_POS != 0 && self.rx.is_empty());
+            trace!("{} read={:02x} {}", self.name, v, if ack { "ACK" } else { "NACK" });
+            self.nacked = !ack;
+            self.rx.push_back(v);
+        }
+    }
+
+    fn read_data(&mut self, sys: &System) -> u8 {
+        let v = self.rx.pop_front().unwrap_or_default();
+        if self.stop_pending {
+            if self.rx.is_empty() {
+                self.stop(sys);
+            }
+        } else {
+            self.receive_data(sys);
+        }
+        v
+    }
 }
 
 impl Peripheral for I2c {
-    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
         match offset {
+            0x0000 => self.cr1,
+            0x0004 => self.cr2,
+            0x0008 => self.oar1,
+            0x000C => self.oar2,
             0x0010 => {
                 // DR
-                debug!("{} READ", self.name);
-                0
-            }
-            0x0014 => {
-                // SR1
-                self.toggle = (self.toggle + 1) % 5;
-                if self.toggle & 2 != 0 { 0xFFFFFFFF } else { 0 }
+                let v = if self.state == State::Receive && !self.addr_flag {
+                    self.read_data(sys) as u32
+                } else {
+                    0
+                };
+                self.update_irq(sys);
+                v
             }
+            0x0014 => self.sr1(),
             0x0018 => {
-                // SR2
-                self.toggle = (self.toggle + 1) % 5;
-                if self.toggle & 1  != 0{ 0xFFFFFFFF } else { 0 }
+                // Reading SR2 after SR1 clears ADDR
+                let v = self.sr2();
+                if self.addr_flag {
+                    self.addr_flag = false;
+                    if self.state == State::Receive {
+                        self.receive_data(sys);
+                    }
+                    self.update_irq(sys);
+                }
+                v
             }
+            0x001C => self.ccr,
+            0x0020 => self.trise,
+            0x0024 => self.fltr,
             _ => 0
         }
     }
 
-    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
         match offset {
+            0x0000 => {
+                if value & CR1_SWRST != 0 {
+                    self.stop(sys);
+                    self.errors = 0;
+                }
+
+                if value & CR1_PE != 0 {
+                    // START and STOP are cleared by hardware once generated
+                    if value & CR1_START != 0 {
+                        self.start();
+                    } else if value & CR1_STOP != 0 && self.state != State::Idle {
+                        self.request_stop(sys);
+                    }
+                }
+
+                self.cr1 = value & !(CR1_START | CR1_STOP);
+                if self.stop_pending {
+                    self.cr1 |= CR1_STOP;
+                }
+            }
+            0x0004 => self.cr2 = value,
+            0x0008 => self.oar1 = value,
+            0x000C => self.oar2 = value,
             0x0010 => {
-                debug!("{} WRITE value=0x{:08x}", self.name, value);
+                // DR
+                match self.state {
+                    State::Start => self.send_address(sys, value as u8),
+                    State::Transmit if !self.addr_flag => self.write_data(sys, value as u8),
+                    _ => debug!("{} unexpected write={:02x}", self.name, value as u8),
+                }
+            }
+            0x0014 => {
+                // SR1: error flags are cleared by writing 0
+                self.errors &= value;
             }
+            0x001C => self.ccr = value,
+            0x0020 => self.trise = value,
+            0x0024 => self.fltr = value,
             _ => {}
         }
+
+        self.update_irq(sys);
     }
 }
@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+pub mod clock;
 pub mod rcc;
 pub mod spi;
 pub mod usart;
@@ -11,6 +12,13 @@ pub mod i2c;
 pub mod nvic;
 pub mod scb;
 pub mod sw_spi;
+pub mod tim;
+pub mod exti;
+pub mod syscfg;
+pub mod analog;
+pub mod adc;
+pub mod dac;
+pub mod rtc;
 
 use rcc::*;
 use serde::Deserialize;
@@ -24,15 +32,27 @@ use i2c::*;
 use nvic::*;
 use scb::*;
 use sw_spi::*;
-
-use std::{collections::{BTreeMap, VecDeque, HashMap}, cell::RefCell};
+use tim::*;
+use exti::*;
+use syscfg::*;
+use adc::*;
+use dac::*;
+use rtc::*;
+
+use anyhow::Result;
+use std::{collections::{BTreeMap, HashMap, VecDeque}, cell::RefCell};
 use svd_parser::svd::{RegisterInfo, Device as SvdDevice};
 
-use crate::{system::System, ext_devices::ExtDevices};
+use crate::{system::System, ext_devices::ExtDevices, watchpoints::Watchpoints};
 
 #[derive(Debug, Deserialize, Default)]
 pub struct PeripheralsConfig {
     pub software_spi: Option<Vec<SoftwareSpiConfig>>,
+    pub rcc: Option<RccConfig>,
+    pub dma: Option<DmaConfig>,
+    pub adc: Option<AdcConfig>,
+    pub dac: Option<DacConfig>,
+    pub rtc: Option<RtcConfig>,
 }
 
 #[derive(Default)]
@@ -40,7 +60,24 @@ pub struct Peripherals {
     debug_peripherals: Vec<PeripheralSlot<GenericPeripheral>>,
     peripherals: Vec<PeripheralSlot<RefCell<Box<dyn Peripheral>>>>,
     pub nvic: RefCell<Nvic>,
+    pub exti: RefCell<Exti>,
+    pub adc_common: RefCell<AdcCommon>,
+    pub dac: RefCell<Dac>,
+    triggers: RefCell<VecDeque<Trigger>>,
+    // Base addresses of the DMA controllers, to tell them about requests
+    dma_controllers: Vec<u32>,
+    pub clock: RefCell<clock::Clock>,
     pub gpio: RefCell<GpioPorts>,
+    pub watchpoints: RefCell<Watchpoints>,
+}
+
+/// Signals that peripherals send to each other, like a timer TRGO starting a DAC conversion
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    TimTrgo(u8),
+    /// A peripheral has a new DMA request. The DMA controllers ask the
+    /// peripherals of their waiting streams with `dma_request()`.
+    DmaRequest,
 }
 
 pub struct PeripheralSlot<T> {
@@ -56,7 +93,9 @@ impl Peripherals {
         (0xE000_0000, 0xE100_0000),
     ];
 
-    pub fn register_peripheral(&mut self, name: String, base: u32, registers: &[RegisterInfo], ext_devices: &ExtDevices) {
+    /// `interrupts` are the (name, irq) of the NVIC lines of the peripheral.
+    pub fn register_peripheral(&mut self, name: String, base: u32, registers: &[RegisterInfo],
+                               interrupts: &[(String, i32)], config: &PeripheralsConfig, ext_devices: &ExtDevices) {
         let p = GenericPeripheral::new(name.clone(), registers);
 
         let (start, end) = (base, base+p.size());
@@ -72,17 +111,34 @@ impl Peripherals {
             _ => (start, end),
         };
 
+        if name == "EXTI" {
+            self.exti.get_mut().configure_irqs(interrupts);
+        }
+        if name == "DAC" {
+            self.dac.get_mut().configure_irq(interrupts);
+        }
+        if name.starts_with("DMA") {
+            self.dma_controllers.push(base);
+        }
+
         let p = None
             .or_else(|| NvicWrapper::new(&name))
-            .or_else(||     SysTick::new(&name))
+            .or_else(||     SysTick::new(&name, base))
             .or_else(||         Scb::new(&name))
             .or_else(||        Gpio::new(&name))
-            .or_else(||       Usart::new(&name, ext_devices))
+            .or_else(||       Usart::new(&name, base, interrupts, ext_devices))
             .or_else(||        Fsmc::new(&name, ext_devices))
-            .or_else(||         Rcc::new(&name))
-            .or_else(||         I2c::new(&name))
-            .or_else(||         Dma::new(&name))
+            .or_else(||         Rcc::new(&name, config.rcc.as_ref().unwrap_or(&RccConfig::default())))
+            .or_else(||         I2c::new(&name, interrupts, ext_devices))
+            .or_else(||         Dma::new(&name, base, interrupts, config.dma.as_ref().unwrap_or(&DmaConfig::default())))
             .or_else(||         Spi::new(&name, ext_devices))
+            .or_else(||         Tim::new(&name, base, interrupts))
+            .or_else(||  ExtiWrapper::new(&name))
+            .or_else(||      Syscfg::new(&name))
+            .or_else(||         Adc::new(&name, base, interrupts))
+            .or_else(|| AdcCommonWrapper::new(&name, base))
+            .or_else(||  DacWrapper::new(&name))
+            .or_else(||         Rtc::new(&name, base, config.rtc.as_ref().unwrap_or(&RtcConfig::default())))
         ;
 
         if let Some(p) = p {
@@ -108,8 +164,18 @@ impl Peripherals {
         }
     }
 
-    pub fn from_svd(mut svd_device: SvdDevice, config: PeripheralsConfig, gpio: GpioPorts, ext_devices: &ExtDevices) -> Self {
-        let mut peripherals = Self { gpio: RefCell::new(gpio), .. Peripherals::default() };
+    pub fn from_svd(mut svd_device: SvdDevice, mut config: PeripheralsConfig, gpio: GpioPorts, ext_devices: &ExtDevices) -> Result<Self> {
+        if let Some(rtc) = config.rtc.as_ref() {
+            rtc.validate()?;
+        }
+        let adc_common = AdcCommon::new(config.adc.take().unwrap_or_default())?;
+        let dac = Dac::new(config.dac.take().unwrap_or_default(), adc_common.vref())?;
+        let mut peripherals = Self {
+            gpio: RefCell::new(gpio),
+            adc_common: RefCell::new(adc_common),
+            dac: RefCell::new(dac),
+            .. Peripherals::default()
+        };
 
         svd_device.peripherals.sort_by_key(|f| f.base_address);
         let svd_peripherals = svd_device.peripherals.iter()
@@ -119,6 +185,10 @@ impl Peripherals {
         for p in &svd_device.peripherals {
             let name = &p.name;
             let base = p.base_address;
+            // Derived peripherals share registers, but not interrupts
+            let interrupts = p.interrupt.iter()
+                .map(|i| (i.name.clone(), i.value as i32))
+                .collect::<Vec<_>>();
 
             let p = if let Some(derived_from) = p.derived_from.as_ref() {
                 svd_peripherals.get(derived_from)
@@ -130,7 +200,7 @@ impl Peripherals {
 
             let regs = crate::util::extract_svd_registers(p);
 
-            peripherals.register_peripheral(name.to_string(), base as u32, &regs, ext_devices);
+            peripherals.register_peripheral(name.to_string(), base as u32, &regs, &interrupts, &config, ext_devices);
 
             if crate::verbose() >= 3 {
                 for r in &regs {
@@ -139,16 +209,78 @@ impl Peripherals {
             }
         }
 
-        for sw_spi_config in config.software_spi.unwrap_or_default() {
+        for sw_spi_config in config.software_spi.take().unwrap_or_default() {
             SoftwareSpi::register(sw_spi_config, &mut peripherals.gpio.borrow_mut(), ext_devices);
         }
 
         peripherals.finish_registration();
-        peripherals
+        Ok(peripherals)
     }
 
     /////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+    /// Delivers the clock events that are due to their peripherals
+    pub fn run_due_events(&self, sys: &System) {
+        loop {
+            // The clock must not be borrowed while the peripheral runs, as it may schedule new events.
+            let event = self.clock.borrow_mut().pop_due_event();
+            match event {
+                Some((addr, id)) => {
+                    if let Some(p) = Self::get_peripheral(&self.peripherals, addr) {
+                        p.peripheral.borrow_mut().on_event(sys, id);
+                    }
+                    self.deliver_triggers(sys);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Queues a trigger. It is delivered once the sending peripheral is done,
+    /// as the receiving peripheral may need to look at it.
+    pub fn send_trigger(&self, trigger: Trigger) {
+        self.triggers.borrow_mut().push_back(trigger);
+    }
+
+    fn deliver_triggers(&self, sys: &System) {
+        loop {
+            let trigger = self.triggers.borrow_mut().pop_front();
+            match trigger {
+                Some(Trigger::DmaRequest) => {
+                    let mut clock = self.clock.borrow_mut();
+                    for base in &self.dma_controllers {
+                        Dma::schedule_serve(&mut clock, *base, 0);
+                    }
+                }
+                Some(trigger) => self.dac.borrow_mut().on_trigger(sys, trigger),
+                None => break,
+            }
+        }
+    }
+
+    /// Samples the GPIO pins that EXTI is watching, and turns their edges into interrupts.
+    /// This is how external devices (e.g., a touchscreen) can interrupt the firmware.
+    pub fn poll_gpio_edges(&self, sys: &System) {
+        let lines = self.exti.borrow().watched_gpio_lines();
+        if lines.is_empty() {
+            return;
+        }
+
+        // The gpio callbacks may look at other peripherals, so we don't hold exti while calling them.
+        let levels = {
+            let mut gpio = self.gpio.borrow_mut();
+            lines.into_iter()
+                .filter_map(|(line, port)| gpio.read_pin(sys, port, line as u8).map(|level| (line, level)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut exti = self.exti.borrow_mut();
+        let mut nvic = self.nvic.borrow_mut();
+        for (line, level) in levels {
+            exti.set_line_level(&mut nvic, line, level);
+        }
+    }
+
     pub fn get_peripheral<T>(peripherals: &Vec<PeripheralSlot<T>>, addr: u32) -> Option<&PeripheralSlot<T>> {
         let index = peripherals.binary_search_by_key(&addr, |p| p.start)
             .map_or_else(|e| e.checked_sub(1), |v| Some(v));
@@ -176,6 +308,11 @@ impl Peripherals {
         }
     }
 
+    /// True when the address is handled by the peripherals, and not by memory
+    pub fn is_mmio(addr: u32) -> bool {
+        Self::MEMORY_MAPS.iter().any(|(start, end)| (*start..*end).contains(&addr))
+    }
+
     fn is_register(addr: u32) -> bool {
         // this is avoiding the FSMC banks, essentially
         !
@@ -190,7 +327,8 @@ impl Peripherals {
 
     pub fn read(&self, sys: &System, addr: u32, size: u8) -> u32 {
         if let Some((addr, bit_number)) = Self::bitbanding(addr) {
-            return (self.read(sys, addr, 1) >> bit_number) & 1;
+            let (addr, byte_offset) = Self::align_addr_4(addr);
+            return (self.read(sys, addr, 4) >> (bit_number + 8*byte_offset)) & 1;
         }
 
         let (addr, byte_offset) = if Self::is_register(addr) {
@@ -217,10 +355,13 @@ impl Peripherals {
 
     pub fn write(&self, sys: &System, addr: u32, size: u8, mut value: u32) {
         if let Some((addr, bit_number)) = Self::bitbanding(addr) {
-            let mut v = self.read(sys, addr, 1);
-            v &= 1 << bit_number;
+            // Read-modify-write of the whole register
+            let (addr, byte_offset) = Self::align_addr_4(addr);
+            let bit_number = bit_number + 8*byte_offset;
+            let mut v = self.read(sys, addr, 4);
+            v &= !(1 << bit_number);
             v |= (value & 1) << bit_number;
-            return self.write(sys, addr, 1, v);
+            return self.write(sys, addr, 4, v);
         }
 
         let (addr, byte_offset) = if Self::is_register(addr) {
@@ -232,13 +373,23 @@ impl Peripherals {
 
         assert!(byte_offset + size <= 4);
 
-        if byte_offset != 0 {
-            let v = self.read(sys, addr, 4);
-            value = (value << 8*byte_offset) | (v & (0xFFFF_FFFF >> (32-8*byte_offset)));
+        // Lanes of the register that are written
+        let mask = ((((1u64 << (8*size)) - 1) << (8*byte_offset)) & 0xFFFF_FFFF) as u32;
+        value = (value << (8*byte_offset)) & mask;
+
+        // The other lanes keep their value. We only read the register when
+        // needed, as reads can have side effects (e.g., a DMA writing a byte to DR).
+        if mask != 0xFFFF_FFFF {
+            let needs_merge = byte_offset != 0 || Self::get_peripheral(&self.peripherals, addr)
+                .is_some_and(|p| p.peripheral.borrow().has_byte_fields(addr - p.start));
+            if needs_merge {
+                value |= self.read(sys, addr, 4) & !mask;
+            }
         }
 
         if let Some(p) = Self::get_peripheral(&self.peripherals, addr) {
-            p.peripheral.borrow_mut().write(sys, addr - p.start, value)
+            p.peripheral.borrow_mut().write(sys, addr - p.start, value);
+            self.deliver_triggers(sys);
         }
 
         if crate::verbose() >= 3 {
@@ -251,18 +402,20 @@ pub trait Peripheral {
     fn read(&mut self, sys: &System, offset: u32) -> u32;
     fn write(&mut self, sys: &System, offset: u32, value: u32);
 
-    fn read_dma(&mut self, sys: &System, offset: u32, size: usize) -> VecDeque<u8> {
-        let mut v = VecDeque::with_capacity(size);
-        for _ in 0..size {
-            v.push_back(self.read(sys, offset) as u8);
-        }
-        v
-    }
-    fn write_dma(&mut self, sys: &System, offset: u32, value: VecDeque<u8>) {
-        for v in value.into_iter() {
-            self.write(sys, offset, v.into());
-        }
-    }
+    /// True for registers made of byte fields, like interrupt priorities. A byte
+    /// write to such a register leaves the other bytes unchanged.
+    fn has_byte_fields(&self, _offset: u32) -> bool { false }
+
+    /// Called when an event scheduled on the clock with this peripheral's address is due
+    fn on_event(&mut self, _sys: &System, _event: u32) {}
+
+    /// True when the peripheral requests a DMA transfer. `offset` is the
+    /// peripheral address of the stream, and `to_memory` its direction.
+    fn dma_request(&mut self, _sys: &System, _offset: u32, _to_memory: bool) -> bool { false }
+
+    /// True when DMA requests come with time, like conversions. Moving all
+    /// the data at once would lose it, so streams always wait for requests.
+    fn paces_dma(&self) -> bool { false }
 }
 
 struct GenericPeripheral {
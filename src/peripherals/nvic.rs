@@ -1,32 +1,135 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::sync::atomic::Ordering;
-
 use unicorn_engine::{RegisterARM, Unicorn};
 
 use crate::system::System;
 use super::Peripheral;
 
-#[derive(Default)]
 pub struct Nvic {
-    pub systick_period: Option<u32>,
-    pub last_systick_trigger: u64,
-
-    // 128 different interrupts. Good enough for now
+    // 128 different interrupts. Good enough for now.
+    // Bit n corresponds to the exception number n (irq + IRQ_OFFSET).
     pending: u128,
-    in_interrupt: bool,
+    // System exceptions (the first 16 bits) are always enabled.
+    enabled: u128,
+    active: u128,
+    // Indexed by exception number, same as the bits above.
+    priorities: [u8; 128],
+    // Exception numbers of the active exceptions, the last one is the one running.
+    active_stack: Vec<usize>,
+    // From AIRCR. Splits the priorities into group priority and sub-priority.
+    prigroup: u8,
+    // SCB fault registers. They live here as the NVIC is the one delivering faults.
+    pub fault_status: FaultStatus,
+}
+
+#[derive(Default)]
+pub struct FaultStatus {
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    /// MEMFAULTENA, BUSFAULTENA, USGFAULTENA bits of SHCSR
+    pub shcsr_enables: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Hard,
+    MemManage,
+    Bus,
+    Usage,
+}
+
+impl Fault {
+    fn exception_number(self) -> usize {
+        match self {
+            Fault::Hard => 3,
+            Fault::MemManage => 4,
+            Fault::Bus => 5,
+            Fault::Usage => 6,
+        }
+    }
+
+    fn shcsr_enable_bit(self) -> u32 {
+        match self {
+            Fault::Hard => 0,
+            Fault::MemManage => 1 << 16,
+            Fault::Bus => 1 << 17,
+            Fault::Usage => 1 << 18,
+        }
+    }
+}
+
+pub mod cfsr {
+    // MemManage
+    pub const IACCVIOL: u32 = 1 << 0;
+    pub const MMARVALID: u32 = 1 << 7;
+    // BusFault
+    pub const IBUSERR: u32 = 1 << 8;
+    pub const PRECISERR: u32 = 1 << 9;
+    pub const BFARVALID: u32 = 1 << 15;
+    // UsageFault
+    pub const UNDEFINSTR: u32 = 1 << 16;
+    pub const INVSTATE: u32 = 1 << 17;
+    pub const NOCP: u32 = 1 << 19;
+    pub const UNALIGNED: u32 = 1 << 24;
+}
+
+pub mod hfsr {
+    pub const FORCED: u32 = 1 << 30;
+    pub const DEBUGEVT: u32 = 1 << 31;
+}
+
+impl Default for Nvic {
+    fn default() -> Self {
+        Self {
+            pending: 0,
+            enabled: SYSTEM_EXCEPTIONS_MASK,
+            active: 0,
+            priorities: [0; 128],
+            active_stack: vec![],
+            prigroup: 0,
+            fault_status: FaultStatus::default(),
+        }
+    }
 }
 
 const IRQ_OFFSET: i32 = 16;
+const SYSTEM_EXCEPTIONS_MASK: u128 = (1 << IRQ_OFFSET) - 1;
+
+// The STM32F4 implements 4 bits of priority, the low bits read as zero.
+const PRIORITY_MASK: u8 = 0xF0;
+
+// Register offsets from the NVIC base (0xE000E100)
+const ISER: u32 = 0x000;
+const ICER: u32 = 0x080;
+const ISPR: u32 = 0x100;
+const ICPR: u32 = 0x180;
+const IABR: u32 = 0x200;
+const IPR: u32 = 0x300;
+// Each of the bit registers have 8 words, but we only have room for 112 external interrupts.
+const NUM_BIT_REGS: u32 = 4;
+const NUM_IPR_REGS: u32 = (128 - IRQ_OFFSET as u32) / 4;
 
 pub mod irq {
     pub const PENDSV: i32 = -2;
     pub const SYSTICK: i32 = -1;
 }
 
-// This is all poorly implemented. If this is not making much sense, it might be
-// best to re-implement everything correctly. Right now, I'm just trying to get
-// the saturn firmware to work just well enough.
+// CONTROL register bits
+const CONTROL_SPSEL: u64 = 1 << 1;
+const CONTROL_FPCA: u64 = 1 << 2;
+
+// EXC_RETURN bits
+const EXC_RETURN_PSP: u32 = 1 << 2;
+const EXC_RETURN_THREAD: u32 = 1 << 3;
+const EXC_RETURN_BASIC_FRAME: u32 = 1 << 4;
+
+// Stacked xPSR bit indicating that we added 4 bytes to align the frame on 8 bytes.
+const XPSR_FRAME_ALIGN: u32 = 1 << 9;
+
+// Lower than any priority, which is what we have in thread mode with no masking.
+const NO_PRIORITY: i16 = 0x100;
 
 impl Nvic {
     pub fn set_intr_pending(&mut self, irq: i32) {
@@ -36,70 +139,177 @@ impl Nvic {
         self.pending |= 1 << (IRQ_OFFSET + irq);
     }
 
-    pub fn get_and_clear_next_intr_pending(&mut self) -> Option<i32> {
-        if self.pending != 0 {
-            let bit = self.pending.trailing_zeros();
-            self.pending &= !(1 << bit);
-            let irq = (bit as i32) - IRQ_OFFSET;
-            Some(irq)
+    pub fn clear_intr_pending(&mut self, irq: i32) {
+        self.pending &= !(1 << (IRQ_OFFSET + irq));
+    }
+
+    pub fn is_intr_pending(&self, irq: i32) -> bool {
+        self.pending & (1 << (IRQ_OFFSET + irq)) != 0
+    }
+
+    /// The currently running exception number, 0 in thread mode. That's ICSR.VECTACTIVE.
+    pub fn current_exception(&self) -> usize {
+        self.active_stack.last().cloned().unwrap_or(0)
+    }
+
+    pub fn aircr_prigroup(&self) -> u8 {
+        self.prigroup
+    }
+
+    pub fn set_aircr_prigroup(&mut self, prigroup: u8) {
+        self.prigroup = prigroup & 7;
+    }
+
+    /// Reset, NMI and HardFault have fixed negative priorities.
+    fn exception_priority(&self, exc: usize) -> i16 {
+        match exc {
+            1 => -3,
+            2 => -2,
+            3 => -1,
+            _ => self.priorities[exc] as i16,
+        }
+    }
+
+    /// Only the group priority is used for preemption. Sub-priorities only
+    /// order pending exceptions of the same group.
+    fn group_priority(&self, priority: i16) -> i16 {
+        if priority < 0 {
+            priority
         } else {
-            None
+            let mask = (0xFF_u16 << (self.prigroup + 1)) as u8;
+            (priority as u8 & mask) as i16
         }
     }
 
-    pub fn maybe_set_systick_intr_pending(&mut self) {
-        if let Some(systick_period) = self.systick_period {
-            let n = crate::emulator::NUM_INSTRUCTIONS.load(Ordering::Relaxed);
-            let delta_num_instructions = n - self.last_systick_trigger;
-            if delta_num_instructions > (systick_period as u64) {
-                self.last_systick_trigger = n;
-                self.set_intr_pending(irq::SYSTICK);
-            }
+    fn execution_priority(&self, uc: &Unicorn<()>) -> i16 {
+        let mut priority = self.active_stack.iter()
+            .map(|exc| self.group_priority(self.exception_priority(*exc)))
+            .min()
+            .unwrap_or(NO_PRIORITY);
+
+        let basepri = (uc.reg_read(RegisterARM::BASEPRI).unwrap() as u8 & PRIORITY_MASK) as i16;
+        if basepri != 0 {
+            priority = priority.min(self.group_priority(basepri));
+        }
+        if uc.reg_read(RegisterARM::PRIMASK).unwrap() & 1 != 0 {
+            priority = priority.min(0);
         }
+        if uc.reg_read(RegisterARM::FAULTMASK).unwrap() & 1 != 0 {
+            priority = priority.min(-1);
+        }
+        priority
     }
 
-   fn are_interrupts_disabled(sys: &System) -> bool {
-        let primask = sys.uc.borrow().reg_read(RegisterARM::PRIMASK).unwrap();
-        primask != 0
+    /// Returns the highest priority pending exception, regardless of the execution priority.
+    /// This is ICSR.VECTPENDING.
+    pub fn next_pending_exception(&self) -> Option<usize> {
+        // Interrupts that are not enabled stay pending until the firmware enables them.
+        let pending = self.pending & self.enabled;
+        (0..128)
+            .filter(|exc| pending & (1 << exc) != 0)
+            .min_by_key(|exc| (self.exception_priority(*exc), *exc))
     }
 
-    pub fn run_pending_interrupts(&mut self, sys: &System, vector_table_addr: u32) {
-        self.maybe_set_systick_intr_pending();
+    /// Returns the pending exception that can preempt what's running, if any.
+    fn next_preempting_exception(&self, uc: &Unicorn<()>) -> Option<usize> {
+        self.next_pending_exception().filter(|exc|
+            self.group_priority(self.exception_priority(*exc)) < self.execution_priority(uc)
+        )
+    }
 
-        if Self::are_interrupts_disabled(sys) || self.in_interrupt {
-            return;
+    pub fn run_pending_interrupts(&mut self, sys: &System, vector_table_addr: u32) {
+        let next = self.next_preempting_exception(&sys.uc.borrow());
+        if let Some(exc) = next {
+            self.run_interrupt(sys, vector_table_addr, exc);
         }
+    }
 
-        if let Some(irq) = self.get_and_clear_next_intr_pending() {
-            self.run_interrupt(sys, vector_table_addr, irq);
+    /// Delivers a fault right away. The stacked PC is the current PC, which
+    /// should be the faulting instruction. Faults that are disabled, or that
+    /// can't preempt the current execution priority escalate to HardFault.
+    /// Returns false if the processor locks up (fault within HardFault, or with FAULTMASK set).
+    pub fn raise_fault(&mut self, sys: &System, vector_table_addr: u32, fault: Fault,
+                       status: u32, fault_addr: Option<u32>) -> bool {
+        let fs = &mut self.fault_status;
+        match fault {
+            Fault::Hard => fs.hfsr |= status,
+            _ => fs.cfsr |= status,
+        }
+        match (fault, fault_addr) {
+            (Fault::MemManage, Some(addr)) => { fs.mmfar = addr; fs.cfsr |= cfsr::MMARVALID; }
+            (Fault::Bus, Some(addr)) => { fs.bfar = addr; fs.cfsr |= cfsr::BFARVALID; }
+            _ => {}
         }
+
+        let execution_priority = self.execution_priority(&sys.uc.borrow());
+        let can_preempt = |exc| self.group_priority(self.exception_priority(exc)) < execution_priority;
+
+        let exc = fault.exception_number();
+        let enabled = fault == Fault::Hard || self.fault_status.shcsr_enables & fault.shcsr_enable_bit() != 0;
+
+        let exc = if enabled && can_preempt(exc) {
+            exc
+        } else if can_preempt(Fault::Hard.exception_number()) {
+            if fault != Fault::Hard {
+                debug!("Escalating {:?} to HardFault", fault);
+                self.fault_status.hfsr |= hfsr::FORCED;
+            }
+            Fault::Hard.exception_number()
+        } else {
+            error!("Lockup: {:?} while the execution priority is {}", fault, execution_priority);
+            return false;
+        };
+
+        warn!("Fault {:?} exc={} cfsr=0x{:08x} hfsr=0x{:08x}{}", fault, exc,
+            self.fault_status.cfsr, self.fault_status.hfsr,
+            fault_addr.map(|a| format!(" addr=0x{:08x}", a)).unwrap_or_default());
+
+        self.run_interrupt(sys, vector_table_addr, exc);
+        true
+    }
+
+    pub fn is_exception_active(&self, exc: usize) -> bool {
+        self.active & (1 << exc) != 0
+    }
+
+    pub fn is_exception_pending(&self, exc: usize) -> bool {
+        self.pending & (1 << exc) != 0
     }
 
-    fn read_vector_addr(sys: &System, vector_table_addr: u32, irq: i32) -> u32 {
+    fn read_vector_addr(uc: &Unicorn<()>, vector_table_addr: u32, exc: usize) -> u32 {
         // 4 because of ptr size
-        let vaddr = vector_table_addr + 4*(IRQ_OFFSET + irq) as u32;
+        let vaddr = vector_table_addr + 4*exc as u32;
 
         let mut vector = [0,0,0,0];
-        sys.uc.borrow().mem_read(vaddr as u64, &mut vector).unwrap();
+        uc.mem_read(vaddr as u64, &mut vector).unwrap();
         u32::from_le_bytes(vector)
     }
 
-    // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP.
-    // FPCA, bit[2], if the processor includes the FP extension.
+    /// Jumps to the exception handler, assuming the context is already stacked.
+    fn enter_handler(&mut self, uc: &mut Unicorn<()>, vector_table_addr: u32, exc: usize) {
+        let vector = Self::read_vector_addr(uc, vector_table_addr, exc);
+
+        self.pending &= !(1 << exc);
+        self.active |= 1 << exc;
+        self.active_stack.push(exc);
 
-    fn run_interrupt(&mut self, sys: &System, vector_table_addr: u32, irq: i32) {
-        let vector = Self::read_vector_addr(sys, vector_table_addr, irq);
+        // Writing IPSR puts unicorn in handler mode
+        uc.reg_write(RegisterARM::IPSR, exc as u64).unwrap();
+        uc.reg_write(RegisterARM::PC, vector as u64).unwrap();
+    }
 
+    fn run_interrupt(&mut self, sys: &System, vector_table_addr: u32, exc: usize) {
         let mut uc = sys.uc.borrow_mut();
 
-        // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP.
+        // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP. Handlers always use MSP.
         // FPCA, bit[2], if the processor includes the FP extension.
+        let handler_mode = !self.active_stack.is_empty();
         let control_reg = uc.reg_read(RegisterARM::CONTROL).unwrap();
-        let spsel = control_reg & (1 << 1) != 0;
-        let fpca = control_reg & (2 << 1) != 0;
+        let spsel = !handler_mode && control_reg & CONTROL_SPSEL != 0;
+        let fpca = control_reg & CONTROL_FPCA != 0;
 
-        trace!("Running interrupt irq={} spsel={} fpca={} vector={:#08x}",
-            irq, spsel, fpca, vector);
+        trace!("Running interrupt exc={} irq={} preempting={} spsel={} fpca={}",
+            exc, exc as i32 - IRQ_OFFSET, self.current_exception(), spsel, fpca);
 
         Self::push_regs(&mut uc, spsel, fpca);
 
@@ -111,69 +321,95 @@ impl Nvic {
         //   0xFFFF_FFF1   Handler mode   Main         Basic
         //   0xFFFF_FFF9   Thread mode    Main         Basic
         //   0xFFFF_FFFD   Thread mode    Process      Basic
-
-        // Right now, we don't supposed nested interrupts.
-        let mut lr: u32 = 0xFFFF_FFE9;
-        if spsel { lr |= 0b0000_0100; }
-        if !fpca { lr |= 0b0001_0000; } // Yes, no fpca means the bit is set
+        let mut lr: u32 = 0xFFFF_FFE1;
+        if !handler_mode { lr |= EXC_RETURN_THREAD; }
+        if spsel { lr |= EXC_RETURN_PSP; }
+        if !fpca { lr |= EXC_RETURN_BASIC_FRAME; } // Yes, no fpca means the bit is set
         uc.reg_write(RegisterARM::LR, lr.into()).unwrap();
 
-        uc.reg_write(RegisterARM::IPSR, irq as u64).unwrap();
-        uc.reg_write(RegisterARM::PC, vector as u64).unwrap();
+        // Switch to the main stack. SPSEL can only be changed in thread mode,
+        // so this must be done before we write IPSR.
+        uc.reg_write(RegisterARM::CONTROL, control_reg & !(CONTROL_SPSEL | CONTROL_FPCA)).unwrap();
 
-        self.in_interrupt = true;
+        self.enter_handler(&mut uc, vector_table_addr, exc);
     }
 
-    pub fn return_from_interrupt(&mut self, sys: &System) {
+    /// Called on EXC_RETURN. Either tail-chains into the next pending exception,
+    /// or restores the context that was interrupted.
+    pub fn return_from_interrupt(&mut self, sys: &System, vector_table_addr: u32) {
         let mut uc = sys.uc.borrow_mut();
 
-        let lr = uc.reg_read(RegisterARM::LR).unwrap();
-        if lr & 0xFFFF_FF00 == 0xFFFF_FF00 {
-            let spsel = lr & 0b0000_0100 != 0;
-            let fpca = lr & 0b0001_0000 == 0; // 0 means yes here
-
-            Self::pop_regs(&mut uc, spsel, fpca);
+        // The EXC_RETURN value was loaded in PC, either with bx lr, or with a pop/ldr.
+        // The low bit is lost, but we don't need it.
+        let pc = uc.reg_read(RegisterARM::PC).unwrap() as u32;
+        let exc_return = if pc & 0xFFFF_FF00 == 0xFFFF_FF00 {
+            pc
+        } else {
+            uc.reg_read(RegisterARM::LR).unwrap() as u32
+        };
 
-            trace!("Return from interrupt spsel={} fpca={} pc=0x{:08x}",
-                spsel, fpca, uc.reg_read(RegisterARM::PC).unwrap());
+        match self.active_stack.pop() {
+            Some(exc) => self.active &= !(1 << exc),
+            None => warn!("Return from interrupt with no active exception exc_return=0x{:08x}", exc_return),
+        }
 
-            // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP.
-            // FPCA, bit[2], if the processor includes the FP extension.
-            let mut control_reg = 0;
-            if spsel { control_reg |= 1 << 1; }
-            if fpca { control_reg |= 2 << 1; }
-            uc.reg_write(RegisterARM::CONTROL, control_reg).unwrap();
-        } else {
+        // Tail-chaining: if a pending exception would preempt the context we
+        // are returning to, we go straight to its handler. The stacked context
+        // and EXC_RETURN stay the same.
+        if let Some(exc) = self.next_preempting_exception(&uc) {
+            trace!("Tail-chaining exc={} irq={}", exc, exc as i32 - IRQ_OFFSET);
+            uc.reg_write(RegisterARM::LR, (exc_return | 1).into()).unwrap();
             let control_reg = uc.reg_read(RegisterARM::CONTROL).unwrap();
-            let spsel = control_reg & (1 << 1) != 0;
-            let fpca = control_reg & (2 << 1) != 0;
-            Self::pop_regs(&mut uc, spsel, fpca);
+            uc.reg_write(RegisterARM::CONTROL, control_reg & !CONTROL_FPCA).unwrap();
+            self.enter_handler(&mut uc, vector_table_addr, exc);
+            return;
+        }
+
+        let thread_mode = exc_return & EXC_RETURN_THREAD != 0;
+        let spsel = thread_mode && exc_return & EXC_RETURN_PSP != 0;
+        let fpca = exc_return & EXC_RETURN_BASIC_FRAME == 0; // 0 means yes here
 
-            trace!("Return from interrupt spsel={} fpca={} pc=0x{:08x} -- LR was not right",
-                spsel, fpca, uc.reg_read(RegisterARM::PC).unwrap());
+        if thread_mode != self.active_stack.is_empty() {
+            warn!("Inconsistent exception return exc_return=0x{:08x} active={:?}",
+                exc_return, self.active_stack);
         }
 
-        self.in_interrupt = false;
-    }
-
-    const CONTEXT_REGS_EXTENDED: [RegisterARM; 17] = [
-        RegisterARM::FPSCR,
-        RegisterARM::S15,
-        RegisterARM::S14,
-        RegisterARM::S13,
-        RegisterARM::S12,
-        RegisterARM::S11,
-        RegisterARM::S10,
-        RegisterARM::S9,
-        RegisterARM::S8,
-        RegisterARM::S7,
-        RegisterARM::S6,
-        RegisterARM::S5,
-        RegisterARM::S4,
-        RegisterARM::S3,
-        RegisterARM::S2,
-        RegisterARM::S1,
-        RegisterARM::S0,
+        // This restores xPSR as well, and IPSR with it. So we are back in
+        // thread mode if that's where we were.
+        Self::pop_regs(&mut uc, spsel, fpca);
+
+        // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP.
+        // FPCA, bit[2], if the processor includes the FP extension.
+        let mut control_reg = uc.reg_read(RegisterARM::CONTROL).unwrap() & !(CONTROL_SPSEL | CONTROL_FPCA);
+        if spsel { control_reg |= CONTROL_SPSEL; }
+        if fpca { control_reg |= CONTROL_FPCA; }
+        uc.reg_write(RegisterARM::CONTROL, control_reg).unwrap();
+
+        trace!("Return from interrupt spsel={} fpca={} pc=0x{:08x} current_exc={}",
+            spsel, fpca, uc.reg_read(RegisterARM::PC).unwrap(), self.current_exception());
+    }
+
+    // The frame is pushed in this order, from high addresses to low addresses.
+    // The extended frame has a reserved word after FPSCR (None here).
+    const CONTEXT_REGS_EXTENDED: [Option<RegisterARM>; 18] = [
+        None,
+        Some(RegisterARM::FPSCR),
+        Some(RegisterARM::S15),
+        Some(RegisterARM::S14),
+        Some(RegisterARM::S13),
+        Some(RegisterARM::S12),
+        Some(RegisterARM::S11),
+        Some(RegisterARM::S10),
+        Some(RegisterARM::S9),
+        Some(RegisterARM::S8),
+        Some(RegisterARM::S7),
+        Some(RegisterARM::S6),
+        Some(RegisterARM::S5),
+        Some(RegisterARM::S4),
+        Some(RegisterARM::S3),
+        Some(RegisterARM::S2),
+        Some(RegisterARM::S1),
+        Some(RegisterARM::S0),
     ];
 
     const CONTEXT_REGS: [RegisterARM; 8] = [
@@ -189,124 +425,170 @@ impl Nvic {
 
     fn push_regs(uc: &mut Unicorn<()>, spsel: bool, fpca: bool) {
         let sp_reg = if spsel { RegisterARM::PSP } else { RegisterARM::MSP };
-        let mut sp = uc.reg_read(sp_reg).unwrap();
+        let sp = uc.reg_read(sp_reg).unwrap() as u32;
 
-        let mut push_reg = |reg| {
-            let v = uc.reg_read(reg).unwrap() as u32;
-            //trace!("push sp=0x{:08x} {:5?}=0x{:08x}", sp, reg, v);
-            sp -= 4;
-            uc.mem_write(sp, &v.to_le_bytes()).expect("Invalid SP pointer during interrupt");
-        };
+        let frame_size = 4 * (Self::CONTEXT_REGS.len() + if fpca { Self::CONTEXT_REGS_EXTENDED.len() } else { 0 }) as u32;
+
+        // The frame must be 8 bytes aligned
+        let aligned = (sp - frame_size) & 4 == 0;
+        let sp = if aligned { sp } else { sp - 4 } - frame_size;
 
+        let mut values = vec![];
         if fpca {
             for reg in Self::CONTEXT_REGS_EXTENDED {
-                push_reg(reg);
+                values.push(reg.map_or(0, |reg| uc.reg_read(reg).unwrap() as u32));
             }
         }
         for reg in Self::CONTEXT_REGS {
-            push_reg(reg);
+            let mut v = uc.reg_read(reg).unwrap() as u32;
+            if reg == RegisterARM::XPSR && !aligned {
+                v |= XPSR_FRAME_ALIGN;
+            }
+            //trace!("push {:5?}=0x{:08x}", reg, v);
+            values.push(v);
         }
-        uc.reg_write(RegisterARM::SP, sp).unwrap();
+
+        // Values were collected in push order, so the last one is at the lowest address.
+        let frame = values.iter().rev().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        uc.mem_write(sp.into(), &frame).expect("Invalid SP pointer during interrupt");
+        uc.reg_write(sp_reg, sp.into()).unwrap();
     }
 
     fn pop_regs(uc: &mut Unicorn<()>, spsel: bool, fpca: bool) {
         let sp_reg = if spsel { RegisterARM::PSP } else { RegisterARM::MSP };
-        let mut sp = uc.reg_read(sp_reg).unwrap();
+        let mut sp = uc.reg_read(sp_reg).unwrap() as u32;
 
-        let mut pop_reg = |reg| {
+        let mut pop = || {
             let mut v = [0,0,0,0];
-            uc.mem_read(sp, &mut v).expect("Invalid SP pointer during interrupt return");
-            let v = u32::from_le_bytes(v);
-            //trace!("pop sp=0x{:08x} {:5?}=0x{:08x}", sp, reg, v);
+            uc.mem_read(sp.into(), &mut v).expect("Invalid SP pointer during interrupt return");
             sp += 4;
-            uc.reg_write(reg, v as u64).unwrap();
+            u32::from_le_bytes(v)
+        };
+
+        let regs = Self::CONTEXT_REGS.iter().rev()
+            .map(|reg| (*reg, pop()))
+            .collect::<Vec<_>>();
+
+        let fp_regs = if fpca {
+            Self::CONTEXT_REGS_EXTENDED.iter().rev()
+                .map(|reg| (*reg, pop()))
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
         };
 
-        for reg in Self::CONTEXT_REGS.iter().rev() {
-            pop_reg(*reg);
+        let xpsr = regs.last().unwrap().1;
+        if xpsr & XPSR_FRAME_ALIGN != 0 {
+            sp += 4;
         }
-        if fpca {
-            for reg in Self::CONTEXT_REGS_EXTENDED.iter().rev() {
-                pop_reg(*reg);
+
+        // The stack pointer is written before xPSR, as restoring IPSR can
+        // switch unicorn back to thread mode, which changes how MSP/PSP are banked.
+        uc.reg_write(sp_reg, sp.into()).unwrap();
+
+        for (reg, v) in fp_regs {
+            if let Some(reg) = reg {
+                uc.reg_write(reg, v as u64).unwrap();
             }
         }
-        uc.reg_write(RegisterARM::SP, sp).unwrap();
+        for (reg, v) in regs {
+            //trace!("pop {:5?}=0x{:08x}", reg, v);
+            let v = if reg == RegisterARM::XPSR { v & !XPSR_FRAME_ALIGN } else { v };
+            uc.reg_write(reg, v as u64).unwrap();
+        }
+    }
+}
+
+impl Nvic {
+    /// Returns the 32 bits of an interrupt bit register (ISER, ICPR, ...) at the given word index.
+    fn reg_bits(bits: u128, index: u32) -> u32 {
+        (bits >> (IRQ_OFFSET as u32 + 32*index)) as u32
+    }
+
+    fn reg_mask(value: u32, index: u32) -> u128 {
+        (value as u128) << (IRQ_OFFSET as u32 + 32*index)
+    }
+
+    pub fn set_priority(&mut self, irq: i32, priority: u8) {
+        self.priorities[(IRQ_OFFSET + irq) as usize] = priority & PRIORITY_MASK;
+    }
+
+    pub fn priority(&self, irq: i32) -> u8 {
+        self.priorities[(IRQ_OFFSET + irq) as usize]
     }
 }
 
 impl Peripheral for Nvic {
-    fn read(&mut self, _sys: &System, _offset: u32) -> u32 {
-        0
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        let index = (offset % 0x80) / 4;
+        match offset {
+            ISER..=0x07C | ICER..=0x0FC if index < NUM_BIT_REGS => Self::reg_bits(self.enabled, index),
+            ISPR..=0x17C | ICPR..=0x1FC if index < NUM_BIT_REGS => Self::reg_bits(self.pending, index),
+            IABR..=0x27C if index < NUM_BIT_REGS => Self::reg_bits(self.active, index),
+            IPR..=0x3EC if (offset - IPR)/4 < NUM_IPR_REGS => {
+                let irq = (offset - IPR) as i32;
+                u32::from_le_bytes([0,1,2,3].map(|i| self.priority(irq + i)))
+            }
+            _ => 0,
+        }
     }
 
-    fn write(&mut self, _sys: &System, _offset: u32, _value: u32) {
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        let index = (offset % 0x80) / 4;
+        match offset {
+            ISER..=0x07C if index < NUM_BIT_REGS => self.enabled |= Self::reg_mask(value, index),
+            ICER..=0x0FC if index < NUM_BIT_REGS => self.enabled &= !Self::reg_mask(value, index),
+            ISPR..=0x17C if index < NUM_BIT_REGS => self.pending |= Self::reg_mask(value, index),
+            ICPR..=0x1FC if index < NUM_BIT_REGS => self.pending &= !Self::reg_mask(value, index),
+            IPR..=0x3EC if (offset - IPR)/4 < NUM_IPR_REGS => {
+                let irq = (offset - IPR) as i32;
+                for (i, priority) in value.to_le_bytes().into_iter().enumerate() {
+                    self.set_priority(irq + i as i32, priority);
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 /// The next part is glue. Maybe we could have a better architecture.
 
-pub struct NvicWrapper;
+pub struct NvicWrapper {
+    // NVIC_STIR is a separate peripheral in the SVD
+    is_stir: bool,
+}
 
 impl NvicWrapper {
     pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
-        if name == "NVIC" {
-            Some(Box::new(Self))
-        } else {
-            None
+        match name {
+            "NVIC" => Some(Box::new(Self { is_stir: false })),
+            "NVIC_STIR" => Some(Box::new(Self { is_stir: true })),
+            _ => None,
         }
     }
 }
 
 impl Peripheral for NvicWrapper {
     fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        if self.is_stir {
+            return 0;
+        }
         sys.p.nvic.borrow_mut().read(sys, offset)
     }
 
+    fn has_byte_fields(&self, offset: u32) -> bool {
+        !self.is_stir && (IPR..=0x3EC).contains(&offset)
+    }
+
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        if self.is_stir {
+            // Software Trigger Interrupt Register
+            let irq = (value & 0x1ff) as i32;
+            if offset == 0 && irq < 128 - IRQ_OFFSET {
+                sys.p.nvic.borrow_mut().set_intr_pending(irq);
+            }
+            return;
+        }
         sys.p.nvic.borrow_mut().write(sys, offset, value)
     }
 }
-
-
-/*
-0xE000E100 B  REGISTER ISER0 (rw): Interrupt Set-Enable Register
-0xE000E104 B  REGISTER ISER1 (rw): Interrupt Set-Enable Register
-0xE000E108 B  REGISTER ISER2 (rw): Interrupt Set-Enable Register
-
-0xE000E180 B  REGISTER ICER0 (rw): Interrupt Clear-Enable Register
-0xE000E184 B  REGISTER ICER1 (rw): Interrupt Clear-Enable Register
-0xE000E188 B  REGISTER ICER2 (rw): Interrupt Clear-Enable Register
-
-0xE000E200 B  REGISTER ISPR0 (rw): Interrupt Set-Pending Register
-0xE000E204 B  REGISTER ISPR1 (rw): Interrupt Set-Pending Register
-0xE000E208 B  REGISTER ISPR2 (rw): Interrupt Set-Pending Register
-
-0xE000E280 B  REGISTER ICPR0 (rw): Interrupt Clear-Pending Register
-0xE000E284 B  REGISTER ICPR1 (rw): Interrupt Clear-Pending Register
-0xE000E288 B  REGISTER ICPR2 (rw): Interrupt Clear-Pending Register
-
-0xE000E300 B  REGISTER IABR0 (ro): Interrupt Active Bit Register
-0xE000E304 B  REGISTER IABR1 (ro): Interrupt Active Bit Register
-0xE000E308 B  REGISTER IABR2 (ro): Interrupt Active Bit Register
-
-0xE000E400 B  REGISTER IPR0 (rw): Interrupt Priority Register
-0xE000E404 B  REGISTER IPR1 (rw): Interrupt Priority Register
-0xE000E408 B  REGISTER IPR2 (rw): Interrupt Priority Register
-0xE000E40C B  REGISTER IPR3 (rw): Interrupt Priority Register
-0xE000E410 B  REGISTER IPR4 (rw): Interrupt Priority Register
-0xE000E414 B  REGISTER IPR5 (rw): Interrupt Priority Register
-0xE000E418 B  REGISTER IPR6 (rw): Interrupt Priority Register
-0xE000E41C B  REGISTER IPR7 (rw): Interrupt Priority Register
-0xE000E420 B  REGISTER IPR8 (rw): Interrupt Priority Register
-0xE000E424 B  REGISTER IPR9 (rw): Interrupt Priority Register
-0xE000E428 B  REGISTER IPR10 (rw): Interrupt Priority Register
-0xE000E42C B  REGISTER IPR11 (rw): Interrupt Priority Register
-0xE000E430 B  REGISTER IPR12 (rw): Interrupt Priority Register
-0xE000E434 B  REGISTER IPR13 (rw): Interrupt Priority Register
-0xE000E438 B  REGISTER IPR14 (rw): Interrupt Priority Register
-0xE000E43C B  REGISTER IPR15 (rw): Interrupt Priority Register
-0xE000E440 B  REGISTER IPR16 (rw): Interrupt Priority Register
-0xE000E444 B  REGISTER IPR17 (rw): Interrupt Priority Register
-0xE000E448 B  REGISTER IPR18 (rw): Interrupt Priority Register
-0xE000E44C B  REGISTER IPR19 (rw): Interrupt Priority Register
-*/
@@ -1,39 +1,135 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
 use crate::system::System;
-use super::Peripheral;
+use super::{Peripheral, clock::HSI_FREQUENCY};
+
+#[derive(Debug, Deserialize, Default)]
+pub struct RccConfig {
+    /// Frequency of the external oscillator in Hz. Defaults to 8MHz.
+    pub hse_frequency: Option<u32>,
+}
 
 pub struct Rcc {
+    hse_frequency: u32,
+    cr: u32,
+    pllcfgr: u32,
+    cfgr: u32,
+    // All the other registers (clock enables, resets, ...) just hold their values.
+    others: HashMap<u32, u32>,
 }
 
+const DEFAULT_HSE_FREQUENCY: u32 = 8_000_000;
+
+// CR register. Ready bits are next to their enable bits.
+const CR_HSION: u32 = 1 << 0;
+const CR_HSEON: u32 = 1 << 16;
+const CR_PLLON: u32 = 1 << 24;
+const CR_PLLI2SON: u32 = 1 << 26;
+const CR_READY_BITS_MASK: u32 = (CR_HSION | CR_HSEON | CR_PLLON | CR_PLLI2SON) << 1;
+
 impl Rcc {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+    pub fn new(name: &str, config: &RccConfig) -> Option<Box<dyn Peripheral>> {
         if name == "RCC" {
-            Some(Box::new(Rcc {}))
+            Some(Box::new(Rcc {
+                hse_frequency: config.hse_frequency.unwrap_or(DEFAULT_HSE_FREQUENCY),
+                // Reset values
+                cr: 0x0000_0083,
+                pllcfgr: 0x2400_3010,
+                cfgr: 0,
+                others: HashMap::new(),
+            }))
         } else {
             None
         }
     }
-}
 
+    fn pll_frequency(&self) -> u32 {
+        let pllm = self.pllcfgr & 0x3F;
+        let plln = (self.pllcfgr >> 6) & 0x1FF;
+        let pllp = 2 * (((self.pllcfgr >> 16) & 0b11) + 1);
+        let src = if self.pllcfgr & (1 << 22) != 0 { self.hse_frequency } else { HSI_FREQUENCY };
+        if pllm == 0 {
+            return src;
+        }
+        ((src as u64 / pllm as u64) * plln as u64 / pllp as u64) as u32
+    }
+
+    fn sysclk(&self) -> u32 {
+        match self.cfgr & 0b11 {
+            0b01 => self.hse_frequency,
+            0b10 => self.pll_frequency(),
+            _ => HSI_FREQUENCY,
+        }
+    }
+
+    fn ahb_prescaler(&self) -> u32 {
+        match (self.cfgr >> 4) & 0xF {
+            v @ 0b1000..=0b1011 => 1 << (v - 0b0111),
+            v @ 0b1100..=0b1111 => 1 << (v - 0b0110),
+            _ => 1,
+        }
+    }
+
+    fn apb_prescaler(bits: u32) -> u32 {
+        match bits & 0b111 {
+            v @ 0b100..=0b111 => 1 << (v - 0b011),
+            _ => 1,
+        }
+    }
+
+    fn update_clock(&self, sys: &System) {
+        sys.p.clock.borrow_mut().set_frequencies(
+            self.sysclk(),
+            self.ahb_prescaler(),
+            Self::apb_prescaler(self.cfgr >> 10),
+            Self::apb_prescaler(self.cfgr >> 13),
+        );
+    }
+}
 
 impl Peripheral for Rcc {
     fn read(&mut self, _sys: &System, offset: u32) -> u32 {
         match offset {
             0x0000 => {
                 // CR register
-                // Return all the r to true. This is where the PLL ready flags are.
-                //0b0010_0000_0010_0000_0000_0000_0010
-                0xFFFF_FFFF
+                // Oscillators and PLLs are ready as soon as they are enabled.
+                (self.cr & !CR_READY_BITS_MASK) | ((self.cr << 1) & CR_READY_BITS_MASK)
             }
+            0x0004 => self.pllcfgr,
             0x0008 => {
                 // CFGR register
-                0b1000
+                // SWS reflects SW right away
+                (self.cfgr & !0b1100) | ((self.cfgr & 0b11) << 2)
+            }
+            0x0070 | 0x0074 => {
+                // BDCR and CSR registers
+                // LSE and LSI are ready as soon as they are enabled. Needed for the RTC.
+                let v = self.others.get(&offset).cloned().unwrap_or(0);
+                (v & !0b10) | ((v & 1) << 1)
             }
-            _ => 0
+            _ => self.others.get(&offset).cloned().unwrap_or(0)
         }
     }
 
-    fn write(&mut self, _sys: &System, _offset: u32, _value: u32) {
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => {
+                self.cr = value & !CR_READY_BITS_MASK;
+                self.update_clock(sys);
+            }
+            0x0004 => {
+                self.pllcfgr = value;
+                self.update_clock(sys);
+            }
+            0x0008 => {
+                self.cfgr = value & !0b1100;
+                self.update_clock(sys);
+            }
+            _ => { self.others.insert(offset, value); }
+        }
     }
 }
@@ -0,0 +1,729 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// The calendar is derived from a time source: the virtual clock by default, so
+// runs are reproducible, or the host's wall clock. It counts ck_apre ticks
+// (LSE / (PREDIV_A+1)), so the prescalers and the sub-seconds behave as on the
+// hardware. Alarms and the wakeup timer interrupt through EXTI lines 17 and 22.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+use super::{Peripheral, exti::line};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RtcStart {
+    /// The host's local time. The calendar keeps following the wall clock.
+    Host,
+    /// The reset value of the calendar (2000-01-01), following the virtual clock
+    Virtual,
+    /// e.g., "2023-06-01 12:30:00", following the virtual clock
+    Date(String),
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct RtcConfig {
+    /// Defaults to `virtual`
+    pub start: Option<RtcStart>,
+}
+
+impl RtcConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(RtcStart::Date(date)) = &self.start {
+            parse_date(date).with_context(|| format!("Invalid RTC start date '{}'", date))?;
+        }
+        Ok(())
+    }
+}
+
+const LSE_FREQUENCY: u64 = 32768;
+const SECS_PER_DAY: i64 = 86400;
+// Days between 1970-01-01 and 2000-01-01
+const EPOCH_2000_DAYS: i64 = 10957;
+
+// CR bits
+const CR_WUCKSEL_MASK: u32 = 0b111;
+const CR_BYPSHAD: u32 = 1 << 5;
+const CR_FMT: u32 = 1 << 6;
+const CR_ALRAE: u32 = 1 << 8;
+const CR_WUTE: u32 = 1 << 10;
+const CR_ALRAIE: u32 = 1 << 12;
+const CR_WUTIE: u32 = 1 << 14;
+const CR_ADD1H: u32 = 1 << 16;
+const CR_SUB1H: u32 = 1 << 17;
+
+// ISR bits. Alarm B bits are next to the alarm A ones.
+const ISR_ALRAWF: u32 = 1 << 0;
+const ISR_WUTWF: u32 = 1 << 2;
+const ISR_INITS: u32 = 1 << 4;
+const ISR_RSF: u32 = 1 << 5;
+const ISR_INITF: u32 = 1 << 6;
+const ISR_INIT: u32 = 1 << 7;
+const ISR_ALRAF: u32 = 1 << 8;
+const ISR_WUTF: u32 = 1 << 10;
+// Flags cleared by writing 0: alarms, wakeup, timestamp, tamper
+const ISR_FLAGS_MASK: u32 = 0x7F << 8;
+
+const TR_PM: u32 = 1 << 22;
+
+const ALRM_MSK1: u32 = 1 << 7;
+const ALRM_MSK2: u32 = 1 << 15;
+const ALRM_MSK3: u32 = 1 << 23;
+const ALRM_WDSEL: u32 = 1 << 30;
+const ALRM_MSK4: u32 = 1 << 31;
+
+// Reset values
+const DR_RESET: u32 = 0x0000_2101;
+const PRER_RESET: u32 = 0x007F_00FF;
+
+// The shadow registers are resynchronized every 2 RTCCLK periods
+const RSF_DELAY_NS: u64 = 2 * 1_000_000_000 / LSE_FREQUENCY;
+
+const EVENT_TICK: u32 = 0;
+
+pub struct Rtc {
+    base: u32,
+    host_time: bool,
+
+    cr: u32,
+    // Only the INIT bit and the flags. The other bits are computed.
+    isr: u32,
+    prer: u32,
+    wutr: u32,
+    calibr: u32,
+    alrmr: [u32; 2],
+    alrmssr: [u32; 2],
+    calr: u32,
+    tafcr: u32,
+    bkp: [u32; 20],
+    // Progress in the write protection key sequence. 2 means unlocked.
+    wpr_step: u8,
+
+    // The calendar was `anchor_ticks` ck_apre ticks since 2000-01-01 when the
+    // time source was at `anchor_ns`, and the weekday was `anchor_wdu`.
+    anchor_ns: u64,
+    anchor_ticks: i64,
+    anchor_wdu: u32,
+    // In init mode, the calendar is stopped and TR/DR are set by the firmware
+    init_tr: u32,
+    init_dr: u32,
+    // Reading TR or SSR locks the calendar until DR is read
+    locked_ticks: Option<i64>,
+    // Virtual time at which RSF was cleared
+    rsf_cleared_at: Option<u64>,
+
+    // Last second checked for alarms
+    alarm_checked_secs: i64,
+    // Wakeup timer start, and number of periods already elapsed
+    wut_start_ns: u64,
+    wut_periods: u64,
+}
+
+impl Rtc {
+    pub fn new(name: &str, base: u32, config: &RtcConfig) -> Option<Box<dyn Peripheral>> {
+        if name != "RTC" {
+            return None;
+        }
+
+        let sync_ticks = (PRER_RESET & 0x7FFF) as i64 + 1;
+        let (host_time, anchor_ns, secs, wdu) = match config.start.as_ref().unwrap_or(&RtcStart::Virtual) {
+            RtcStart::Host => {
+                let unix_secs = host_time_ns() / 1_000_000_000;
+                let secs = unix_secs as i64 + host_utc_offset() - EPOCH_2000_DAYS * SECS_PER_DAY;
+                (true, unix_secs * 1_000_000_000, secs, weekday(secs))
+            }
+            RtcStart::Virtual => (false, 0, 0, (DR_RESET >> 13) & 0b111),
+            RtcStart::Date(date) => {
+                let secs = parse_date(date).expect("RTC date is validated with the config");
+                (false, 0, secs, weekday(secs))
+            }
+        };
+        info!("RTC starts at {}", format_date(secs));
+
+        Some(Box::new(Self {
+            base,
+            host_time,
+            cr: 0,
+            isr: 0,
+            prer: PRER_RESET,
+            wutr: 0xFFFF,
+            calibr: 0,
+            alrmr: [0; 2],
+            alrmssr: [0; 2],
+            calr: 0,
+            tafcr: 0,
+            bkp: [0; 20],
+            wpr_step: 0,
+            anchor_ns,
+            anchor_ticks: secs * sync_ticks,
+            anchor_wdu: wdu,
+            init_tr: 0,
+            init_dr: DR_RESET,
+            locked_ticks: None,
+            rsf_cleared_at: Some(0),
+            alarm_checked_secs: secs,
+            wut_start_ns: 0,
+            wut_periods: 0,
+        }))
+    }
+
+    fn source_ns(&self, sys: &System) -> u64 {
+        if self.host_time {
+            host_time_ns()
+        } else {
+            sys.p.clock.borrow().now_ns()
+        }
+    }
+
+    fn in_init(&self) -> bool {
+        self.isr & ISR_INIT != 0
+    }
+
+    fn is_locked(&self) -> bool {
+        self.wpr_step != 2
+    }
+
+    fn async_ticks(&self) -> u64 {
+        ((self.prer >> 16) & 0x7F) as u64 + 1
+    }
+
+    /// Number of ck_apre ticks per second
+    fn sync_ticks(&self) -> i64 {
+        (self.prer & 0x7FFF) as i64 + 1
+    }
+
+    /// Duration of a ck_apre tick in ns
+    fn tick_ns(&self) -> f64 {
+        self.async_ticks() as f64 * 1e9 / LSE_FREQUENCY as f64
+    }
+
+    fn ticks(&self, sys: &System) -> i64 {
+        if self.in_init() {
+            return self.anchor_ticks;
+        }
+        let elapsed = self.source_ns(sys).saturating_sub(self.anchor_ns);
+        let ticks = elapsed as u128 * LSE_FREQUENCY as u128 / (1_000_000_000 * self.async_ticks() as u128);
+        self.anchor_ticks + ticks as i64
+    }
+
+    fn wdu(&self, secs: i64) -> u32 {
+        let anchor_day = self.anchor_ticks.div_euclid(self.sync_ticks()).div_euclid(SECS_PER_DAY);
+        let days = secs.div_euclid(SECS_PER_DAY) - anchor_day;
+        ((self.anchor_wdu as i64 - 1 + days).rem_euclid(7) + 1) as u32
+    }
+
+    fn fmt12(&self) -> bool {
+        self.cr & CR_FMT != 0
+    }
+
+    fn tr(&self, ticks: i64) -> u32 {
+        if self.in_init() {
+            return self.init_tr;
+        }
+        encode_tr(ticks.div_euclid(self.sync_ticks()), self.fmt12())
+    }
+
+    fn dr(&self, ticks: i64) -> u32 {
+        if self.in_init() {
+            return self.init_dr;
+        }
+        let secs = ticks.div_euclid(self.sync_ticks());
+        encode_dr(secs, self.wdu(secs))
+    }
+
+    fn ssr(&self, ticks: i64) -> u32 {
+        (self.sync_ticks() - 1 - ticks.rem_euclid(self.sync_ticks())) as u32
+    }
+
+    fn isr(&mut self, sys: &System) -> u32 {
+        let mut v = self.isr;
+        if self.in_init() {
+            v |= ISR_INITF;
+        }
+        // The alarm and wakeup registers can be written when they are disabled
+        for i in 0..2 {
+            if self.cr & (CR_ALRAE << i) == 0 {
+                v |= ISR_ALRAWF << i;
+            }
+        }
+        if self.cr & CR_WUTE == 0 {
+            v |= ISR_WUTWF;
+        }
+        // INITS tells if the calendar was ever set: the year isn't 0
+        if self.dr(self.ticks(sys)) & 0xFF_0000 != 0 {
+            v |= ISR_INITS;
+        }
+        if let Some(cleared_at) = self.rsf_cleared_at {
+            if !self.in_init() && sys.p.clock.borrow().now_ns() >= cleared_at + RSF_DELAY_NS {
+                self.rsf_cleared_at = None;
+            }
+        }
+        if self.rsf_cleared_at.is_none() {
+            v |= ISR_RSF;
+        }
+        v
+    }
+
+    fn enter_init(&mut self, sys: &System) {
+        let ticks = self.ticks(sys);
+        self.init_tr = self.tr(ticks);
+        self.init_dr = self.dr(ticks);
+        self.set_calendar(sys, ticks);
+        self.isr |= ISR_INIT;
+        self.rsf_cleared_at = Some(0);
+    }
+
+    /// The calendar restarts from what the firmware wrote in TR and DR
+    fn exit_init(&mut self, sys: &System) {
+        let secs = decode_dr(self.init_dr) + decode_tr(self.init_tr, self.fmt12());
+        self.isr &= !ISR_INIT;
+        self.set_calendar(sys, secs * self.sync_ticks());
+        self.anchor_wdu = (self.init_dr >> 13) & 0b111;
+        self.rsf_cleared_at = Some(sys.p.clock.borrow().now_ns());
+        debug!("RTC set to {}", format_date(secs));
+    }
+
+    fn set_calendar(&mut self, sys: &System, ticks: i64) {
+        let wdu = self.wdu(ticks.div_euclid(self.sync_ticks()));
+        self.anchor_ns = self.source_ns(sys);
+        self.anchor_ticks = ticks;
+        self.anchor_wdu = wdu;
+        self.alarm_checked_secs = ticks.div_euclid(self.sync_ticks());
+        self.locked_ticks = None;
+    }
+
+    fn alarm_matches(&self, alrm: u32, secs: i64) -> bool {
+        let time = decode_tr(alrm & 0x7F_7F7F, self.fmt12());
+        let (h, m, s) = (time / 3600, (time / 60) % 60, time % 60);
+        let now = secs.rem_euclid(SECS_PER_DAY);
+
+        if alrm & ALRM_MSK1 == 0 && s != now % 60 { return false; }
+        if alrm & ALRM_MSK2 == 0 && m != (now / 60) % 60 { return false; }
+        if alrm & ALRM_MSK3 == 0 && h != now / 3600 { return false; }
+        if alrm & ALRM_MSK4 == 0 {
+            if alrm & ALRM_WDSEL != 0 {
+                if (alrm >> 24) & 0xF != self.wdu(secs) { return false; }
+            } else {
+                let (_, _, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY) + EPOCH_2000_DAYS);
+                if from_bcd((alrm >> 24) & 0x3F) != day { return false; }
+            }
+        }
+        true
+    }
+
+    fn wakeup_period_ns(&self) -> u64 {
+        let wut = self.wutr as u64 + 1;
+        let ns = match self.cr & CR_WUCKSEL_MASK {
+            // RTCCLK divided by 16, 8, 4, or 2
+            v @ 0..=3 => wut * (16 >> v) * 1_000_000_000 / LSE_FREQUENCY,
+            // ck_spre, 2^16 is added to WUT with 11x
+            v => {
+                let wut = if v >= 6 { wut + 0x10000 } else { wut };
+                (wut as f64 * self.sync_ticks() as f64 * self.tick_ns()) as u64
+            }
+        };
+        ns.max(1)
+    }
+
+    /// Sets the alarm and wakeup flags for what happened since the last sync
+    fn sync(&mut self, sys: &System) {
+        if self.in_init() {
+            return;
+        }
+
+        let secs = self.ticks(sys).div_euclid(self.sync_ticks());
+        if secs < self.alarm_checked_secs {
+            self.alarm_checked_secs = secs;
+        }
+        // If we haven't looked for a long time, we only check the last day
+        let start = (self.alarm_checked_secs + 1).max(secs - SECS_PER_DAY + 1);
+        for s in start..=secs {
+            for i in 0..2 {
+                if self.cr & (CR_ALRAE << i) != 0 && self.alarm_matches(self.alrmr[i], s) {
+                    self.isr |= ISR_ALRAF << i;
+                    if self.cr & (CR_ALRAIE << i) != 0 {
+                        trace!("RTC alarm {}", ['A', 'B'][i]);
+                        sys.p.exti.borrow_mut().trigger_line(&mut sys.p.nvic.borrow_mut(), line::RTC_ALARM);
+                    }
+                }
+            }
+        }
+        self.alarm_checked_secs = secs;
+
+        if self.cr & CR_WUTE != 0 {
+            let elapsed = self.source_ns(sys).saturating_sub(self.wut_start_ns);
+            let periods = elapsed / self.wakeup_period_ns();
+            if periods > self.wut_periods {
+                self.wut_periods = periods;
+                self.isr |= ISR_WUTF;
+                if self.cr & CR_WUTIE != 0 {
+                    sys.p.exti.borrow_mut().trigger_line(&mut sys.p.nvic.borrow_mut(), line::RTC_WAKEUP);
+                }
+            }
+        }
+    }
+
+    /// Schedules a clock event when an interrupt may be due. With the host
+    /// time, the delay is an estimate, the next event checks again.
+    fn schedule(&self, sys: &System) {
+        let mut delays = vec![];
+        if !self.in_init() {
+            if self.cr & (CR_ALRAIE | (CR_ALRAIE << 1)) != 0 {
+                // Next second
+                let ticks = self.sync_ticks() - self.ticks(sys).rem_euclid(self.sync_ticks());
+                delays.push((ticks as f64 * self.tick_ns()) as u64);
+            }
+            if self.cr & CR_WUTE != 0 && self.cr & CR_WUTIE != 0 {
+                let period = self.wakeup_period_ns();
+                let elapsed = self.source_ns(sys).saturating_sub(self.wut_start_ns);
+                delays.push(period - elapsed % period);
+            }
+        }
+
+        let mut clock = sys.p.clock.borrow_mut();
+        clock.cancel(self.base, EVENT_TICK);
+        if let Some(delay) = delays.into_iter().min() {
+            let cycles = clock.ns_to_cycles(delay);
+            clock.schedule(cycles, self.base, EVENT_TICK);
+        }
+    }
+
+    fn write_protected(&mut self, offset: u32) -> bool {
+        if self.is_locked() {
+            warn!("RTC write to offset=0x{:02x} ignored, registers are write protected", offset);
+            return true;
+        }
+        false
+    }
+}
+
+impl Peripheral for Rtc {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        self.sync(sys);
+        let shadowed = self.cr & CR_BYPSHAD == 0;
+        match offset {
+            0x0000 | 0x0028 => {
+                // TR and SSR lock the calendar shadow registers until DR is read
+                let ticks = self.ticks(sys);
+                if shadowed && !self.in_init() {
+                    self.locked_ticks = Some(ticks);
+                }
+                if offset == 0x0000 { self.tr(ticks) } else { self.ssr(ticks) }
+            }
+            0x0004 => {
+                let ticks = self.locked_ticks.take().unwrap_or_else(|| self.ticks(sys));
+                self.dr(ticks)
+            }
+            0x0008 => self.cr,
+            0x000C => self.isr(sys),
+            0x0010 => self.prer,
+            0x0014 => self.wutr,
+            0x0018 => self.calibr,
+            0x001C => self.alrmr[0],
+            0x0020 => self.alrmr[1],
+            0x003C => self.calr,
+            0x0040 => self.tafcr,
+            0x0044 => self.alrmssr[0],
+            0x0048 => self.alrmssr[1],
+            0x0050..=0x009C => self.bkp[((offset - 0x50) / 4) as usize],
+            // Timestamps and tamper are not supported
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        // Catch up with what happened before the registers change
+        self.sync(sys);
+
+        match offset {
+            0x0000 | 0x0004 => {
+                if self.write_protected(offset) {
+                    return;
+                }
+                if !self.in_init() {
+                    warn!("RTC calendar write outside of init mode ignored");
+                    return;
+                }
+                if offset == 0x0000 {
+                    self.init_tr = value & 0x007F_7F7F;
+                } else {
+                    self.init_dr = value & 0x00FF_FF3F;
+                }
+            }
+            0x0008 => {
+                if self.write_protected(offset) {
+                    return;
+                }
+                let ticks = self.ticks(sys);
+                let was_wute = self.cr & CR_WUTE != 0;
+                // Daylight saving adjustments, the bits are not stored
+                let hour = 3600 * self.sync_ticks();
+                if value & CR_ADD1H != 0 && !self.in_init() {
+                    self.set_calendar(sys, ticks + hour);
+                }
+                if value & CR_SUB1H != 0 && !self.in_init() {
+                    self.set_calendar(sys, ticks - hour);
+                }
+                self.cr = value & 0x00FF_FF7F & !(CR_ADD1H | CR_SUB1H);
+                if !was_wute && self.cr & CR_WUTE != 0 {
+                    self.wut_start_ns = self.source_ns(sys);
+                    self.wut_periods = 0;
+                }
+            }
+            0x000C => {
+                // The flags are rc_w0, and can be cleared even when the registers are protected
+                self.isr &= value | !ISR_FLAGS_MASK;
+                if value & ISR_RSF == 0 {
+                    self.rsf_cleared_at = Some(sys.p.clock.borrow().now_ns());
+                }
+                let init = value & ISR_INIT != 0;
+                if init != self.in_init() && !self.write_protected(offset) {
+                    if init { self.enter_init(sys) } else { self.exit_init(sys) }
+                }
+            }
+            0x0010 => {
+                if self.write_protected(offset) {
+                    return;
+                }
+                if self.in_init() {
+                    // The calendar is expressed in ticks of the prescaler
+                    let secs = self.anchor_ticks.div_euclid(self.sync_ticks());
+                    self.prer = value & 0x007F_7FFF;
+                    self.anchor_ticks = secs * self.sync_ticks();
+                }
+            }
+            0x0014 if !self.write_protected(offset) && self.cr & CR_WUTE == 0 => {
+                self.wutr = value & 0xFFFF;
+            }
+            0x0018 if !self.write_protected(offset) => self.calibr = value,
+            0x001C | 0x0020 => {
+                let i = ((offset - 0x1C) / 4) as usize;
+                if !self.write_protected(offset) && self.cr & (CR_ALRAE << i) == 0 {
+                    self.alrmr[i] = value;
+                }
+            }
+            0x0024 => {
+                // WPR: 0xCA then 0x53 unlocks. Anything else locks again.
+                self.wpr_step = match (self.wpr_step, value & 0xFF) {
+                    (0, 0xCA) => 1,
+                    (1, 0x53) => 2,
+                    _ => 0,
+                };
+            }
+            0x002C if !self.write_protected(offset) && !self.in_init() => {
+                // SHIFTR: adds one second (ADD1S) and/or subtracts a fraction of a second (SUBFS)
+                let add = if value & (1 << 31) != 0 { self.sync_ticks() } else { 0 };
+                let sub = (value & 0x7FFF) as i64;
+                let ticks = self.ticks(sys);
+                self.set_calendar(sys, ticks + add - sub);
+            }
+            0x003C if !self.write_protected(offset) => self.calr = value,
+            0x0040 => self.tafcr = value,
+            0x0044 if !self.write_protected(offset) => self.alrmssr[0] = value,
+            0x0048 if !self.write_protected(offset) => self.alrmssr[1] = value,
+            0x0050..=0x009C => self.bkp[((offset - 0x50) / 4) as usize] = value,
+            _ => {}
+        }
+
+        self.schedule(sys);
+    }
+
+    fn on_event(&mut self, sys: &System, _event: u32) {
+        self.sync(sys);
+        self.schedule(sys);
+    }
+}
+
+fn host_time_ns() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Offset of the local time zone in seconds
+fn host_utc_offset() -> i64 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            0
+        } else {
+            tm.tm_gmtoff as i64
+        }
+    }
+}
+
+fn bcd(v: u32) -> u32 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+fn from_bcd(v: u32) -> u32 {
+    (v >> 4) * 10 + (v & 0xF)
+}
+
+/// Days since 1970-01-01 of a date. From Howard Hinnant's date algorithms.
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// (year, month, day) of a number of days since 1970-01-01
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// Weekday of a time in seconds since 2000-01-01, 1 is Monday
+fn weekday(secs: i64) -> u32 {
+    // 1970-01-01 was a Thursday
+    ((secs.div_euclid(SECS_PER_DAY) + EPOCH_2000_DAYS + 3).rem_euclid(7) + 1) as u32
+}
+
+fn encode_tr(secs: i64, fmt12: bool) -> u32 {
+    let t = secs.rem_euclid(SECS_PER_DAY) as u32;
+    let (mut h, m, s) = (t / 3600, (t / 60) % 60, t % 60);
+    let mut pm = 0;
+    if fmt12 {
+        if h >= 12 { pm = TR_PM; }
+        h %= 12;
+        if h == 0 { h = 12; }
+    }
+    pm | (bcd(h) << 16) | (bcd(m) << 8) | bcd(s)
+}
+
+/// Seconds since midnight
+fn decode_tr(tr: u32, fmt12: bool) -> i64 {
+    let mut h = from_bcd((tr >> 16) & 0x3F);
+    if fmt12 {
+        h = h % 12 + if tr & TR_PM != 0 { 12 } else { 0 };
+    }
+    let m = from_bcd((tr >> 8) & 0x7F);
+    let s = from_bcd(tr & 0x7F);
+    (h * 3600 + m * 60 + s) as i64
+}
+
+fn encode_dr(secs: i64, wdu: u32) -> u32 {
+    let (y, m, d) = civil_from_days(secs.div_euclid(SECS_PER_DAY) + EPOCH_2000_DAYS);
+    let yy = (y - 2000).rem_euclid(100) as u32;
+    (bcd(yy) << 16) | (wdu << 13) | (bcd(m) << 8) | bcd(d)
+}
+
+/// Seconds since 2000-01-01 at midnight of the date
+fn decode_dr(dr: u32) -> i64 {
+    let y = 2000 + from_bcd((dr >> 16) & 0xFF) as i64;
+    let m = from_bcd((dr >> 8) & 0x1F).clamp(1, 12);
+    let d = from_bcd(dr & 0x3F).max(1);
+    (days_from_civil(y, m, d) - EPOCH_2000_DAYS) * SECS_PER_DAY
+}
+
+/// Parses "YYYY-MM-DD HH:MM:SS" (the time is optional) into seconds since 2000-01-01
+fn parse_date(date: &str) -> Result<i64> {
+    let mut parts = date.trim().splitn(2, [' ', 'T']);
+    let numbers = |s: &str, sep: char| -> Result<Vec<u32>> {
+        s.split(sep).map(|v| v.trim().parse::<u32>().context("Expected a number")).collect()
+    };
+
+    let (y, m, d) = match numbers(parts.next().unwrap_or_default(), '-')?.as_slice() {
+        [y, m, d] => (*y as i64, *m, *d),
+        _ => bail!("Expected YYYY-MM-DD"),
+    };
+    if !(2000..=2099).contains(&y) {
+        bail!("The RTC only counts years from 2000 to 2099");
+    }
+    let days = days_from_civil(y, m, d);
+    if civil_from_days(days) != (y, m, d) {
+        bail!("Invalid date");
+    }
+
+    let time = match parts.next() {
+        Some(time) => match numbers(time, ':')?.as_slice() {
+            [h, mi, s] if *h < 24 && *mi < 60 && *s < 60 => (h * 3600 + mi * 60 + s) as i64,
+            _ => bail!("Expected HH:MM:SS"),
+        },
+        None => 0,
+    };
+
+    Ok((days - EPOCH_2000_DAYS) * SECS_PER_DAY + time)
+}
+
+fn format_date(secs: i64) -> String {
+    let (y, m, d) = civil_from_days(secs.div_euclid(SECS_PER_DAY) + EPOCH_2000_DAYS);
+    let t = secs.rem_euclid(SECS_PER_DAY);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", y, m, d, t / 3600, (t / 60) % 60, t % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_days_works() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 1, 1), EPOCH_2000_DAYS);
+        // 2000 is a leap year, 2100 isn't
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2100, 3, 1), 47541);
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+
+        for days in -1000..60000 {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn parse_date_works() {
+        assert_eq!(parse_date("2000-01-01").unwrap(), 0);
+        assert_eq!(parse_date(" 2000-03-01 00:00:01 ").unwrap(), 60 * SECS_PER_DAY + 1);
+        assert_eq!(parse_date("2024-02-29T12:34:56").unwrap(), 762525296);
+        assert_eq!(format_date(762525296), "2024-02-29 12:34:56");
+    }
+
+    #[test]
+    fn parse_date_rejects_bad_input() {
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("2000-13-01").is_err());
+        assert!(parse_date("2000-01-00").is_err());
+        assert!(parse_date("1999-12-31").is_err());
+        assert!(parse_date("2100-01-01").is_err());
+        assert!(parse_date("2000-01-01 24:00:00").is_err());
+        assert!(parse_date("2000-01-01 12:00").is_err());
+        assert!(parse_date("2000/01/01").is_err());
+    }
+
+    #[test]
+    fn encode_tr_12_hour_format() {
+        // Midnight is 12 AM, noon is 12 PM
+        assert_eq!(encode_tr(0, true), 0x12_0000);
+        assert_eq!(encode_tr(12 * 3600, true), TR_PM | 0x12_0000);
+        assert_eq!(encode_tr(13 * 3600 + 5 * 60 + 9, true), TR_PM | 0x01_0509);
+        assert_eq!(encode_tr(13 * 3600 + 5 * 60 + 9, false), 0x13_0509);
+        // Only the time of the day is encoded
+        assert_eq!(encode_tr(SECS_PER_DAY + 59, false), 0x00_0059);
+
+        assert_eq!(decode_tr(0x12_0000, true), 0);
+        assert_eq!(decode_tr(TR_PM | 0x12_0000, true), 12 * 3600);
+        assert_eq!(decode_tr(TR_PM | 0x11_5959, true), SECS_PER_DAY - 1);
+
+        for secs in 0..SECS_PER_DAY {
+            assert_eq!(decode_tr(encode_tr(secs, true), true), secs);
+            assert_eq!(decode_tr(encode_tr(secs, false), false), secs);
+        }
+    }
+}
@@ -17,24 +17,107 @@ impl Scb {
     }
 }
 
+const AIRCR_VECTKEY: u32 = 0x05FA;
+const AIRCR_VECTKEYSTAT: u32 = 0xFA05;
+
+// The first exception configured by SHPR1 is MemManage (4)
+const SHPR_FIRST_IRQ: i32 = 4 - 16;
+
+// SHCSR active and pended bits, with their exception number
+const SHCSR_ACTIVE_BITS: [(u32, usize); 7] = [(0, 4), (1, 5), (3, 6), (7, 11), (8, 12), (10, 14), (11, 15)];
+const SHCSR_PENDED_BITS: [(u32, usize); 4] = [(12, 6), (13, 4), (14, 5), (15, 11)];
+const SHCSR_ENABLES_MASK: u32 = 0b111 << 16;
+
 impl Peripheral for Scb {
-    fn read(&mut self, _sys: &System, _offset: u32) -> u32 {
-        0
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        let nvic = sys.p.nvic.borrow();
+        match offset {
+            0x0004 => {
+                // ICSR register
+                let vect_active = nvic.current_exception() as u32;
+                let vect_pending = nvic.next_pending_exception().unwrap_or(0) as u32;
+                let isr_pending = nvic.next_pending_exception().is_some_and(|exc| exc >= 16);
+
+                let mut v = vect_active | (vect_pending << 12);
+                if isr_pending { v |= 1 << 22; }
+                if nvic.is_intr_pending(irq::SYSTICK) { v |= 1 << 26; }
+                if nvic.is_intr_pending(irq::PENDSV) { v |= 1 << 28; }
+                v
+            }
+            0x000C => {
+                // AIRCR register
+                (AIRCR_VECTKEYSTAT << 16) | ((nvic.aircr_prigroup() as u32) << 8)
+            }
+            0x0018..=0x0020 => {
+                // SHPR1-3 registers
+                let irq = SHPR_FIRST_IRQ + (offset - 0x18) as i32;
+                u32::from_le_bytes([0,1,2,3].map(|i| nvic.priority(irq + i)))
+            }
+            0x0024 => {
+                // SHCSR register
+                let active = SHCSR_ACTIVE_BITS.iter()
+                    .filter(|(_, exc)| nvic.is_exception_active(*exc))
+                    .fold(0, |v, (bit, _)| v | (1 << bit));
+                let pended = SHCSR_PENDED_BITS.iter()
+                    .filter(|(_, exc)| nvic.is_exception_pending(*exc))
+                    .fold(0, |v, (bit, _)| v | (1 << bit));
+                nvic.fault_status.shcsr_enables | active | pended
+            }
+            0x0028 => nvic.fault_status.cfsr,
+            0x002C => nvic.fault_status.hfsr,
+            0x0034 => nvic.fault_status.mmfar,
+            0x0038 => nvic.fault_status.bfar,
+            _ => 0
+        }
+    }
+
+    fn has_byte_fields(&self, offset: u32) -> bool {
+        // SHPR1-3, e.g., SCB->SHP[10] = prio for PendSV
+        (0x0018..=0x0020).contains(&offset)
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        let mut nvic = sys.p.nvic.borrow_mut();
         match offset {
             0x0004 => {
                 // ICSR register
+                // bit 25: clear systick pending
                 // bit 26: set systick pending
+                // bit 27: clear PendSV pending
                 // bit 28: set PendSV pending
+                if value & (1 << 25) != 0 {
+                    nvic.clear_intr_pending(irq::SYSTICK);
+                }
                 if value & (1 << 26) != 0 {
-                    sys.p.nvic.borrow_mut().set_intr_pending(irq::SYSTICK);
+                    nvic.set_intr_pending(irq::SYSTICK);
+                }
+                if value & (1 << 27) != 0 {
+                    nvic.clear_intr_pending(irq::PENDSV);
                 }
                 if value & (1 << 28) != 0 {
-                    sys.p.nvic.borrow_mut().set_intr_pending(irq::PENDSV);
+                    nvic.set_intr_pending(irq::PENDSV);
                 }
             }
+            // AIRCR register. Writes are ignored without the key.
+            0x000C if value >> 16 == AIRCR_VECTKEY => {
+                nvic.set_aircr_prigroup(((value >> 8) & 7) as u8);
+            }
+            0x0018..=0x0020 => {
+                // SHPR1-3 registers
+                let irq = SHPR_FIRST_IRQ + (offset - 0x18) as i32;
+                for (i, priority) in value.to_le_bytes().into_iter().enumerate() {
+                    nvic.set_priority(irq + i as i32, priority);
+                }
+            }
+            0x0024 => {
+                // SHCSR register. We only care about the fault enable bits.
+                nvic.fault_status.shcsr_enables = value & SHCSR_ENABLES_MASK;
+            }
+            // CFSR and HFSR bits are write one to clear
+            0x0028 => nvic.fault_status.cfsr &= !value,
+            0x002C => nvic.fault_status.hfsr &= !value,
+            0x0034 => nvic.fault_status.mmfar = value,
+            0x0038 => nvic.fault_status.bfar = value,
             _ => {}
         }
     }
@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use crate::{system::System, ext_devices::ExtDevice};
-use super::Peripheral;
+use super::{Peripheral, Trigger};
 
 use crate::ext_devices::ExtDevices;
 
@@ -11,6 +11,9 @@ use std::{rc::Rc, cell::RefCell};
 pub struct Spi {
     pub name: String,
     pub cr1: u32,
+    pub cr2: u32,
+    // A byte was received and DR wasn't read yet
+    pub rx_pending: bool,
     pub rx_buffer: u32,
     pub ready_toggle: bool,
     pub ext_device: Option<Rc<RefCell<dyn ExtDevice<(), u8>>>>,
@@ -40,6 +43,7 @@ impl Peripheral for Spi {
             0x0000 => {
                 self.cr1
             }
+            0x0004 => self.cr2,
             0x0008 => {
                 // SR register
                 // receive buffer not empty
@@ -50,6 +54,7 @@ impl Peripheral for Spi {
             0x000C => {
                 // DR register
                 let v = self.rx_buffer;
+                self.rx_pending = false;
                 if self.is_16bits() {
                     trace!("{} read={:04x?}", self.name, v as u16);
                 } else {
@@ -68,6 +73,13 @@ impl Peripheral for Spi {
                 // CR1 register
                 self.cr1 = value;
             }
+            0x0004 => {
+                self.cr2 = value;
+                // TX requests are always up
+                if value & (1 << 1) != 0 {
+                    sys.p.send_trigger(Trigger::DmaRequest);
+                }
+            }
             0x000C => {
                 // DR register
 
@@ -80,6 +92,10 @@ impl Peripheral for Spi {
                         d.read(sys, ()) as u32
                     }
                 }).unwrap_or(0);
+                self.rx_pending = true;
+                if self.cr2 & (1 << 0) != 0 {
+                    sys.p.send_trigger(Trigger::DmaRequest);
+                }
 
                 if self.is_16bits() {
                     self.ext_device.as_ref().map(|d| d.borrow_mut()).map(|mut d| {
@@ -97,4 +113,13 @@ impl Peripheral for Spi {
             _ => {}
         }
     }
+
+    fn dma_request(&mut self, _sys: &System, offset: u32, to_memory: bool) -> bool {
+        // CR2: RXDMAEN is bit 0, TXDMAEN is bit 1
+        match (offset, to_memory) {
+            (0x000C, true) => self.cr2 & (1 << 0) != 0 && self.rx_pending,
+            (0x000C, false) => self.cr2 & (1 << 1) != 0,
+            _ => false,
+        }
+    }
 }
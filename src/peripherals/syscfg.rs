@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::system::System;
+use super::Peripheral;
+
+#[derive(Default)]
+pub struct Syscfg {
+    memrmp: u32,
+    pmc: u32,
+    cmpcr: u32,
+}
+
+impl Syscfg {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "SYSCFG" {
+            Some(Box::new(Self::default()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for Syscfg {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.memrmp,
+            0x0004 => self.pmc,
+            0x0008..=0x0014 => {
+                // EXTICR1-4: 4 bits per line, selecting the GPIO port
+                let first_line = (offset - 0x8) as usize;
+                let exti = sys.p.exti.borrow();
+                (0..4).fold(0, |v, i| v | (exti.gpio_port(first_line + i) as u32) << (4*i))
+            }
+            0x0020 => {
+                // CMPCR: the compensation cell is ready as soon as it's enabled
+                self.cmpcr | ((self.cmpcr & 1) << 8)
+            }
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => self.memrmp = value,
+            0x0004 => self.pmc = value,
+            0x0008..=0x0014 => {
+                let first_line = (offset - 0x8) as usize;
+                let mut exti = sys.p.exti.borrow_mut();
+                for i in 0..4 {
+                    let port = ((value >> (4*i)) & 0xF) as u8;
+                    if port != exti.gpio_port(first_line + i) {
+                        trace!("EXTI{} mapped to GPIO{}", first_line + i, (b'A' + port) as char);
+                        exti.set_gpio_port(first_line + i, port);
+                    }
+                }
+            }
+            0x0020 => self.cmpcr = value & 1,
+            _ => {}
+        }
+    }
+}
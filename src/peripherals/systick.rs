@@ -1,52 +1,115 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use crate::system::System;
-use super::Peripheral;
+use super::{Peripheral, nvic::irq};
 
-#[derive(Default)]
 pub struct SysTick {
-    ctl: u32,
+    base: u32,
+    ctrl: u32,
     reload: u32,
-    val_toggle: bool,
+    countflag: bool,
+    // The counter had the value `anchor_val` at the cycle `anchor_cycle`.
+    // The current value is derived from the elapsed cycles.
+    anchor_cycle: u64,
+    anchor_val: u32,
 }
 
+// CTRL register
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_TICKINT: u32 = 1 << 1;
+const CTRL_CLKSOURCE: u32 = 1 << 2;
+const CTRL_COUNTFLAG: u32 = 1 << 16;
+
+// CALIB register
+const CALIB_SKEW: u32 = 1 << 30;
+
+const COUNTER_MASK: u32 = 0x00FF_FFFF;
+
+// The counter reached zero
+const EVENT_ZERO: u32 = 0;
+
 impl SysTick {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+    pub fn new(name: &str, base: u32) -> Option<Box<dyn Peripheral>> {
         if name == "STK" {
-            Some(Box::new(Self::default()))
+            Some(Box::new(Self { base, ctrl: 0, reload: 0, countflag: false, anchor_cycle: 0, anchor_val: 0 }))
         } else {
             None
         }
     }
 
-    fn has_int_enabled(&self) -> bool {
-        (self.ctl & 0b11) == 0b11
+    fn is_enabled(&self) -> bool {
+        self.ctrl & CTRL_ENABLE != 0
     }
 
-    fn set_nvic_systick_period(&self, sys: &System) {
-        let nvic_systick_period = if self.has_int_enabled() {
-            Some(self.reload)
+    /// Number of HCLK cycles per counter tick.
+    /// Without CLKSOURCE, the counter runs at HCLK/8.
+    fn divider(&self) -> u64 {
+        if self.ctrl & CTRL_CLKSOURCE != 0 { 1 } else { 8 }
+    }
+
+    fn elapsed_ticks(&self, sys: &System) -> u64 {
+        let now = sys.p.clock.borrow().now();
+        now.saturating_sub(self.anchor_cycle) / self.divider()
+    }
+
+    fn current_val(&self, sys: &System) -> u32 {
+        if !self.is_enabled() {
+            return self.anchor_val;
+        }
+
+        // The counter goes down to 0, and is reloaded on the next tick.
+        let ticks = self.elapsed_ticks(sys);
+        let v0 = self.anchor_val as u64;
+        let reload = self.reload as u64;
+        let v = if ticks <= v0 {
+            v0 - ticks
         } else {
-            None
+            reload - ((ticks - v0 - 1) % (reload + 1))
         };
+        v as u32
+    }
+
+    /// Restarts the counting from `val`, and schedules the next time we reach zero.
+    fn reanchor(&mut self, sys: &System, val: u32) {
+        let mut clock = sys.p.clock.borrow_mut();
+        self.anchor_cycle = clock.now();
+        self.anchor_val = val;
 
-        sys.p.nvic.borrow_mut().systick_period = nvic_systick_period;
+        clock.cancel(self.base, EVENT_ZERO);
+        if !self.is_enabled() {
+            return;
+        }
+
+        // A reload value of 0 stops the counter on the next wrap
+        let ticks_to_zero = match (val, self.reload) {
+            (0, 0) => return,
+            (0, reload) => reload as u64 + 1,
+            (val, _) => val as u64,
+        };
+        clock.schedule(ticks_to_zero * self.divider(), self.base, EVENT_ZERO);
+    }
+
+    fn calib(&self, sys: &System) -> u32 {
+        // Number of ticks in 10ms for the HCLK/8 reference clock
+        let reference = sys.p.clock.borrow().hclk / 8;
+        let tenms = (reference / 100) & COUNTER_MASK;
+        let skew = if !reference.is_multiple_of(100) { CALIB_SKEW } else { 0 };
+        skew | tenms
     }
 }
 
 impl Peripheral for SysTick {
-    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
         match offset {
             0x0000 => {
-                self.val_toggle = !self.val_toggle;
-                // toggle the count bit
-                self.ctl | if self.val_toggle { 0 } else { 1 << 16 }
+                // CTRL register. COUNTFLAG is cleared on read.
+                let countflag = if self.countflag { CTRL_COUNTFLAG } else { 0 };
+                self.countflag = false;
+                self.ctrl | countflag
             }
             0x0004 => self.reload,
-            0x0008 => {
-                self.val_toggle = !self.val_toggle;
-                if self.val_toggle { 0 } else { self.reload/2 }
-            }
+            0x0008 => self.current_val(sys),
+            0x000C => self.calib(sys),
             _ => 0
         }
     }
@@ -55,15 +118,30 @@ impl Peripheral for SysTick {
         match offset {
             0x0000 => {
                 // CTRL register
-                self.ctl = value;
-                self.set_nvic_systick_period(sys);
+                let val = self.current_val(sys);
+                self.ctrl = value & (CTRL_ENABLE | CTRL_TICKINT | CTRL_CLKSOURCE);
+                self.reanchor(sys, val);
             }
             0x0004 => {
-                // LOAD register
-                self.reload = value;
-                self.set_nvic_systick_period(sys);
+                // LOAD register. It's used on the next reload.
+                let val = self.current_val(sys);
+                self.reload = value & COUNTER_MASK;
+                self.reanchor(sys, val);
+            }
+            0x0008 => {
+                // VAL register. Any write clears the counter and COUNTFLAG.
+                self.countflag = false;
+                self.reanchor(sys, 0);
             }
             _ => {}
         }
     }
+
+    fn on_event(&mut self, sys: &System, _event: u32) {
+        self.countflag = true;
+        if self.ctrl & CTRL_TICKINT != 0 {
+            sys.p.nvic.borrow_mut().set_intr_pending(irq::SYSTICK);
+        }
+        self.reanchor(sys, 0);
+    }
 }
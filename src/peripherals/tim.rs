@@ -0,0 +1,427 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// General purpose, advanced, and basic timers. The counter is derived from the
+// virtual clock. Flags are computed lazily when SR is read, and clock events
+// are only scheduled when something needs to happen at a precise time
+// (interrupts, one-pulse mode, preloaded registers).
+
+use crate::system::System;
+use super::{Peripheral, Trigger};
+
+pub struct Tim {
+    name: String,
+    // e.g., 6 for TIM6. Identifies the TRGO of the timer.
+    number: u8,
+    base: u32,
+    // Advanced timers have separate interrupt lines. Otherwise, both are the same.
+    irq_update: Option<i32>,
+    irq_cc: Option<i32>,
+
+    cr1: u32,
+    cr2: u32,
+    smcr: u32,
+    dier: u32,
+    sr: u32,
+    ccmr: [u32; 2],
+    ccer: u32,
+    psc: u32,
+    arr: u32,
+    rcr: u32,
+    ccr: [u32; 4],
+    bdtr: u32,
+    dcr: u32,
+
+    // PSC is always preloaded, ARR only with ARPE. These are the values in use.
+    psc_active: u32,
+    arr_active: u32,
+
+    // The counter position is tracked as a "phase" that only goes up, even
+    // when counting down. The counter had the phase `anchor_phase` at
+    // `anchor_cycle`, and advances by `tick_num/tick_den` per cycle.
+    anchor_cycle: u64,
+    anchor_phase: u64,
+    tick_num: u64,
+    tick_den: u64,
+    // Phase at which we last updated the flags
+    synced_phase: u64,
+    // An update event happened with UDE set, the DMA hasn't served it yet
+    dma_update_pending: bool,
+}
+
+const CR1_CEN: u32 = 1 << 0;
+const CR1_UDIS: u32 = 1 << 1;
+const CR1_URS: u32 = 1 << 2;
+const CR1_OPM: u32 = 1 << 3;
+const CR1_DIR: u32 = 1 << 4;
+const CR1_CMS: u32 = 0b11 << 5;
+const CR1_ARPE: u32 = 1 << 7;
+
+const SR_UIF: u32 = 1 << 0;
+const SR_CC_MASK: u32 = 0b1111 << 1;
+
+const EGR_UG: u32 = 1 << 0;
+
+// CR2 MMS: what is sent on TRGO
+const CR2_MMS_SHIFT: u32 = 4;
+const MMS_RESET: u32 = 0b000;
+const MMS_ENABLE: u32 = 0b001;
+const MMS_UPDATE: u32 = 0b010;
+
+const DIER_UDE: u32 = 1 << 8;
+
+const EVENT_TICK: u32 = 0;
+
+impl Tim {
+    pub fn new(name: &str, base: u32, interrupts: &[(String, i32)]) -> Option<Box<dyn Peripheral>> {
+        if !name.starts_with("TIM") {
+            return None;
+        }
+
+        let find_irq = |pattern: &str| interrupts.iter()
+            .find(|(n, _)| n.contains(pattern))
+            .map(|(_, irq)| *irq);
+
+        // TIM1 and TIM8 have one interrupt line per event kind. The other timers
+        // have a single line, possibly shared with another peripheral.
+        let (irq_update, irq_cc) = if interrupts.len() > 1 {
+            (find_irq("_UP"), find_irq("_CC"))
+        } else {
+            let irq = interrupts.first().map(|(_, irq)| *irq);
+            (irq, irq)
+        };
+
+        Some(Box::new(Self {
+            name: name.to_string(),
+            number: name[3..].parse().unwrap_or(0),
+            base,
+            irq_update,
+            irq_cc,
+            cr1: 0,
+            cr2: 0,
+            smcr: 0,
+            dier: 0,
+            sr: 0,
+            ccmr: [0; 2],
+            ccer: 0,
+            psc: 0,
+            arr: 0xFFFF,
+            rcr: 0,
+            ccr: [0; 4],
+            bdtr: 0,
+            dcr: 0,
+            psc_active: 0,
+            arr_active: 0xFFFF,
+            anchor_cycle: 0,
+            anchor_phase: 0,
+            tick_num: 1,
+            tick_den: 1,
+            synced_phase: 0,
+            dma_update_pending: false,
+        }))
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.cr1 & CR1_CEN != 0
+    }
+
+    fn counts_down(&self) -> bool {
+        // Center-aligned mode is treated as counting up
+        self.cr1 & CR1_CMS == 0 && self.cr1 & CR1_DIR != 0
+    }
+
+    fn modulo(&self) -> u64 {
+        self.arr_active as u64 + 1
+    }
+
+    fn phase(&self, now: u64) -> u64 {
+        if !self.is_enabled() {
+            return self.anchor_phase;
+        }
+        let elapsed = now.saturating_sub(self.anchor_cycle) as u128;
+        self.anchor_phase + (elapsed * self.tick_num as u128 / self.tick_den as u128) as u64
+    }
+
+    fn cnt_from_phase(&self, phase: u64) -> u32 {
+        let v = (phase % self.modulo()) as u32;
+        if self.counts_down() { self.arr_active - v } else { v }
+    }
+
+    fn phase_from_cnt(&self, cnt: u32) -> u64 {
+        let cnt = cnt.min(self.arr_active);
+        (if self.counts_down() { self.arr_active - cnt } else { cnt }) as u64
+    }
+
+    /// Phase (modulo ARR+1) at which the counter matches CCRx
+    fn cc_phase(&self, ch: usize) -> Option<u64> {
+        // Only output compare channels. Input capture would need an input signal.
+        let is_output = (self.ccmr[ch / 2] >> (8 * (ch % 2))) & 0b11 == 0;
+        let ccr = self.ccr[ch];
+        if is_output && ccr <= self.arr_active {
+            Some(self.phase_from_cnt(ccr))
+        } else {
+            None
+        }
+    }
+
+    fn now(sys: &System) -> u64 {
+        sys.p.clock.borrow().now()
+    }
+
+    /// Restarts counting from `cnt`. Used when the counting parameters change.
+    fn reanchor(&mut self, sys: &System, cnt: u32) {
+        let clock = sys.p.clock.borrow();
+        self.anchor_cycle = clock.now();
+        self.anchor_phase = self.phase_from_cnt(cnt);
+        self.synced_phase = self.anchor_phase;
+        // ticks per cycle = timer_clock / (hclk * (psc+1))
+        self.tick_num = clock.timer_clock(self.base) as u64;
+        self.tick_den = clock.hclk as u64 * (self.psc_active as u64 + 1);
+    }
+
+    fn mms(&self) -> u32 {
+        (self.cr2 >> CR2_MMS_SHIFT) & 0b111
+    }
+
+    fn send_trgo(&self, sys: &System, mms: u32) {
+        if self.mms() == mms {
+            sys.p.send_trigger(Trigger::TimTrgo(self.number));
+        }
+    }
+
+    fn raise_interrupts(&self, sys: &System, new_flags: u32) {
+        let new_flags = new_flags & self.dier;
+        let mut nvic = sys.p.nvic.borrow_mut();
+        if new_flags & SR_UIF != 0 {
+            if let Some(irq) = self.irq_update {
+                nvic.set_intr_pending(irq);
+            }
+        }
+        if new_flags & SR_CC_MASK != 0 {
+            if let Some(irq) = self.irq_cc {
+                nvic.set_intr_pending(irq);
+            }
+        }
+    }
+
+    fn set_flags(&mut self, sys: &System, flags: u32) {
+        if flags & SR_UIF != 0 && self.dier & DIER_UDE != 0 {
+            self.dma_update_pending = true;
+            sys.p.send_trigger(Trigger::DmaRequest);
+        }
+        let new_flags = flags & !self.sr;
+        self.sr |= flags;
+        self.raise_interrupts(sys, new_flags);
+    }
+
+    /// Update event: preloaded registers are transferred
+    fn apply_preload(&mut self) {
+        self.psc_active = self.psc;
+        self.arr_active = self.arr;
+    }
+
+    fn has_pending_preload(&self) -> bool {
+        self.psc_active != self.psc || self.arr_active != self.arr
+    }
+
+    /// Sets the flags of what happened since the last sync
+    fn sync(&mut self, sys: &System) {
+        let now = Self::now(sys);
+        let (a, b) = (self.synced_phase, self.phase(now));
+        if b <= a {
+            return;
+        }
+        self.synced_phase = b;
+
+        // Number of phases n in (a, b] such that n ≡ c (mod m)
+        let m = self.modulo();
+        let count = |c: u64| (b + m - c) / m - (a + m - c) / m;
+
+        let mut flags = 0;
+        let overflow = count(0) > 0;
+        if overflow && self.cr1 & CR1_UDIS == 0 {
+            flags |= SR_UIF;
+            self.send_trgo(sys, MMS_UPDATE);
+        }
+        for ch in 0..4 {
+            if let Some(c) = self.cc_phase(ch) {
+                if count(c) > 0 {
+                    flags |= 1 << (ch + 1);
+                }
+            }
+        }
+        self.set_flags(sys, flags);
+
+        if overflow {
+            let cnt = if self.cr1 & CR1_OPM != 0 {
+                // One pulse mode: the counter stops at the update event
+                self.cr1 &= !CR1_CEN;
+                if self.counts_down() { self.arr_active } else { 0 }
+            } else {
+                self.cnt_from_phase(b)
+            };
+            if self.cr1 & CR1_UDIS == 0 && self.has_pending_preload() {
+                self.apply_preload();
+                self.reanchor(sys, cnt);
+            } else if !self.is_enabled() {
+                self.reanchor(sys, cnt);
+            }
+        }
+    }
+
+    /// Next phase strictly after `phase` such that n ≡ c (mod m)
+    fn next_phase(&self, phase: u64, c: u64) -> u64 {
+        let m = self.modulo();
+        phase + ((c + m - phase % m - 1) % m) + 1
+    }
+
+    /// Schedules a clock event at the next moment we need to be precise
+    fn schedule(&self, sys: &System) {
+        let mut clock = sys.p.clock.borrow_mut();
+        clock.cancel(self.base, EVENT_TICK);
+        if !self.is_enabled() || self.tick_num == 0 {
+            return;
+        }
+
+        let now = clock.now();
+        let phase = self.phase(now);
+
+        let needs_update = self.dier & (SR_UIF | DIER_UDE) != 0
+            || self.cr1 & CR1_OPM != 0
+            || self.mms() == MMS_UPDATE
+            || self.has_pending_preload();
+
+        let mut targets = vec![];
+        if needs_update {
+            targets.push(self.next_phase(phase, 0));
+        }
+        for ch in 0..4 {
+            if self.dier & (1 << (ch + 1)) != 0 {
+                if let Some(c) = self.cc_phase(ch) {
+                    targets.push(self.next_phase(phase, c));
+                }
+            }
+        }
+
+        if let Some(target) = targets.into_iter().min() {
+            // Cycle at which the phase reaches the target, rounded up
+            let ticks = (target - self.anchor_phase) as u128;
+            let cycle = self.anchor_cycle as u128 +
+                (ticks * self.tick_den as u128).div_ceil(self.tick_num as u128);
+            let delay = (cycle as u64).saturating_sub(now).max(1);
+            clock.schedule(delay, self.base, EVENT_TICK);
+        }
+    }
+
+    fn cnt(&self, sys: &System) -> u32 {
+        self.cnt_from_phase(self.phase(Self::now(sys)))
+    }
+}
+
+impl Peripheral for Tim {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr1,
+            0x0004 => self.cr2,
+            0x0008 => self.smcr,
+            0x000C => self.dier,
+            0x0010 => {
+                self.sync(sys);
+                self.sr
+            }
+            0x0018 => self.ccmr[0],
+            0x001C => self.ccmr[1],
+            0x0020 => self.ccer,
+            0x0024 => self.cnt(sys),
+            0x0028 => self.psc,
+            0x002C => self.arr,
+            0x0030 => self.rcr,
+            0x0034..=0x0040 => self.ccr[((offset - 0x34) / 4) as usize],
+            0x0044 => self.bdtr,
+            0x0048 => self.dcr,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        // Catch up with what happened before the registers change
+        self.sync(sys);
+        let cnt = self.cnt(sys);
+
+        match offset {
+            0x0000 => {
+                let was_enabled = self.is_enabled();
+                self.cr1 = value & 0x3FF;
+                if !was_enabled && self.is_enabled() {
+                    trace!("{} enabled psc={} arr={}", self.name, self.psc_active, self.arr_active);
+                    self.send_trgo(sys, MMS_ENABLE);
+                }
+                if self.cr1 & CR1_ARPE == 0 {
+                    self.arr_active = self.arr;
+                }
+                self.reanchor(sys, cnt);
+            }
+            0x0004 => self.cr2 = value,
+            0x0008 => self.smcr = value,
+            0x000C => {
+                self.dier = value;
+                // Enabling an interrupt with its flag already set triggers it
+                self.raise_interrupts(sys, self.sr);
+            }
+            0x0010 => {
+                // rc_w0: writing 0 clears the flag
+                self.sr &= value;
+            }
+            0x0014 => {
+                // EGR register
+                let mut flags = value & SR_CC_MASK;
+                if value & EGR_UG != 0 {
+                    // Re-initializes the counter and generates an update event
+                    self.apply_preload();
+                    let cnt = if self.counts_down() { self.arr_active } else { 0 };
+                    self.reanchor(sys, cnt);
+                    if self.cr1 & CR1_URS == 0 {
+                        flags |= SR_UIF;
+                    }
+                    // The update event is also sent on TRGO, whatever URS is
+                    self.send_trgo(sys, MMS_RESET);
+                    self.send_trgo(sys, MMS_UPDATE);
+                }
+                self.set_flags(sys, flags);
+            }
+            0x0018 => self.ccmr[0] = value,
+            0x001C => self.ccmr[1] = value,
+            0x0020 => self.ccer = value,
+            0x0024 => self.reanchor(sys, value),
+            0x0028 => self.psc = value & 0xFFFF,
+            0x002C => {
+                self.arr = value;
+                if self.cr1 & CR1_ARPE == 0 {
+                    self.arr_active = value;
+                    self.reanchor(sys, cnt);
+                }
+            }
+            0x0030 => self.rcr = value,
+            0x0034..=0x0040 => self.ccr[((offset - 0x34) / 4) as usize] = value,
+            0x0044 => self.bdtr = value,
+            0x0048 => self.dcr = value,
+            _ => {}
+        }
+
+        self.schedule(sys);
+    }
+
+    fn on_event(&mut self, sys: &System, _event: u32) {
+        self.sync(sys);
+        self.schedule(sys);
+    }
+
+    fn dma_request(&mut self, sys: &System, _offset: u32, _to_memory: bool) -> bool {
+        // Only the update request. It's served by any stream pointing at the timer.
+        self.sync(sys);
+        std::mem::replace(&mut self.dma_update_pending, false)
+    }
+
+    fn paces_dma(&self) -> bool {
+        true
+    }
+}
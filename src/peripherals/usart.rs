@@ -1,59 +1,169 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+// Bytes written to DR go to the external device right away, but the TXE and
+// TC flags follow the baud rate. Received bytes come from the external device
+// when it has some available. We poll it on the clock when the firmware wants
+// RX interrupts.
+
 use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::ext_devices::{ExtDevices, ExtDevice};
 use crate::system::System;
-use super::Peripheral;
+use super::{Peripheral, Trigger};
+
+// SR bits
+const SR_TXE: u32 = 1 << 7;
+const SR_TC: u32 = 1 << 6;
+const SR_RXNE: u32 = 1 << 5;
+const SR_IDLE: u32 = 1 << 4;
+
+// CR1 bits. Interrupt enables are at the same position as their SR flags.
+const CR1_OVER8: u32 = 1 << 15;
+const CR1_M: u32 = 1 << 12;
+const CR1_TXEIE: u32 = SR_TXE;
+const CR1_TCIE: u32 = SR_TC;
+const CR1_RXNEIE: u32 = SR_RXNE;
+const CR1_IDLEIE: u32 = SR_IDLE;
+const CR1_IE_MASK: u32 = CR1_TXEIE | CR1_TCIE | CR1_RXNEIE | CR1_IDLEIE;
+
+// CR3 bits
+const CR3_DMAR: u32 = 1 << 6;
+const CR3_DMAT: u32 = 1 << 7;
+
+// When the firmware hasn't configured BRR, we pretend it's running at this speed.
+const DEFAULT_BAUDRATE: u32 = 115200;
+
+const EVENT_TX_DONE: u32 = 0;
+const EVENT_RX_POLL: u32 = 1;
 
 #[derive(Default)]
 pub struct Usart {
     pub name: String,
     pub ext_device: Option<Rc<RefCell<dyn ExtDevice<(), u8>>>>,
+    base: u32,
+    irq: Option<i32>,
+
+    brr: u32,
+    cr1: u32,
+    cr2: u32,
+    cr3: u32,
+    gtpr: u32,
+
+    // Number of bytes being transmitted: the one in the shift register, and the one waiting in DR.
+    tx_queue: u8,
+    tc: bool,
+    idle: bool,
+    // Set when bytes were received since the last IDLE detection
+    rx_activity: bool,
+    // Received data is checked once per frame for the DMA, so it gets the bytes at the baud rate
+    rx_dma_ready: bool,
 }
 
 impl Usart {
-    pub fn new(name: &str, ext_devices: &ExtDevices) -> Option<Box<dyn Peripheral>> {
-        if name.starts_with("USART") {
+    pub fn new(name: &str, base: u32, interrupts: &[(String, i32)], ext_devices: &ExtDevices) -> Option<Box<dyn Peripheral>> {
+        if name.starts_with("USART") || name.starts_with("UART") {
             let ext_device = ext_devices.find_serial_device(&name);
             let name = ext_device.as_ref()
                 .map(|d| d.borrow_mut().connect_peripheral(name))
                 .unwrap_or_else(|| name.to_string());
-            Some(Box::new(Self { name, ext_device, ..Default::default() }))
+            let irq = interrupts.first().map(|(_, irq)| *irq);
+            // TC is set at reset
+            Some(Box::new(Self { name, ext_device, base, irq, tc: true, ..Default::default() }))
         } else {
             None
         }
     }
+
+    fn rx_available(&self, sys: &System) -> bool {
+        self.ext_device.as_ref().is_some_and(|d| d.borrow_mut().rx_available(sys))
+    }
+
+    fn sr(&self, sys: &System) -> u32 {
+        let mut v = 0;
+        if self.tx_queue < 2 { v |= SR_TXE; }
+        if self.tc { v |= SR_TC; }
+        if self.rx_available(sys) { v |= SR_RXNE; }
+        if self.idle { v |= SR_IDLE; }
+        v
+    }
+
+    /// Duration of a frame (start bit, data bits, stop bits) in HCLK cycles
+    fn frame_cycles(&self, sys: &System) -> u64 {
+        let clock = sys.p.clock.borrow();
+        let pclk = clock.pclk(self.base);
+
+        // Number of pclk ticks per bit
+        let divisor = if self.brr == 0 {
+            (pclk / DEFAULT_BAUDRATE) as u64
+        } else if self.cr1 & CR1_OVER8 != 0 {
+            (((self.brr >> 4) << 3) | (self.brr & 0b111)) as u64
+        } else {
+            self.brr as u64
+        };
+
+        let data_bits = if self.cr1 & CR1_M != 0 { 9 } else { 8 };
+        let stop_bits = if (self.cr2 >> 12) & 0b11 == 0b10 { 2 } else { 1 };
+        let bits = 1 + data_bits + stop_bits;
+
+        clock.ticks_to_cycles(bits * divisor, pclk)
+    }
+
+    /// The USART interrupt is a level interrupt: it stays pending as long as
+    /// an enabled flag is set.
+    fn update_irq(&self, sys: &System) {
+        if self.sr(sys) & self.cr1 & CR1_IE_MASK != 0 {
+            if let Some(irq) = self.irq {
+                sys.p.nvic.borrow_mut().set_intr_pending(irq);
+            }
+        }
+    }
+
+    fn schedule_rx_poll(&self, sys: &System) {
+        let delay = self.frame_cycles(sys);
+        let mut clock = sys.p.clock.borrow_mut();
+        clock.cancel(self.base, EVENT_RX_POLL);
+        if self.cr1 & (CR1_RXNEIE | CR1_IDLEIE) != 0 || self.cr3 & CR3_DMAR != 0 {
+            clock.schedule(delay, self.base, EVENT_RX_POLL);
+        }
+    }
 }
 
 impl Peripheral for Usart {
     fn read(&mut self, sys: &System, offset: u32) -> u32 {
         match offset {
-            0x0000 => {
-                // SR register
-                // Bit 7 TXE: Transmit data register empty
-                // Bit 6 TC: Transmission complete
-                // Bit 5 RXNE: Read data register not empty
-                // Bit 4 IDLE: IDLE line detected
-                // We could do something smarter to indicate that there's data to read
-                (1 << 7) | (1 << 6) | (1 << 5) | (1 << 4)
-            }
+            0x0000 => self.sr(sys),
             0x0004 => {
                 // DR register
+                let has_data = self.rx_available(sys);
                 let v = self.ext_device.as_ref().map(|d|
                     d.borrow_mut().read(sys, ())
                 ).unwrap_or_default() as u32;
 
+                if has_data {
+                    self.rx_activity = true;
+                }
+                // Reading SR then DR clears IDLE
+                self.idle = false;
+                self.rx_dma_ready = false;
+                self.update_irq(sys);
+
                 trace!("{} read={:02x}", self.name, v);
                 v
             }
+            0x0008 => self.brr,
+            0x000C => self.cr1,
+            0x0010 => self.cr2,
+            0x0014 => self.cr3,
+            0x0018 => self.gtpr,
             _ => 0
         }
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
         match offset {
+            // SR register. TC can be cleared by writing 0.
+            0x0000 if value & SR_TC == 0 => self.tc = false,
             0x0004 => {
                 // DR register
                 self.ext_device.as_ref().map(|d|
@@ -61,8 +171,73 @@ impl Peripheral for Usart {
                 );
 
                 trace!("{} write={:02x}", self.name, value as u8);
+
+                self.tc = false;
+                if self.tx_queue == 0 {
+                    let delay = self.frame_cycles(sys);
+                    sys.p.clock.borrow_mut().schedule(delay, self.base, EVENT_TX_DONE);
+                }
+                // If DR was already full, the firmware has overwritten the pending byte.
+                self.tx_queue = (self.tx_queue + 1).min(2);
+                self.update_irq(sys);
+            }
+            0x0008 => self.brr = value & 0xFFFF,
+            0x000C => {
+                self.cr1 = value;
+                self.schedule_rx_poll(sys);
+                self.update_irq(sys);
+            }
+            0x0010 => self.cr2 = value,
+            0x0014 => {
+                self.cr3 = value;
+                self.schedule_rx_poll(sys);
+                if value & CR3_DMAT != 0 {
+                    sys.p.send_trigger(Trigger::DmaRequest);
+                }
+            }
+            0x0018 => self.gtpr = value,
+            _ => {}
+        }
+    }
+
+    fn dma_request(&mut self, _sys: &System, offset: u32, to_memory: bool) -> bool {
+        // Both requests are on DR
+        match (offset, to_memory) {
+            (0x0004, true) => self.rx_dma_ready,
+            (0x0004, false) => self.cr3 & CR3_DMAT != 0 && self.tx_queue < 2,
+            _ => false,
+        }
+    }
+
+    fn on_event(&mut self, sys: &System, event: u32) {
+        match event {
+            EVENT_TX_DONE => {
+                self.tx_queue = self.tx_queue.saturating_sub(1);
+                if self.tx_queue > 0 {
+                    // The byte waiting in DR moves to the shift register
+                    let delay = self.frame_cycles(sys);
+                    sys.p.clock.borrow_mut().schedule(delay, self.base, EVENT_TX_DONE);
+                } else {
+                    self.tc = true;
+                }
+                if self.cr3 & CR3_DMAT != 0 {
+                    sys.p.send_trigger(Trigger::DmaRequest);
+                }
+            }
+            EVENT_RX_POLL => {
+                self.rx_dma_ready = self.cr3 & CR3_DMAR != 0 && self.rx_available(sys);
+                if self.rx_dma_ready {
+                    sys.p.send_trigger(Trigger::DmaRequest);
+                }
+                // The line becomes idle one frame after the last received byte
+                if self.rx_activity && !self.rx_available(sys) {
+                    self.rx_activity = false;
+                    self.idle = true;
+                }
+                self.schedule_rx_poll(sys);
             }
             _ => {}
         }
+        self.update_irq(sys);
     }
 }
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::RwLock;
+use anyhow::{Context as _, Result};
+
+lazy_static::lazy_static! {
+    /// Symbols of the firmware, if we have any. Used to print function+offset in traces.
+    pub static ref SYMBOLS: RwLock<Symbols> = RwLock::new(Symbols::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    /// 0 when unknown
+    pub size: u32,
+}
+
+#[derive(Default)]
+pub struct Symbols {
+    // sorted by addr
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    /// When we don't know the size of a symbol, we won't match addresses further than this.
+    const MAX_UNSIZED_OFFSET: u32 = 0x1000;
+
+    pub fn extend(&mut self, symbols: impl IntoIterator<Item=Symbol>) {
+        self.symbols.extend(symbols);
+        self.symbols.sort_by_key(|s| s.addr);
+        self.symbols.dedup_by(|a, b| a.addr == b.addr && a.name == b.name);
+    }
+
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let index = self.symbols.partition_point(|s| s.addr <= addr).checked_sub(1)?;
+
+        // Multiple symbols can share the same address, pick the one that covers addr.
+        let base = self.symbols[index].addr;
+        self.symbols[..=index].iter().rev()
+            .take_while(|s| s.addr == base)
+            .find(|s| {
+                let offset = addr - s.addr;
+                if s.size == 0 { offset < Self::MAX_UNSIZED_OFFSET } else { offset < s.size }
+            })
+            .map(|s| (s, addr - s.addr))
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+}
+
+/// Parses a symbol map file in the nm format: "ADDR [SIZE] [TYPE] NAME" on each line.
+/// This is useful when the firmware is not an ELF file.
+/// Lines without an address, like undefined symbols ("U foo"), are skipped.
+pub fn parse_map_file(content: &str) -> Result<Vec<Symbol>> {
+    let parse_hex = |v: &str| u32::from_str_radix(v.trim_start_matches("0x"), 16);
+
+    content.lines().enumerate()
+        .map(|(i, line)| (i+1, line.split_whitespace().collect::<Vec<_>>()))
+        .filter(|(_, fields)| fields.len() >= 2 && parse_hex(fields[0]).is_ok())
+        .map(|(lineno, fields)| {
+            let addr = parse_hex(fields[0])?;
+            // nm -S prints the size after the address
+            let size = match fields.len() {
+                4 => parse_hex(fields[1]).with_context(|| format!("line {}: invalid size", lineno))?,
+                _ => 0,
+            };
+            let type_ = if fields.len() >= 3 { fields[fields.len()-2] } else { "" };
+            let name = fields[fields.len()-1].to_string();
+            // Thumb functions may have the low bit set. Data symbols can be at odd addresses.
+            let addr = if type_ == "T" || type_ == "t" { addr & !1 } else { addr };
+            Ok(Symbol { name, addr, size })
+        })
+        .collect()
+}
+
+/// Returns "function+0x12" if we know where addr is
+pub fn describe(addr: u32) -> Option<String> {
+    let symbols = SYMBOLS.read().unwrap();
+    symbols.lookup(addr).map(|(s, offset)| {
+        if offset == 0 {
+            s.name.clone()
+        } else {
+            format!("{}+0x{:x}", s.name, offset)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_map_file_works() {
+        let content = "\
+# comment
+08000101 T main
+08000201 00000010 t helper
+20000001 D odd_data
+         U undefined
+0x08000400 vectors
+";
+        let symbols = parse_map_file(content).unwrap();
+        let find = |name: &str| symbols.iter().find(|s| s.name == name).unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(find("main").addr, 0x0800_0100);
+        assert_eq!(find("helper").addr, 0x0800_0200);
+        assert_eq!(find("helper").size, 0x10);
+        assert_eq!(find("odd_data").addr, 0x2000_0001);
+        assert_eq!(find("vectors").addr, 0x0800_0400);
+    }
+}
@@ -2,8 +2,8 @@
 
 use std::{rc::Rc, cell::RefCell};
 use unicorn_engine::{Unicorn, unicorn_const::Permission};
-use crate::{peripherals::{Peripherals, gpio::GpioPorts}, ext_devices::ExtDevices, util::{UniErr, round_up, self}, config::Config, framebuffers::Framebuffers};
-use anyhow::{Context as _, Result};
+use crate::{peripherals::{Peripherals, gpio::GpioPorts}, ext_devices::ExtDevices, util::{UniErr, round_up, self}, config::Config, framebuffers::Framebuffers, watchpoints::WatchKind, firmware::Firmware, symbols::{self, SYMBOLS}, patches};
+use anyhow::{Context as _, Result, bail};
 use svd_parser::svd::Device as SvdDevice;
 
 // System is passed around during read/write hooks. It's more convenient than passing each thing individually.
@@ -31,8 +31,12 @@ impl<'a, 'b> System<'a, 'b> {
                 let p = self.p.clone();
                 let d = self.d.clone();
                 move |uc: &mut Unicorn<'_, ()>, addr, size| {
-                    let mut sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
-                    p.read(&mut sys, start + addr as u32, size as u8) as u64
+                    let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                    let addr = start + addr as u32;
+                    let v = p.read(&sys, addr, size as u8);
+                    p.watchpoints.borrow_mut().on_access(&sys.uc.borrow(), addr, size as u8,
+                        WatchKind::Read, Some(v), || p.addr_desc(addr));
+                    v as u64
                 }
             };
 
@@ -40,8 +44,11 @@ impl<'a, 'b> System<'a, 'b> {
                 let p = self.p.clone();
                 let d = self.d.clone();
                 move |uc: &mut Unicorn<'_, ()>, addr, size, value| {
-                    let mut sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
-                    p.write(&mut sys, start + addr as u32, size as u8, value as u32)
+                    let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                    let addr = start + addr as u32;
+                    p.write(&sys, addr, size as u8, value as u32);
+                    p.watchpoints.borrow_mut().on_access(&sys.uc.borrow(), addr, size as u8,
+                        WatchKind::Write, Some(value as u32), || p.addr_desc(addr));
                 }
             };
 
@@ -53,7 +60,13 @@ impl<'a, 'b> System<'a, 'b> {
     }
 }
 
-fn load_memory_regions(uc: &mut Unicorn<()>, config: &Config) -> Result<()> {
+fn is_mapped(config: &Config, addr: u32, len: usize) -> bool {
+    let (start, end) = (addr as u64, addr as u64 + len as u64);
+    config.regions.iter().any(|r| r.start as u64 <= start && end <= r.start as u64 + r.size as u64)
+}
+
+/// Returns the vector table address found in the firmware files, if any.
+fn load_memory_regions(uc: &mut Unicorn<()>, config: &Config) -> Result<Option<u32>> {
     for region in &config.regions {
         debug!("Mapping region start=0x{:08x} len=0x{:x} name={}",
             region.start, region.size, region.name);
@@ -62,35 +75,71 @@ fn load_memory_regions(uc: &mut Unicorn<()>, config: &Config) -> Result<()> {
         uc.mem_map(region.start.into(), size, Permission::ALL)
             .map_err(UniErr).with_context(||
                 format!("Memory mapping of peripheral={} failed", region.name))?;
+    }
+
+    let mut vector_table = None;
 
+    // Files are loaded once all regions are mapped, as some firmware formats
+    // place their content anywhere in the address space.
+    for region in &config.regions {
         if let Some(ref load) = region.load {
-            info!("Loading file={} at base=0x{:08x}", load, region.start);
             let content = util::read_file(load)?;
-            let content = &content[0..content.len().min(size)];
-            uc.mem_write(region.start.into(), content).map_err(UniErr)?;
+            let firmware = Firmware::parse(&content)
+                .with_context(|| format!("Failed to parse {}", load))?;
+
+            if let Some(firmware) = firmware {
+                info!("Loading firmware file={} entry=0x{:08x}", load, firmware.entry.unwrap_or_default());
+
+                for segment in &firmware.segments {
+                    if !is_mapped(config, segment.addr, segment.data.len()) {
+                        bail!("{}: segment addr=0x{:08x} len=0x{:x} is outside of the mapped regions",
+                            load, segment.addr, segment.data.len());
+                    }
+                    debug!("Loading segment addr=0x{:08x} len=0x{:x}", segment.addr, segment.data.len());
+                    uc.mem_write(segment.addr.into(), &segment.data).map_err(UniErr)?;
+                }
+
+                if !firmware.symbols.is_empty() {
+                    info!("Loaded {} symbols from {}", firmware.symbols.len(), load);
+                    SYMBOLS.write().unwrap().extend(firmware.symbols);
+                }
+
+                vector_table = vector_table.or(firmware.vector_table);
+            } else {
+                info!("Loading file={} at base=0x{:08x}", load, region.start);
+                let size = round_up(region.size as usize, 4096);
+                let content = &content[0..content.len().min(size)];
+                uc.mem_write(region.start.into(), content).map_err(UniErr)?;
+            }
         }
     }
 
-    for patch in config.patches.as_ref().unwrap_or(&vec![]) {
-        uc.mem_write(patch.start.into(), &patch.data)
-            .map_err(UniErr).with_context(||
-                format!("Failed to apply patch at addr={}", patch.start))?;
+    for file in config.symbols.as_ref().unwrap_or(&vec![]) {
+        let symbols = symbols::parse_map_file(&util::read_file_str(file)?)
+            .with_context(|| format!("Failed to parse {}", file))?;
+        info!("Loaded {} symbols from {}", symbols.len(), file);
+        SYMBOLS.write().unwrap().extend(symbols);
     }
 
-    Ok(())
+    patches::apply(uc, config.patches.as_deref().unwrap_or_default())?;
+
+    Ok(vector_table)
 }
 
 pub fn prepare<'a, 'b>(uc: &'a mut Unicorn<'b, ()>, config: Config, svd_device: SvdDevice)
--> Result<(System<'a, 'b>, Framebuffers)>
+-> Result<(System<'a, 'b>, Framebuffers, u32)>
   {
-    load_memory_regions(uc, &config)?;
+    let vector_table = load_memory_regions(uc, &config)?;
+    let vector_table = config.cpu.vector_table.or(vector_table)
+        .context("cpu.vector_table must be specified in the config")?;
+    debug!("Vector table at 0x{:08x}", vector_table);
 
     let framebuffers = Framebuffers::from_config(config.framebuffers.unwrap_or_default());
     let mut gpio: GpioPorts = Default::default();
     let ext_devices = config.devices.unwrap_or_default().into_ext_devices(&mut gpio, &framebuffers)?;
-    let peripherals = Peripherals::from_svd(svd_device, config.peripherals.unwrap_or_default(), gpio, &ext_devices);
+    let peripherals = Peripherals::from_svd(svd_device, config.peripherals.unwrap_or_default(), gpio, &ext_devices)?;
 
     let mut system = System::new(uc, peripherals, ext_devices);
     system.bind_peripherals_to_unicorn()?;
-    Ok((system, framebuffers))
+    Ok((system, framebuffers, vector_table))
 }
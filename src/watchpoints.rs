@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, str::FromStr};
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use unicorn_engine::{Unicorn, unicorn_const::{HookType, MemType}};
+
+use crate::{peripherals::Peripherals, util::UniErr};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchAction {
+    /// Stop the emulation (or give control to gdb if connected)
+    Stop,
+    /// Log the access with a backtrace, and keep going
+    Log,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WatchpointConfig {
+    pub start: u32,
+    pub size: Option<u32>,
+    pub kind: Option<WatchKind>,
+    pub action: Option<WatchAction>,
+}
+
+impl FromStr for WatchpointConfig {
+    type Err = anyhow::Error;
+
+    /// Format: [read|write|access:]ADDR[+SIZE][:stop|log]
+    /// For example: write:0x40011004, or 0x20000100+16:log
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kind = None;
+        let mut action = None;
+        let mut range = None;
+
+        for part in s.split(':') {
+            match part {
+                "read" => kind = Some(WatchKind::Read),
+                "write" => kind = Some(WatchKind::Write),
+                "access" => kind = Some(WatchKind::Access),
+                "stop" => action = Some(WatchAction::Stop),
+                "log" => action = Some(WatchAction::Log),
+                _ if range.is_none() => range = Some(part),
+                _ => bail!("Invalid watchpoint spec: {}", s),
+            }
+        }
+
+        let range = range.ok_or_else(|| anyhow!("Missing watchpoint address: {}", s))?;
+        let (start, size) = match range.split_once('+') {
+            Some((start, size)) => (start, Some(size)),
+            None => (range, None),
+        };
+
+        let parse = |v: &str| clap_num::maybe_hex::<u32>(v).map_err(|e| anyhow!("{}: {}", v, e));
+        let start = parse(start)?;
+        let size = size.map(parse).transpose()?;
+
+        Ok(Self { start, size, kind, action })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub addr: u32,
+    pub kind: WatchKind,
+}
+
+pub struct Watchpoint {
+    pub start: u32,
+    /// inclusive, so a watchpoint can end at 0xFFFF_FFFF
+    pub last: u32,
+    pub kind: WatchKind,
+    pub action: WatchAction,
+    /// For RAM watchpoints, we register a unicorn hook. MMIO accesses are
+    /// checked directly in the mmio callbacks.
+    hook: Option<*mut std::ffi::c_void>,
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    /// The last watchpoint that requested a stop
+    hit: Option<Hit>,
+}
+
+impl Watchpoints {
+    fn is_mmio(addr: u32) -> bool {
+        Peripherals::MEMORY_MAPS.iter().any(|(start, end)| (*start..*end).contains(&addr))
+    }
+
+    pub fn add(&mut self, uc: &mut Unicorn<()>, p: Rc<Peripherals>, config: &WatchpointConfig) -> anyhow::Result<()> {
+        let start = config.start;
+        let size = config.size.unwrap_or(4).max(1);
+        let last = start.saturating_add(size - 1);
+        let kind = config.kind.unwrap_or(WatchKind::Access);
+        let action = config.action.unwrap_or(WatchAction::Stop);
+
+        info!("Watchpoint addr=0x{:08x} size={} kind={:?} action={:?}", start, last-start+1, kind, action);
+
+        let hook = if Self::is_mmio(start) {
+            None
+        } else {
+            let hook_type = match kind {
+                WatchKind::Read => HookType::MEM_READ,
+                WatchKind::Write => HookType::MEM_WRITE,
+                WatchKind::Access => HookType::MEM_READ | HookType::MEM_WRITE,
+            };
+
+            let hook = uc.add_mem_hook(hook_type, start.into(), last.into(), move |uc, type_, addr, size, value| {
+                let (kind, value) = match type_ {
+                    MemType::WRITE => (WatchKind::Write, Some(value as u32)),
+                    _ => (WatchKind::Read, None),
+                };
+                p.watchpoints.borrow_mut().on_access(uc, addr as u32, size as u8, kind, value,
+                    || format!("addr=0x{:08x}", addr));
+                true
+            }).map_err(UniErr)?;
+
+            Some(hook)
+        };
+
+        self.list.push(Watchpoint { start, last, kind, action, hook });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, uc: &mut Unicorn<()>, start: u32, kind: WatchKind) -> bool {
+        let index = self.list.iter().position(|w| w.start == start && w.kind == kind);
+        if let Some(w) = index.map(|i| self.list.remove(i)) {
+            if let Some(hook) = w.hook {
+                uc.remove_hook(hook).ok();
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<Hit> {
+        self.hit.take()
+    }
+
+    pub fn on_access(&mut self, uc: &Unicorn<()>, addr: u32, size: u8, kind: WatchKind,
+                     value: Option<u32>, desc: impl FnOnce() -> String) {
+        if self.list.is_empty() {
+            return;
+        }
+
+        let access_last = addr.saturating_add((size as u32).max(1) - 1);
+        let w = self.list.iter().find(|w|
+            addr <= w.last && w.start <= access_last &&
+            (w.kind == WatchKind::Access || w.kind == kind)
+        );
+
+        if let Some(w) = w {
+            let value = value.map(|v| format!(" value=0x{:08x}", v)).unwrap_or_default();
+            warn!("Watchpoint {:?} {} size={}{}", kind, desc(), size, value);
+
+            match w.action {
+                WatchAction::Log => {
+                    for (i, addr) in crate::emulator::backtrace(uc).iter().enumerate() {
+                        warn!("  #{} 0x{:08x}{}", i, addr, crate::emulator::describe_addr(*addr));
+                    }
+                }
+                WatchAction::Stop => {
+                    self.hit = Some(Hit { addr, kind: w.kind });
+                }
+            }
+        }
+    }
+}